@@ -0,0 +1,55 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::PageNumber, state, APP_ID};
+
+/// A freehand line, with coordinates relative to the page from 0 to 1
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stroke {
+    pub points: Vec<(f64, f64)>,
+}
+
+pub type PageAnnotations = HashMap<PageNumber, Vec<Stroke>>;
+
+// Kept apart from the state, because strokes take much more space than positions
+fn annotations_file_path() -> PathBuf {
+    glib::user_data_dir().join(APP_ID).join("annotations.json")
+}
+
+fn read_annotations() -> HashMap<String, PageAnnotations> {
+    fs::read_to_string(annotations_file_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_annotations(annotations: &HashMap<String, PageAnnotations>) -> Result<()> {
+    let path = annotations_file_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(annotations)?)?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> PageAnnotations {
+    read_annotations()
+        .remove(&state::document_key(path.as_ref()))
+        .unwrap_or_default()
+}
+
+pub fn save(path: impl AsRef<Path>, page_annotations: &PageAnnotations) {
+    debug!("Saving annotations for {:?}", path.as_ref());
+    let mut annotations = read_annotations();
+    annotations.insert(state::document_key(path.as_ref()), page_annotations.clone());
+    if let Err(e) = write_annotations(&annotations) {
+        error!("Failed saving annotations: {}", e);
+    }
+}
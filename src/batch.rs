@@ -0,0 +1,88 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::{
+    document::{self, OpenOptions},
+    draw::{self, RenderSettings},
+    prefs,
+};
+
+/// Pages given on the command line, numbered from 1 like in the window
+#[derive(Debug, Clone, Copy)]
+pub struct PageRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl FromStr for PageRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse_page = |page: &str| match page.trim().parse() {
+            Ok(page) if page > 0 => Ok(page),
+            _ => Err(format!("'{}' is not a valid page number", page)),
+        };
+        let (first, last) = match value.split_once('-') {
+            Some((first, last)) => (parse_page(first)?, parse_page(last)?),
+            None => {
+                let page = parse_page(value)?;
+                (page, page)
+            }
+        };
+        if first > last {
+            return Err(format!("The pages {} end before they start", value));
+        }
+        Ok(PageRange { first, last })
+    }
+}
+
+/// Renders every page of the range to a PNG file of its own, without opening a window.
+/// Pages keep the rotation they were given in the window.
+pub fn render_to_png(
+    file: &Path,
+    pages: Option<PageRange>,
+    dpi: f64,
+    out_dir: &Path,
+) -> Result<()> {
+    let document = document::open_documents(&[file.to_path_buf()], &OpenOptions::default())?;
+    let num_pages = document.n_pages();
+    let range = pages.unwrap_or(PageRange {
+        first: 1,
+        last: num_pages,
+    });
+    if num_pages == 0 || range.last > num_pages {
+        bail!("{} only has {} pages", file.display(), num_pages);
+    }
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed creating folder {}", out_dir.display()))?;
+
+    let name = file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "page".to_string());
+    // Padded numbers keep the files sorted by page
+    let digits = num_pages.to_string().len();
+    let rotations = prefs::load(file).rotations;
+    for page_number in range.first - 1..range.last {
+        let page = document
+            .page(page_number)
+            .ok_or_else(|| anyhow!("Failed loading page {}", page_number + 1))?;
+        let rotation = rotations.get(&page_number).copied().unwrap_or_default();
+        let path = out_dir.join(format!(
+            "{}-{:0digits$}.png",
+            name,
+            page_number + 1,
+            digits = digits
+        ));
+        draw::export_pages_to_png(
+            &[draw::rotate_page(page, rotation)],
+            dpi,
+            &RenderSettings::default(),
+            &path,
+        )
+        .with_context(|| format!("Failed saving {}", path.display()))?;
+        println!("Saved {}", path.display());
+    }
+    Ok(())
+}
@@ -1,12 +1,17 @@
-use crate::draw;
-use anyhow::{anyhow, bail, Result};
+use crate::{
+    document::OpenOptions,
+    draw::{self, Crop, Offset, Pixels, RenderSettings, RenderSize, Rotation},
+    render_worker::{RenderRequest, RenderWorker},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_channel::{Receiver, Sender};
 use glib::timeout_future;
-use gtk::{gdk::Texture, prelude::TextureExt};
-use log::{debug, error};
-use poppler::Document;
+use gtk::gdk::Texture;
+use log::{debug, error, warn};
 use std::{
     cell::RefCell,
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::PathBuf,
     rc::Rc,
     time::{Duration, Instant},
 };
@@ -14,158 +19,551 @@ use std::{
 pub type PageNumber = usize;
 pub type MyPageType = Texture;
 
+// Cache commands sent quicker than this after each other replace the previous ones
+const CACHE_COMMAND_DEBOUNCE: Duration = Duration::from_millis(150);
+// Previews are tiny, so many more of them are kept than full resolution pages
+const PREVIEWS_PER_STORED_PAGE: usize = 4;
+// Work is done back to back, but gtk gets a chance to draw at least once per frame
+const MAX_BUSY_TIME: Duration = Duration::from_millis(16);
+
+struct CachedPage {
+    texture: Rc<MyPageType>,
+    size: RenderSize,
+    settings: RenderSettings,
+    rotation: Rotation,
+    crop: Option<Crop>,
+    offset: Offset,
+    // Bytes of the pixel data
+    memory_size: usize,
+}
+
+/// Renders pages on a worker thread and keeps the ones around the reader,
+/// in full resolution and as small previews that can be shown right away.
+/// Most apps drive it through `spawn_sync_cache` instead of using it directly.
 pub struct PageCache {
-    document: Document,
+    worker: RenderWorker,
+    num_pages: usize,
     max_num_stored_pages: usize,
-    pages: BTreeMap<usize, Rc<MyPageType>>,
+    // In bytes, high resolution spreads take far more than small pages
+    max_memory: usize,
+    // Up to four pages of a grid are shown at once
+    num_visible_pages: usize,
+    // Full resolution pages are evicted independently, so a preview can always be shown
+    pages: BTreeMap<usize, CachedPage>,
+    previews: BTreeMap<usize, CachedPage>,
     last_requested_page_number: PageNumber,
+    render_settings: RenderSettings,
+    rotations: HashMap<PageNumber, Rotation>,
+    crops: HashMap<PageNumber, Crop>,
+    offsets: HashMap<PageNumber, Offset>,
+    preview_size: RenderSize,
+    // The latest full resolution asked for, only pages in it count as cached
+    full_size: Option<RenderSize>,
+    // Checking the rendered pixels costs time, so it is only done when debugging
+    detect_blank_pages: bool,
+    new_blank_pages: Vec<PageNumber>,
+    stats: CacheStats,
+}
+
+/// Numbers for tuning the cache, shown in the debug overlay
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    pub last_render_time: Duration,
+    // Pages that could be shown right away, or had to be rendered first
+    pub hits: usize,
+    pub misses: usize,
+    pub cached_pages: usize,
+    pub memory_usage: usize,
 }
 
 impl PageCache {
-    pub fn new(document: Document, max_num_stored_pages: usize) -> Self {
+    pub fn new(
+        worker: RenderWorker,
+        num_pages: usize,
+        max_num_stored_pages: usize,
+        max_memory: usize,
+    ) -> Self {
         PageCache {
-            document,
+            worker,
+            num_pages,
             max_num_stored_pages,
+            max_memory,
+            num_visible_pages: 2,
             pages: BTreeMap::new(),
+            previews: BTreeMap::new(),
             last_requested_page_number: 0,
+            render_settings: RenderSettings::default(),
+            rotations: HashMap::new(),
+            crops: HashMap::new(),
+            offsets: HashMap::new(),
+            preview_size: RenderSize::PREVIEW,
+            full_size: None,
+            detect_blank_pages: false,
+            new_blank_pages: Vec::new(),
+            stats: CacheStats::default(),
         }
     }
 
+    pub fn set_render_settings(&mut self, render_settings: RenderSettings) {
+        self.render_settings = render_settings;
+    }
+
+    // Only pages in full resolution, previews are not smooth to read
+    pub fn num_cached_pages(&self) -> usize {
+        let fit_mode = self.render_settings.fit_mode;
+        self.pages
+            .iter()
+            .filter(|(&page_number, page)| self.is_up_to_date(page_number, page))
+            .filter(|(_, page)| {
+                self.full_size
+                    .map_or(true, |full_size| page.size.covers(full_size, fit_mode))
+            })
+            .count()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            cached_pages: self.num_cached_pages(),
+            memory_usage: memory_usage(&self.pages) + memory_usage(&self.previews),
+            ..self.stats
+        }
+    }
+
+    // Blank pages found since the last call
+    pub fn take_new_blank_pages(&mut self) -> Vec<PageNumber> {
+        std::mem::take(&mut self.new_blank_pages)
+    }
+
+    pub fn set_preview_size(&mut self, preview_size: RenderSize) {
+        self.preview_size = preview_size;
+    }
+
+    // Pages rendered in smaller resolution steps on their way to it don't count as cached
+    pub fn set_full_size(&mut self, full_size: Option<RenderSize>) {
+        self.full_size = full_size;
+    }
+
+    pub fn set_num_visible_pages(&mut self, num_visible_pages: usize) {
+        self.num_visible_pages = num_visible_pages;
+    }
+
+    // Pages beyond smaller limits are evicted as the next pages are stored
+    pub fn set_limits(&mut self, max_num_stored_pages: usize, max_memory: usize) {
+        self.max_num_stored_pages = max_num_stored_pages;
+        self.max_memory = max_memory;
+    }
+
+    pub fn set_rotations(&mut self, rotations: HashMap<PageNumber, Rotation>) {
+        self.rotations = rotations;
+    }
+
+    pub fn set_crops(&mut self, crops: HashMap<PageNumber, Crop>) {
+        self.crops = crops;
+    }
+
+    fn crop(&self, page_number: PageNumber) -> Option<Crop> {
+        self.crops.get(&page_number).copied()
+    }
+
+    pub fn set_offsets(&mut self, offsets: HashMap<PageNumber, Offset>) {
+        self.offsets = offsets;
+    }
+
+    fn offset(&self, page_number: PageNumber) -> Offset {
+        self.offsets.get(&page_number).copied().unwrap_or_default()
+    }
+
+    fn rotation(&self, page_number: PageNumber) -> Rotation {
+        self.rotations
+            .get(&page_number)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Pages rendered with outdated settings, rotation, crop or offset count as missing
+    fn is_up_to_date(&self, page_number: PageNumber, page: &CachedPage) -> bool {
+        page.settings == self.render_settings
+            && page.rotation == self.rotation(page_number)
+            && page.crop == self.crop(page_number)
+            && page.offset == self.offset(page_number)
+    }
+
     pub fn get_page(&mut self, page_number: usize) -> Option<Rc<MyPageType>> {
         self.last_requested_page_number = page_number;
-        self.pages.get(&page_number).map(Rc::clone)
+        self.pages
+            .get(&page_number)
+            .filter(|page| self.is_up_to_date(page_number, page))
+            .or_else(|| {
+                self.previews
+                    .get(&page_number)
+                    .filter(|page| self.is_up_to_date(page_number, page))
+            })
+            .map(|page| Rc::clone(&page.texture))
     }
 
-    pub fn get_page_or_cache(&mut self, page_number: usize) -> Result<Rc<MyPageType>> {
+    pub async fn get_page_or_cache(&mut self, page_number: usize) -> Result<Rc<MyPageType>> {
         if let Some(page) = self.get_page(page_number) {
+            self.stats.hits += 1;
             Ok(page)
         } else {
-            let _ = self.cache_page(page_number, 100);
+            self.stats.misses += 1;
+            self.cache_page(page_number, self.preview_size).await?;
             if let Some(page) = self.get_page(page_number) {
                 Ok(page)
             } else {
-                bail!("Failed caching and retrieving page {}", page_number);
+                bail!("Page {} could not be rendered", page_number + 1);
             }
         }
     }
 
-    pub fn cache_page(&mut self, page_number: PageNumber, height: i32) -> Option<CacheResponse> {
-        debug!("Caching page {}", page_number);
-        if page_number.abs_diff(self.last_requested_page_number)
-            > self.max_num_stored_pages.div_ceil(2)
-        {
-            debug!("Page too far from reader, aborting caching call");
-            return None;
-        }
+    // Whether the page can be shown in the size without rendering it first
+    fn is_cached_in(&self, page_number: PageNumber, size: RenderSize) -> bool {
+        let is_covered_by = |pages: &BTreeMap<usize, CachedPage>| {
+            pages.get(&page_number).is_some_and(|page| {
+                self.is_up_to_date(page_number, page)
+                    && page.size.covers(size, self.render_settings.fit_mode)
+            })
+        };
+        // A page in full resolution also covers its preview
+        is_covered_by(&self.pages) || (size == self.preview_size && is_covered_by(&self.previews))
+    }
 
-        let begin_of_cashing = Instant::now();
-        if let Some(page) = self.pages.get(&page_number) {
-            if page.height() >= height {
-                debug!("Page already in cache");
-                return None;
-            }
+    // Visible pages come before all queued work, missing ones are rendered together at once.
+    // Previews and lower resolution steps count as missing, the reader waits for the full size.
+    async fn retrieve_pages(
+        &mut self,
+        page_numbers: &[PageNumber],
+        size: RenderSize,
+    ) -> Vec<Result<Rc<MyPageType>>> {
+        // Before the first layout the area is unknown, the preview is upgraded once it is
+        let size = if size.is_empty() {
+            self.preview_size
+        } else {
+            size
+        };
+        // Pages far from the reader are not rendered, so this has to be known first
+        if let Some(&page_number) = page_numbers.first() {
+            self.last_requested_page_number = page_number;
         }
+        let missing: Vec<CachePageCommand> = page_numbers
+            .iter()
+            .filter(|&&page| page < self.num_pages && !self.is_cached_in(page, size))
+            .map(|&page| CachePageCommand { page, size })
+            .collect();
+        self.stats.hits += page_numbers.len() - missing.len();
+        self.stats.misses += missing.len();
+        let mut failures = HashMap::new();
+        if !missing.is_empty() {
+            // The pages are returned below, which replaces showing them as upgrades
+            (_, failures) = self.cache_pages(missing).await;
+        }
+        let pages = page_numbers
+            .iter()
+            .map(|&page_number| {
+                // A preview would hide that the full resolution failed
+                if let Some(e) = failures.remove(&page_number) {
+                    return Err(e);
+                }
+                self.get_page(page_number)
+                    .ok_or_else(|| anyhow!("Page {} could not be rendered", page_number + 1))
+            })
+            .collect();
+        // The reader is at the first of the pages
+        if let Some(&page_number) = page_numbers.first() {
+            self.last_requested_page_number = page_number;
+        }
+        pages
+    }
 
-        let mut response = None;
-
-        if let Some(page) = self.document.page(page_number as i32) {
-            let pages = vec![Rc::new(page)];
-            let texture = draw::draw_pages_to_texture(&pages, height);
-            let page = Rc::new(texture);
-
-            // Overwrite page with lower resolution if exists
-            let previous_page = self.pages.insert(page_number, Rc::clone(&page));
-            let page_resolution_upgraded = previous_page.is_some();
-            if page_resolution_upgraded {
-                response = Some(CacheResponse::PageResolutionUpgraded { page_number, page });
-            }
+    pub async fn cache_page(
+        &mut self,
+        page_number: PageNumber,
+        size: RenderSize,
+    ) -> Result<Option<CacheResponse>> {
+        debug!("Caching page {}", page_number);
+        let begin_of_cashing = Instant::now();
+        let Some(request) = self.render_request(page_number, size) else {
+            return Ok(None);
+        };
+        let pixels = self.worker.render(request).await;
+        let response = self
+            .store_page(page_number, size, pixels)?
+            .map(|upgraded_page| CacheResponse::PagesResolutionUpgraded {
+                pages: vec![upgraded_page],
+            });
+        self.stats.last_render_time = begin_of_cashing.elapsed();
+        debug!(
+            "done caching of page {} in {}ms",
+            page_number,
+            begin_of_cashing.elapsed().as_millis()
+        );
+        Ok(response)
+    }
 
-            if self.pages.len() > self.max_num_stored_pages && self.pages.len() > 2 {
-                let _result = self.remove_most_distant_page();
+    // Used for the first pages of a document, which are otherwise rendered one after another.
+    // Also returns why the pages that failed could not be rendered.
+    pub async fn cache_pages(
+        &mut self,
+        commands: Vec<CachePageCommand>,
+    ) -> (Vec<CacheResponse>, HashMap<PageNumber, anyhow::Error>) {
+        debug!("Caching pages {:?} in parallel", commands);
+        let begin_of_cashing = Instant::now();
+        let (commands, requests): (Vec<_>, Vec<_>) = commands
+            .into_iter()
+            .filter_map(|command| {
+                self.render_request(command.page, command.size)
+                    .map(|request| (command, request))
+            })
+            .unzip();
+        let results = self.worker.render_all(requests).await;
+        // Pages rendered together are shown together, so a spread never changes one page at a time
+        let mut pages = Vec::new();
+        let mut failures = HashMap::new();
+        for (command, pixels) in commands.into_iter().zip(results) {
+            match self.store_page(command.page, command.size, pixels) {
+                Ok(Some(page)) => pages.push(page),
+                Ok(None) => {}
+                Err(e) => {
+                    failures.insert(command.page, e);
+                }
             }
         }
+        let responses = if pages.is_empty() {
+            Vec::new()
+        } else {
+            vec![CacheResponse::PagesResolutionUpgraded { pages }]
+        };
+        self.stats.last_render_time = begin_of_cashing.elapsed();
         debug!(
-            "done caching of page {} in {}ms",
-            page_number,
+            "done caching of pages in {}ms",
             begin_of_cashing.elapsed().as_millis()
         );
-        response
-    }
-
-    fn remove_most_distant_page(&mut self) -> anyhow::Result<()> {
-        let (min_cached_page_number, min_cached_page) = self
-            .pages
-            .pop_first()
-            .ok_or(anyhow!("The cache is empty, cannot remove first page"))?;
-        let (max_cached_page_number, max_cached_page) = self
-            .pages
-            .pop_last()
-            .ok_or(anyhow!("The cache is empty, cannot remove last page"))?;
-
-        if self
-            .last_requested_page_number
-            .abs_diff(min_cached_page_number)
-            > self
-                .last_requested_page_number
-                .abs_diff(max_cached_page_number)
+        (responses, failures)
+    }
+
+    // None if the page doesn't need to be rendered
+    fn render_request(&self, page_number: PageNumber, size: RenderSize) -> Option<RenderRequest> {
+        // Everything is kept if the whole document fits
+        if self.max_num_stored_pages < self.num_pages
+            && page_number.abs_diff(self.last_requested_page_number)
+                > self.max_num_stored_pages.div_ceil(2)
         {
-            self.pages.insert(max_cached_page_number, max_cached_page);
-            debug!(
-                "Removed page {} from cache to keep size low...",
-                min_cached_page_number
-            );
-        } else {
-            self.pages.insert(min_cached_page_number, min_cached_page);
-            debug!(
-                "Removed page {} from cache to keep size low...",
-                max_cached_page_number
-            );
+            debug!("Page too far from reader, aborting caching call");
+            return None;
+        }
+
+        if self.is_cached_in(page_number, size) {
+            debug!("Page already in cache");
+            return None;
+        }
+        if page_number >= self.num_pages {
+            return None;
+        }
+        Some(RenderRequest {
+            page_number,
+            size,
+            settings: self.render_settings.clone(),
+            rotation: self.rotation(page_number),
+            crop: self.crop(page_number),
+            offset: self.offset(page_number),
+        })
+    }
+
+    // Returns the page if it replaces a lower resolution
+    fn store_page(
+        &mut self,
+        page_number: PageNumber,
+        size: RenderSize,
+        pixels: Result<Pixels>,
+    ) -> Result<Option<(PageNumber, Rc<MyPageType>)>> {
+        let is_preview = size == self.preview_size;
+        let mut upgraded_page = None;
+        let pixels =
+            pixels.with_context(|| format!("Page {} could not be rendered", page_number + 1))?;
+        // Previews are too small to tell
+        if self.detect_blank_pages && !is_preview && draw::is_blank(&pixels) {
+            warn!("Page {} was rendered blank", page_number + 1);
+            self.new_blank_pages.push(page_number);
         }
+        let memory_size = pixels.stride * pixels.height as usize;
+        let page = Rc::new(draw::pixels_to_texture(pixels));
+        let cached_page = CachedPage {
+            texture: Rc::clone(&page),
+            size,
+            settings: self.render_settings.clone(),
+            rotation: self.rotation(page_number),
+            crop: self.crop(page_number),
+            offset: self.offset(page_number),
+            memory_size,
+        };
 
-        Ok(())
+        if is_preview {
+            self.previews.insert(page_number, cached_page);
+            if self.previews.len() > self.max_num_stored_pages * PREVIEWS_PER_STORED_PAGE {
+                let _result =
+                    remove_most_distant_page(&mut self.previews, self.last_requested_page_number);
+            }
+        } else {
+            // The preview or an older resolution of the page may currently be shown
+            self.pages.insert(page_number, cached_page);
+            upgraded_page = Some((page_number, page));
+            // The visible pages are never evicted, even if they alone exceed the budget
+            while (self.pages.len() > self.max_num_stored_pages
+                || memory_usage(&self.pages) > self.max_memory)
+                && self.pages.len() > self.num_visible_pages.max(2)
+            {
+                if remove_most_distant_page(&mut self.pages, self.last_requested_page_number)
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+        Ok(upgraded_page)
     }
 
-    fn process_command(&mut self, command: CacheCommand) -> Result<Option<CacheResponse>> {
+    async fn process_command(&mut self, command: CacheCommand) -> Result<Vec<CacheResponse>> {
         debug!("Processing command: {:?}...", command);
         match command {
-            CacheCommand::Cache(command) => Ok(self.cache_page(command.page, command.height)),
+            // Pages that fail in advance are reported once the reader gets to them
+            CacheCommand::Cache(command) => {
+                match self.cache_page(command.page, command.size).await {
+                    Ok(response) => Ok(response.into_iter().collect()),
+                    Err(e) => {
+                        error!("Failed caching page {}: {:#}", command.page + 1, e);
+                        Ok(Vec::new())
+                    }
+                }
+            }
+            CacheCommand::CacheInParallel(commands) => {
+                let (responses, failures) = self.cache_pages(commands).await;
+                for (page_number, e) in failures {
+                    error!("Failed caching page {}: {:#}", page_number + 1, e);
+                }
+                Ok(responses)
+            }
             CacheCommand::Retrieve(command) => match command {
-                RetrievePagesCommand::GetCurrentTwoPages { page_left_number } => {
-                    let page_left = self.get_page_or_cache(page_left_number)?;
-                    if let Ok(page_right) = self.get_page_or_cache(page_left_number + 1) {
-                        Ok(Some(CacheResponse::TwoPagesRetrieved {
-                            page_left,
-                            page_right,
-                        }))
-                    } else {
-                        Ok(Some(CacheResponse::SinglePageRetrieved { page: page_left }))
+                RetrievePagesCommand::GetCurrentTwoPages {
+                    page_left_number,
+                    size,
+                } => {
+                    let page_right_number = page_left_number + 1;
+                    let mut pages = self
+                        .retrieve_pages(&[page_left_number, page_right_number], size)
+                        .await
+                        .into_iter();
+                    let (page_left, page_right) = (pages.next().unwrap(), pages.next().unwrap());
+                    // The last page of an odd document has no right neighbour
+                    let has_page_right = page_right_number < self.num_pages;
+                    // The page that did render is shown, a placeholder takes the place of the other
+                    match (page_left, page_right) {
+                        (Ok(page_left), Ok(page_right)) => {
+                            Ok(vec![CacheResponse::TwoPagesRetrieved {
+                                page_left,
+                                page_right,
+                            }])
+                        }
+                        (Ok(page), Err(e)) if has_page_right => Ok(vec![
+                            CacheResponse::SinglePageRetrieved { page },
+                            CacheResponse::page_failed(page_right_number, e),
+                        ]),
+                        (Ok(page), Err(_)) => Ok(vec![CacheResponse::SinglePageRetrieved { page }]),
+                        (Err(e), page_right) => {
+                            let mut responses =
+                                vec![CacheResponse::page_failed(page_left_number, e)];
+                            match page_right {
+                                Ok(page) => responses.push(CacheResponse::PageRetrieved {
+                                    page_number: page_right_number,
+                                    page,
+                                }),
+                                Err(e) if has_page_right => {
+                                    responses.push(CacheResponse::page_failed(page_right_number, e))
+                                }
+                                Err(_) => {}
+                            }
+                            Ok(responses)
+                        }
                     }
                 }
-                RetrievePagesCommand::GetCurrentPage { page_number } => {
-                    let page = self.get_page_or_cache(page_number)?;
-                    Ok(Some(CacheResponse::SinglePageRetrieved { page }))
+                RetrievePagesCommand::GetCurrentPage { page_number, size } => {
+                    match self.retrieve_pages(&[page_number], size).await.remove(0) {
+                        Ok(page) => Ok(vec![CacheResponse::SinglePageRetrieved { page }]),
+                        Err(e) => Ok(vec![CacheResponse::page_failed(page_number, e)]),
+                    }
+                }
+                RetrievePagesCommand::GetPage { page_number } => {
+                    match self.get_page_or_cache(page_number).await {
+                        Ok(page) => Ok(vec![CacheResponse::PageRetrieved { page_number, page }]),
+                        Err(e) => Ok(vec![CacheResponse::page_failed(page_number, e)]),
+                    }
                 }
             },
         }
     }
 }
 
+fn memory_usage(pages: &BTreeMap<usize, CachedPage>) -> usize {
+    pages.values().map(|page| page.memory_size).sum()
+}
+
+fn remove_most_distant_page(
+    pages: &mut BTreeMap<usize, CachedPage>,
+    last_requested_page_number: PageNumber,
+) -> anyhow::Result<()> {
+    let (min_cached_page_number, min_cached_page) = pages
+        .pop_first()
+        .ok_or(anyhow!("The cache is empty, cannot remove first page"))?;
+    let (max_cached_page_number, max_cached_page) = pages
+        .pop_last()
+        .ok_or(anyhow!("The cache is empty, cannot remove last page"))?;
+
+    if last_requested_page_number.abs_diff(min_cached_page_number)
+        > last_requested_page_number.abs_diff(max_cached_page_number)
+    {
+        pages.insert(max_cached_page_number, max_cached_page);
+        debug!(
+            "Removed page {} from cache to keep size low...",
+            min_cached_page_number
+        );
+    } else {
+        pages.insert(min_cached_page_number, min_cached_page);
+        debug!(
+            "Removed page {} from cache to keep size low...",
+            max_cached_page_number
+        );
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum CacheCommand {
     Cache(CachePageCommand),
+    CacheInParallel(Vec<CachePageCommand>),
     Retrieve(RetrievePagesCommand),
 }
 
 #[derive(Debug)]
 pub struct CachePageCommand {
     page: PageNumber,
-    height: i32,
+    size: RenderSize,
 }
 
 #[derive(Debug)]
 pub enum RetrievePagesCommand {
-    GetCurrentTwoPages { page_left_number: PageNumber },
-    GetCurrentPage { page_number: PageNumber },
+    // The pages are shown in the size right away, not as previews
+    GetCurrentTwoPages {
+        page_left_number: PageNumber,
+        size: RenderSize,
+    },
+    GetCurrentPage {
+        page_number: PageNumber,
+        size: RenderSize,
+    },
+    // Any page coming into view while scrolling continuously
+    GetPage {
+        page_number: PageNumber,
+    },
 }
 
 pub enum CacheResponse {
@@ -176,115 +574,410 @@ pub enum CacheResponse {
         page_left: Rc<MyPageType>,
         page_right: Rc<MyPageType>,
     },
-    PageResolutionUpgraded {
+    PagesResolutionUpgraded {
+        pages: Vec<(PageNumber, Rc<MyPageType>)>,
+    },
+    PageRetrieved {
         page_number: PageNumber,
         page: Rc<MyPageType>,
     },
+    // Shown as a placeholder in place of the page, next to the pages that did render
+    PageFailed {
+        page_number: PageNumber,
+        message: String,
+    },
+    CommandFailed {
+        message: String,
+    },
+    Progress {
+        cached_pages: usize,
+        total_pages: usize,
+    },
+    PageBlank {
+        page_number: PageNumber,
+    },
+    Stats(CacheStats),
+}
+
+impl CacheResponse {
+    fn page_failed(page_number: PageNumber, e: anyhow::Error) -> Self {
+        error!("Failed retrieving page {}: {:#}", page_number, e);
+        // With the cause, e.g. the error of poppler
+        CacheResponse::PageFailed {
+            page_number,
+            message: format!("{:#}", e),
+        }
+    }
 }
 
 pub struct SyncCacheCommandChannel {
+    render_settings: RenderSettings,
+    rotations: HashMap<PageNumber, Rotation>,
+    crops: HashMap<PageNumber, Crop>,
+    offsets: HashMap<PageNumber, Offset>,
+    num_visible_pages: usize,
+    max_num_stored_pages: usize,
+    max_memory: usize,
+    preview_size: RenderSize,
+    render_scale: f64,
+    // Boxes in unscaled pixels the pages are rendered in between the preview and the full size
+    resolution_steps: Vec<i32>,
+    // The size the latest cache commands asked for
+    full_size: Option<RenderSize>,
     retrieve_commands: Vec<RetrievePagesCommand>,
     cache_commands: VecDeque<CachePageCommand>,
-    priority_cache_commands: Vec<CachePageCommand>,
+    // Pages rendered together, one batch for every resolution step
+    priority_cache_commands: VecDeque<Vec<CachePageCommand>>,
+    // Done once nothing else is left, and never dropped by turning pages
+    background_cache_commands: VecDeque<CachePageCommand>,
+    last_cache_commands_sent: Option<Instant>,
+    shut_down: bool,
 }
 
 pub struct SyncCacheCommandSender {
     channel: Rc<RefCell<SyncCacheCommandChannel>>,
+    // Wakes up the idle cache task, which sleeps until there is work
+    wake: Sender<()>,
 }
 
 pub struct SyncCacheCommandReceiver {
     channel: Rc<RefCell<SyncCacheCommandChannel>>,
+    wake: Receiver<()>,
 }
 
 impl SyncCacheCommandChannel {
     pub fn open() -> (SyncCacheCommandSender, SyncCacheCommandReceiver) {
         let channel = SyncCacheCommandChannel {
+            render_settings: RenderSettings::default(),
+            rotations: HashMap::new(),
+            crops: HashMap::new(),
+            offsets: HashMap::new(),
+            num_visible_pages: 2,
+            // Unlimited until the cache sets its own
+            max_num_stored_pages: usize::MAX,
+            max_memory: usize::MAX,
+            preview_size: RenderSize::PREVIEW,
+            render_scale: 1.0,
+            resolution_steps: Vec::new(),
+            full_size: None,
             retrieve_commands: Vec::new(),
             cache_commands: VecDeque::new(),
-            priority_cache_commands: Vec::new(),
+            priority_cache_commands: VecDeque::new(),
+            background_cache_commands: VecDeque::new(),
+            last_cache_commands_sent: None,
+            shut_down: false,
         };
         let channel = Rc::new(RefCell::new(channel));
 
+        // A single pending wake up is enough, the task takes every command once awake
+        let (wake_sender, wake_receiver) = async_channel::bounded(1);
         let sender = SyncCacheCommandSender {
             channel: Rc::clone(&channel),
+            wake: wake_sender,
+        };
+        let receiver = SyncCacheCommandReceiver {
+            channel,
+            wake: wake_receiver,
         };
-        let receiver = SyncCacheCommandReceiver { channel };
         (sender, receiver)
     }
+
+    // The steps a page of this size goes through after its preview, smallest first
+    fn resolution_steps(&self, size: RenderSize) -> Vec<RenderSize> {
+        let fit_mode = self.render_settings.fit_mode;
+        self.resolution_steps
+            .iter()
+            .map(|&step| {
+                RenderSize {
+                    width: step,
+                    height: step,
+                }
+                .scaled(self.render_scale)
+            })
+            .filter(|step| {
+                !self.preview_size.covers(*step, fit_mode) && !step.covers(size, fit_mode)
+            })
+            .collect()
+    }
 }
 
 impl SyncCacheCommandSender {
     pub fn is_channel_open(&self) -> bool {
-        Rc::strong_count(&self.channel) > 1
+        Rc::strong_count(&self.channel) > 1 && !self.channel.borrow().shut_down
+    }
+
+    // Stops the cache task right away instead of once every sender is dropped
+    pub fn shut_down(&self) {
+        self.channel.borrow_mut().shut_down = true;
+        self.wake();
+    }
+
+    fn wake(&self) {
+        // Full means a wake up is pending already
+        let _ = self.wake.try_send(());
     }
 
     pub fn send_retrieve_command(&self, command: RetrievePagesCommand) {
         // Make newest message the most important
         self.channel.borrow_mut().retrieve_commands.push(command);
+        self.wake();
     }
 
-    pub fn send_priority_cache_commands(&self, pages: &[PageNumber], height: i32) {
-        for &page in pages {
-            // Make message in front the most important
-            self.channel
-                .borrow_mut()
-                .priority_cache_commands
-                .push(CachePageCommand { page, height });
+    pub fn set_render_settings(&self, render_settings: RenderSettings) {
+        self.channel.borrow_mut().render_settings = render_settings;
+    }
+
+    pub fn set_rotations(&self, rotations: HashMap<PageNumber, Rotation>) {
+        self.channel.borrow_mut().rotations = rotations;
+    }
+
+    pub fn set_crops(&self, crops: HashMap<PageNumber, Crop>) {
+        self.channel.borrow_mut().crops = crops;
+    }
+
+    pub fn set_offsets(&self, offsets: HashMap<PageNumber, Offset>) {
+        self.channel.borrow_mut().offsets = offsets;
+    }
+
+    pub fn set_num_visible_pages(&self, num_visible_pages: usize) {
+        self.channel.borrow_mut().num_visible_pages = num_visible_pages;
+    }
+
+    // The cache keeps running with the new limits, e.g. once they are changed in the settings
+    pub fn set_cache_limits(&self, max_num_stored_pages: usize, max_memory: usize) {
+        let mut channel = self.channel.borrow_mut();
+        channel.max_num_stored_pages = max_num_stored_pages;
+        channel.max_memory = max_memory;
+    }
+
+    // The preview resolution and the resolution steps follow the scale of the full resolution
+    pub fn set_render_scale(&self, scale: f64) {
+        let mut channel = self.channel.borrow_mut();
+        channel.preview_size = RenderSize::PREVIEW.scaled(scale);
+        channel.render_scale = scale;
+    }
+
+    // Pages sharpen step by step instead of jumping from the preview to the full resolution
+    pub fn set_resolution_steps(&self, mut resolution_steps: Vec<i32>) {
+        resolution_steps.sort_unstable();
+        resolution_steps.dedup();
+        self.channel.borrow_mut().resolution_steps = resolution_steps;
+    }
+
+    pub fn send_priority_cache_commands(&self, pages: &[PageNumber], size: RenderSize) {
+        let mut channel = self.channel.borrow_mut();
+        channel.full_size = Some(size);
+        let batches: Vec<Vec<CachePageCommand>> = channel
+            .resolution_steps(size)
+            .into_iter()
+            .chain([size])
+            .map(|size| {
+                pages
+                    .iter()
+                    .map(|&page| CachePageCommand { page, size })
+                    .collect()
+            })
+            .collect();
+        // The newest pages are the most important, ahead of the steps of older ones
+        for (index, batch) in batches.into_iter().enumerate() {
+            channel.priority_cache_commands.insert(index, batch);
         }
+        self.wake();
+    }
+
+    pub fn send_background_cache_commands(&self, pages: &[PageNumber], size: RenderSize) {
+        let mut channel = self.channel.borrow_mut();
+        channel.full_size = Some(size);
+        channel
+            .background_cache_commands
+            .extend(pages.iter().map(|&page| CachePageCommand { page, size }));
+        self.wake();
+    }
+
+    // Surrounding pages of a page that was skipped quickly are not worth caching anymore
+    pub fn clear_cache_commands(&self) {
+        self.channel.borrow_mut().cache_commands.clear();
     }
 
-    pub fn send_cache_commands(&self, pages: &[PageNumber], height: i32) {
+    pub fn send_cache_commands(&self, pages: &[PageNumber], size: RenderSize) {
+        let last_sent = self
+            .channel
+            .borrow_mut()
+            .last_cache_commands_sent
+            .replace(Instant::now());
+        if last_sent.is_some_and(|last_sent| last_sent.elapsed() < CACHE_COMMAND_DEBOUNCE) {
+            debug!("Dropping pending cache commands of a skipped page");
+            self.clear_cache_commands();
+        }
+        self.channel.borrow_mut().full_size = Some(size);
+        let preview_size = self.channel.borrow().preview_size;
+        let resolution_steps = self.channel.borrow().resolution_steps(size);
         for &page in pages {
             // Make message in front the most important
             self.channel
                 .borrow_mut()
                 .cache_commands
-                .push_front(CachePageCommand { page, height: 100 }); // Cache with lower resolution
+                .push_front(CachePageCommand {
+                    page,
+                    size: preview_size,
+                }); // Cache with lower resolution
+            // Every step is shown as an upgrade of its own
+            self.channel.borrow_mut().cache_commands.extend(
+                resolution_steps
+                    .iter()
+                    .map(|&size| CachePageCommand { page, size }),
+            );
             self.channel
                 .borrow_mut()
                 .cache_commands
-                .push_back(CachePageCommand { page, height });
+                .push_back(CachePageCommand { page, size });
         }
+        self.wake();
     }
 }
 
 impl SyncCacheCommandReceiver {
     pub fn is_channel_open(&self) -> bool {
-        Rc::strong_count(&self.channel) > 1
+        Rc::strong_count(&self.channel) > 1 && !self.channel.borrow().shut_down
+    }
+
+    // Returns false once the sender is dropped, so nothing can arrive anymore
+    pub async fn wait_for_command(&self) -> bool {
+        self.wake.recv().await.is_ok()
+    }
+
+    pub fn render_settings(&self) -> RenderSettings {
+        self.channel.borrow().render_settings.clone()
+    }
+
+    pub fn preview_size(&self) -> RenderSize {
+        self.channel.borrow().preview_size
+    }
+
+    pub fn full_size(&self) -> Option<RenderSize> {
+        self.channel.borrow().full_size
+    }
+
+    pub fn rotations(&self) -> HashMap<PageNumber, Rotation> {
+        self.channel.borrow().rotations.clone()
+    }
+
+    pub fn crops(&self) -> HashMap<PageNumber, Crop> {
+        self.channel.borrow().crops.clone()
+    }
+
+    pub fn offsets(&self) -> HashMap<PageNumber, Offset> {
+        self.channel.borrow().offsets.clone()
+    }
+
+    pub fn num_visible_pages(&self) -> usize {
+        self.channel.borrow().num_visible_pages
+    }
+
+    pub fn cache_limits(&self) -> (usize, usize) {
+        let channel = self.channel.borrow();
+        (channel.max_num_stored_pages, channel.max_memory)
     }
 
     pub fn receive_most_important_command(&self) -> Option<CacheCommand> {
         let mut channel = self.channel.borrow_mut();
-        if let Some(command) = channel.priority_cache_commands.pop() {
-            return Some(CacheCommand::Cache(command));
-        } else if let Some(command) = channel.retrieve_commands.pop() {
+        // The pages the reader is waiting for go before any caching
+        if let Some(command) = channel.retrieve_commands.pop() {
             return Some(CacheCommand::Retrieve(command));
+        } else if let Some(commands) = channel.priority_cache_commands.pop_front() {
+            return Some(CacheCommand::CacheInParallel(commands));
         } else if let Some(command) = channel.cache_commands.pop_front() {
             return Some(CacheCommand::Cache(command));
+        } else if let Some(command) = channel.background_cache_commands.pop_front() {
+            return Some(CacheCommand::Cache(command));
         }
         None
     }
 }
 
-pub fn spawn_sync_cache<F>(document: Document, receiver: F) -> SyncCacheCommandSender
+/// Runs a page cache for the files on the main loop and passes every response to the receiver.
+/// The returned sender takes the commands, the cache stops once it is dropped or shut down.
+pub fn spawn_sync_cache<F>(
+    files: Vec<PathBuf>,
+    options: OpenOptions,
+    num_pages: usize,
+    max_num_stored_pages: usize,
+    max_memory: usize,
+    detect_blank_pages: bool,
+    receiver: F,
+) -> SyncCacheCommandSender
 where
     F: Fn(CacheResponse) + 'static,
 {
     let (command_sender, command_receiver) = SyncCacheCommandChannel::open();
+    command_sender.set_cache_limits(max_num_stored_pages, max_memory);
 
-    let mut cache = PageCache::new(document, 30);
+    let mut cache = PageCache::new(
+        RenderWorker::spawn(files, options),
+        num_pages,
+        max_num_stored_pages,
+        max_memory,
+    );
+    cache.detect_blank_pages = detect_blank_pages;
 
-    // Besides the name, it is not in another thread
+    // The cache itself lives on the main loop, only rasterization happens on the render thread
     glib::spawn_future_local(async move {
+        let mut busy_since = Instant::now();
         while command_receiver.is_channel_open() {
-            // Add delay to tell gtk to give rendering priority
-            timeout_future(Duration::from_millis(1)).await;
+            if busy_since.elapsed() > MAX_BUSY_TIME {
+                // Add delay to tell gtk to give rendering priority
+                timeout_future(Duration::from_millis(1)).await;
+                busy_since = Instant::now();
+            }
 
-            if let Some(command) = command_receiver.receive_most_important_command() {
-                if let Some(response) = cache.process_command(command).unwrap_or_else(|e| {
+            let command = command_receiver.receive_most_important_command();
+            if command.is_none() {
+                // Sleep instead of polling until the next command
+                if !command_receiver.wait_for_command().await {
+                    break;
+                }
+                busy_since = Instant::now();
+            }
+            if let Some(command) = command {
+                cache.set_render_settings(command_receiver.render_settings());
+                cache.set_preview_size(command_receiver.preview_size());
+                cache.set_full_size(command_receiver.full_size());
+                cache.set_rotations(command_receiver.rotations());
+                cache.set_crops(command_receiver.crops());
+                cache.set_offsets(command_receiver.offsets());
+                cache.set_num_visible_pages(command_receiver.num_visible_pages());
+                let (max_num_stored_pages, max_memory) = command_receiver.cache_limits();
+                cache.set_limits(max_num_stored_pages, max_memory);
+                let cached_pages_before = cache.num_cached_pages();
+                let stats_before = cache.stats();
+                let mut responses = cache.process_command(command).await.unwrap_or_else(|e| {
                     error!("Error processing command: {}", e);
-                    None
-                }) {
+                    vec![CacheResponse::CommandFailed {
+                        message: e.to_string(),
+                    }]
+                });
+                let cached_pages = cache.num_cached_pages();
+                if cached_pages != cached_pages_before {
+                    responses.push(CacheResponse::Progress {
+                        cached_pages,
+                        total_pages: num_pages,
+                    });
+                }
+                let stats = cache.stats();
+                if stats != stats_before {
+                    responses.push(CacheResponse::Stats(stats));
+                }
+                responses.extend(
+                    cache
+                        .take_new_blank_pages()
+                        .into_iter()
+                        .map(|page_number| CacheResponse::PageBlank { page_number }),
+                );
+                for response in responses {
+                    // Pages of a document that was closed while rendering are not shown
+                    if !command_receiver.is_channel_open() {
+                        break;
+                    }
                     // response_sender.send_blocking(response).unwrap();
                     debug!("Command processed, activating receiver....");
                     receiver(response);
@@ -292,6 +985,7 @@ where
                 }
             }
         }
+        debug!("Cache task stopped");
     });
 
     command_sender
@@ -0,0 +1,159 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    draw::{Color, FitMode, ViewMode},
+    APP_ID,
+};
+
+/// Settings edited by hand or in the settings dialog, unlike the state which the app writes itself.
+/// Command line flags take precedence over these defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub cache_pages: usize,
+    pub cache_memory_mb: usize,
+    // Pages rendered ahead of and behind the current ones
+    pub prefetch_pages: usize,
+    pub view_mode: ViewMode,
+    // Pages a spread advances by, 1 keeps the right page in view as the new left page
+    pub spread_step: usize,
+    pub fit_mode: FitMode,
+    pub invert: bool,
+    pub page_background: Color,
+    // Sharper but slower rendering, unless changed for a document
+    pub print_rendering: bool,
+    pub render_scale: Option<f64>,
+    pub click_zones: ClickZones,
+    pub mouse_buttons: MouseButtons,
+    // Crossfades from the preview to the full resolution of a page
+    pub fade_resolution_upgrades: bool,
+    // Boxes in pixels, like [300, 600], pages are rendered in on their way to the full resolution
+    pub resolution_steps: Vec<i32>,
+    // A page shown alone in a spread keeps its spread size and is centered
+    pub center_single_pages: bool,
+    // Pixels between the two pages of a spread
+    pub page_gap: i32,
+    // Large buttons at the edges for touchscreens, hidden until the pages are tapped
+    pub page_turn_buttons: bool,
+    // Tells when a page turn hits the first or last page, instead of doing nothing
+    pub edge_feedback: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cache_pages: 30,
+            cache_memory_mb: 512,
+            prefetch_pages: 3,
+            view_mode: ViewMode::Spread,
+            spread_step: 2,
+            fit_mode: FitMode::Height,
+            invert: false,
+            page_background: Color::WHITE,
+            print_rendering: false,
+            render_scale: None,
+            click_zones: ClickZones::default(),
+            mouse_buttons: MouseButtons::default(),
+            fade_resolution_upgrades: true,
+            resolution_steps: Vec::new(),
+            center_single_pages: true,
+            page_gap: 0,
+            page_turn_buttons: false,
+            edge_feedback: true,
+        }
+    }
+}
+
+/// Where clicks on the pages toggle fullscreen, close the app or turn pages
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClickZones {
+    // The top edge toggles fullscreen, its top right corner closes the app
+    pub edge_fraction: f64,
+    pub max_edge_size: f64,
+    // Clicks on the outer parts of both sides turn two pages at once, unless disabled
+    pub double_turns: bool,
+    pub double_turn_fraction: f64,
+}
+
+impl Default for ClickZones {
+    fn default() -> Self {
+        ClickZones {
+            edge_fraction: 0.1,
+            max_edge_size: 100.0,
+            double_turns: true,
+            double_turn_fraction: 0.25,
+        }
+    }
+}
+
+impl ClickZones {
+    pub fn edge_size(&self, height: f64) -> f64 {
+        f64::min(self.max_edge_size, height * self.edge_fraction)
+    }
+
+    // Zero without double turns, so every click on a side turns a single page
+    pub fn double_turn_width(&self, width: f64) -> f64 {
+        if self.double_turns {
+            width * self.double_turn_fraction
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Mouse buttons numbered like gtk does, 1 is left, 2 middle, 3 right, 8 back and 9 forward
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MouseButtons {
+    // Clicks with these buttons act depending on the click zone
+    pub zones: Vec<u32>,
+    // These turn pages wherever they are clicked
+    pub next: Vec<u32>,
+    pub previous: Vec<u32>,
+}
+
+impl Default for MouseButtons {
+    fn default() -> Self {
+        MouseButtons {
+            zones: vec![1],
+            next: vec![9],
+            previous: vec![3, 8],
+        }
+    }
+}
+
+fn config_file_path() -> PathBuf {
+    glib::user_config_dir().join(APP_ID).join("config.toml")
+}
+
+/// Styles the widgets, loaded after the theme so it can override it
+pub fn css_file_path() -> PathBuf {
+    glib::user_config_dir().join(APP_ID).join("style.css")
+}
+
+pub fn load() -> Config {
+    let path = config_file_path();
+    let Ok(content) = fs::read_to_string(&path) else {
+        debug!("No config at {}, using defaults", path.display());
+        return Config::default();
+    };
+    toml::from_str(&content).unwrap_or_else(|e| {
+        error!("Failed parsing config {}: {}", path.display(), e);
+        Config::default()
+    })
+}
+
+pub fn save(config: &Config) -> Result<()> {
+    let path = config_file_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, toml::to_string_pretty(config)?)?;
+    debug!("Saved config to {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use cairo::Context;
+use gtk::{gdk::prelude::GdkCairoContextExt, gdk_pixbuf::Pixbuf};
+use log::error;
+use poppler::{Document, Page};
+
+use crate::{
+    cache::PageNumber,
+    outline::{self, OutlineEntry},
+    page_map::MappedDocument,
+    setlist::Divider,
+};
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg"];
+
+/// Anything that provides pages, e.g. a PDF or a set of scanned images
+pub trait DocumentSource {
+    fn n_pages(&self) -> usize;
+    fn page(&self, page_number: PageNumber) -> Option<Rc<dyn PageSource>>;
+
+    fn outline(&self) -> Vec<OutlineEntry> {
+        Vec::new()
+    }
+
+    fn title(&self) -> Option<String> {
+        None
+    }
+
+    // The number printed on the page, e.g. roman numerals for the front matter
+    fn page_label(&self, _page_number: PageNumber) -> Option<String> {
+        None
+    }
+}
+
+pub trait PageSource {
+    fn size(&self) -> (f64, f64);
+    fn render(&self, context: &Context);
+
+    // Only PDFs are rendered differently for printing
+    fn render_for_printing(&self, context: &Context) {
+        self.render(context)
+    }
+
+    // Scans and generated pages have no text
+    fn contains_text(&self, _text: &str) -> bool {
+        false
+    }
+}
+
+impl DocumentSource for Document {
+    fn n_pages(&self) -> usize {
+        Document::n_pages(self) as usize
+    }
+
+    fn page(&self, page_number: PageNumber) -> Option<Rc<dyn PageSource>> {
+        Document::page(self, page_number as i32).map(|page| Rc::new(page) as Rc<dyn PageSource>)
+    }
+
+    fn outline(&self) -> Vec<OutlineEntry> {
+        outline::read_outline(self)
+    }
+
+    // Many PDFs carry an empty or whitespace title
+    fn title(&self) -> Option<String> {
+        Document::title(self)
+            .map(|title| title.trim().to_string())
+            .filter(|title| !title.is_empty())
+    }
+
+    fn page_label(&self, page_number: PageNumber) -> Option<String> {
+        Document::page(self, page_number as i32)
+            .and_then(|page| page.label())
+            .map(|label| label.trim().to_string())
+            .filter(|label| !label.is_empty())
+    }
+}
+
+impl PageSource for Page {
+    fn size(&self) -> (f64, f64) {
+        Page::size(self)
+    }
+
+    fn render(&self, context: &Context) {
+        Page::render(self, context)
+    }
+
+    fn render_for_printing(&self, context: &Context) {
+        Page::render_for_printing(self, context)
+    }
+
+    // Poppler ignores the case by default
+    fn contains_text(&self, text: &str) -> bool {
+        !Page::find_text(self, text).is_empty()
+    }
+}
+
+/// Every image is one page, images are only loaded when their page is requested
+pub struct ImageDocument {
+    files: Vec<PathBuf>,
+}
+
+struct ImagePage {
+    pixbuf: Pixbuf,
+}
+
+impl DocumentSource for ImageDocument {
+    fn n_pages(&self) -> usize {
+        self.files.len()
+    }
+
+    fn page(&self, page_number: PageNumber) -> Option<Rc<dyn PageSource>> {
+        let file = self.files.get(page_number)?;
+        match Pixbuf::from_file(file) {
+            Ok(pixbuf) => Some(Rc::new(ImagePage { pixbuf })),
+            Err(e) => {
+                error!("Failed loading image {}: {}", file.display(), e);
+                None
+            }
+        }
+    }
+}
+
+impl PageSource for ImagePage {
+    fn size(&self) -> (f64, f64) {
+        (self.pixbuf.width() as f64, self.pixbuf.height() as f64)
+    }
+
+    fn render(&self, context: &Context) {
+        context.set_source_pixbuf(&self.pixbuf, 0.0, 0.0);
+        if let Err(e) = context.paint() {
+            error!("Failed drawing image: {}", e);
+        }
+    }
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn open_image_folder(path: &Path) -> Result<ImageDocument> {
+    let mut files: Vec<PathBuf> = fs::read_dir(path)
+        .with_context(|| format!("Failed reading folder {}", path.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| is_image(file))
+        .collect();
+    if files.is_empty() {
+        bail!("The folder {} contains no images", path.display());
+    }
+    files.sort();
+    Ok(ImageDocument { files })
+}
+
+/// Returned by `open_document` for encrypted PDFs without the right password
+#[derive(Debug)]
+pub struct PasswordRequired {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for PasswordRequired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is protected by a password", self.path.display())
+    }
+}
+
+impl std::error::Error for PasswordRequired {}
+
+/// How files are opened, shared by the ui and the render thread so both see the same pages
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    // Setlists get a divider page between their pieces
+    pub with_dividers: bool,
+    pub passwords: HashMap<PathBuf, String>,
+    // Documents with a custom order of pages
+    pub page_maps: HashMap<PathBuf, Vec<PageNumber>>,
+}
+
+pub fn open_document(
+    file: impl AsRef<Path>,
+    password: Option<&str>,
+) -> Result<Rc<dyn DocumentSource>> {
+    let path: PathBuf = file.as_ref().to_path_buf();
+    if path.is_dir() {
+        return Ok(Rc::new(open_image_folder(&path)?));
+    }
+    if is_image(&path) {
+        if !path.is_file() {
+            bail!("The image {} does not exist", path.display());
+        }
+        return Ok(Rc::new(ImageDocument { files: vec![path] }));
+    }
+
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| anyhow!("The path {:?} is not valid UTF-8", path))?;
+    let uri = format!("file://{}", path_str);
+    let document = match Document::from_file(&uri, password) {
+        Ok(document) => document,
+        Err(e) if e.matches(poppler::Error::Encrypted) => {
+            return Err(PasswordRequired { path }.into());
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed opening {}", path.display())),
+    };
+    Ok(Rc::new(document))
+}
+
+/// Opens all files as one document, which has to have at least one page
+pub fn open_documents(files: &[PathBuf], options: &OpenOptions) -> Result<ConcatenatedDocument> {
+    let mut documents = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        if options.with_dividers && index > 0 {
+            documents
+                .push(Rc::new(Divider::new(&files[index - 1], file)) as Rc<dyn DocumentSource>);
+        }
+        let document = open_document(file, options.passwords.get(file).map(String::as_str))?;
+        match options.page_maps.get(file) {
+            Some(pages) => documents.push(Rc::new(
+                MappedDocument::new(document, pages.clone())
+                    .with_context(|| format!("Failed ordering {}", file.display()))?,
+            )),
+            None => documents.push(document),
+        }
+    }
+    let document = ConcatenatedDocument::new(documents);
+    // Nothing could be shown, and the cache would only request missing pages
+    if document.n_pages() == 0 {
+        bail!("This PDF contains no pages");
+    }
+    Ok(document)
+}
+
+/// Several documents presented as one continuous sequence of pages
+#[derive(Clone)]
+pub struct ConcatenatedDocument {
+    documents: Vec<Rc<dyn DocumentSource>>,
+    // Maps a global page number to the document and its local page number
+    page_index: Vec<(usize, PageNumber)>,
+}
+
+impl ConcatenatedDocument {
+    pub fn new(documents: Vec<Rc<dyn DocumentSource>>) -> Self {
+        let page_index = documents
+            .iter()
+            .enumerate()
+            .flat_map(|(document_index, document)| {
+                (0..document.n_pages()).map(move |local_page| (document_index, local_page))
+            })
+            .collect();
+        ConcatenatedDocument {
+            documents,
+            page_index,
+        }
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.page_index.len()
+    }
+
+    pub fn page(&self, page_number: PageNumber) -> Option<Rc<dyn PageSource>> {
+        let &(document_index, local_page) = self.page_index.get(page_number)?;
+        self.documents[document_index].page(local_page)
+    }
+
+    pub fn first_page_numbers(&self) -> Vec<PageNumber> {
+        let mut first_page_number = 0;
+        self.documents
+            .iter()
+            .map(|document| {
+                let page_number = first_page_number;
+                first_page_number += document.n_pages();
+                page_number
+            })
+            .collect()
+    }
+
+    // Only a single document has a title of its own
+    pub fn title(&self) -> Option<String> {
+        match self.documents.as_slice() {
+            [document] => document.title(),
+            _ => None,
+        }
+    }
+
+    // None if every page is labeled with its position, which is mostly the case
+    pub fn page_labels(&self) -> Option<Vec<String>> {
+        let labels: Vec<String> = self
+            .page_index
+            .iter()
+            .enumerate()
+            .map(|(page_number, &(document_index, local_page))| {
+                self.documents[document_index]
+                    .page_label(local_page)
+                    .unwrap_or_else(|| (page_number + 1).to_string())
+            })
+            .collect();
+        let is_numbered = labels
+            .iter()
+            .enumerate()
+            .all(|(page_number, label)| *label == (page_number + 1).to_string());
+        (!is_numbered).then_some(labels)
+    }
+
+    // Page numbers of every document are moved behind the pages of the documents before it
+    pub fn outline(&self) -> Vec<OutlineEntry> {
+        let mut first_page_number = 0;
+        let mut entries = Vec::new();
+        for document in self.documents.iter() {
+            entries.extend(document.outline().into_iter().map(|entry| OutlineEntry {
+                page_number: first_page_number + entry.page_number,
+                ..entry
+            }));
+            first_page_number += document.n_pages();
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A valid PDF whose page tree has no pages
+    fn write_empty_pdf(path: &Path) {
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [] /Count 0 >>",
+        ];
+        let mut pdf = String::from("%PDF-1.4\n");
+        let mut offsets = Vec::new();
+        for (index, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", index + 1, object));
+        }
+        let xref_offset = pdf.len();
+        pdf.push_str(&format!(
+            "xref\n0 {}\n0000000000 65535 f \n",
+            objects.len() + 1
+        ));
+        for offset in offsets {
+            pdf.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        pdf.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        ));
+        fs::write(path, pdf).unwrap();
+    }
+
+    #[test]
+    fn no_documents_are_rejected() {
+        let result = open_documents(&[], &OpenOptions::default());
+        assert!(result.is_err());
+    }
+
+    // Loading stops at this error, before a cache is spawned for the document
+    #[test]
+    fn empty_pdf_is_rejected() {
+        let path = std::env::temp_dir().join("music-reader-empty-document-test.pdf");
+        write_empty_pdf(&path);
+        let result = open_documents(&[path.clone()], &OpenOptions::default());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -1,73 +1,883 @@
-use std::rc::Rc;
+use std::{f64::consts::PI, path::Path, rc::Rc, time::Instant};
 
-use cairo::{Context, ImageSurface};
+use cairo::{Context, FontSlant, FontWeight, ImageSurface};
 use glib::Bytes;
-use gtk::gdk::Texture;
-use log::debug;
-use poppler::Page;
+use gtk::{
+    gdk::{MemoryFormat, MemoryTexture, Texture},
+    prelude::*,
+};
+use log::trace;
+use serde::{Deserialize, Serialize};
 
-pub fn draw_pages_to_texture(pages: &[Rc<Page>], area_height: i32) -> Texture {
-    let area_height = i32::max(100, area_height);
-    let total_width_normalized: f64 = pages
+use crate::{cache::PageNumber, config::ClickZones, document::PageSource};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    #[default]
+    Height,
+    Width,
+    // Like fitting the height, but small pages keep their own size instead of being blown up
+    Native,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ViewMode {
+    Single,
+    Spread,
+    // Two spreads above each other
+    Grid,
+}
+
+/// A color written like #f4ecd8 in the config
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color {
+    pub red: f64,
+    pub green: f64,
+    pub blue: f64,
+}
+
+impl Color {
+    pub const WHITE: Color = Color {
+        red: 1.0,
+        green: 1.0,
+        blue: 1.0,
+    };
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Color::WHITE
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.is_ascii())
+            .ok_or_else(|| format!("'{}' has to look like #rrggbb", value))?;
+        let channel = |index: usize| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .map(|channel| channel as f64 / 255.0)
+                .map_err(|_| format!("'{}' is not a valid color", value))
+        };
+        Ok(Color {
+            red: channel(0)?,
+            green: channel(2)?,
+            blue: channel(4)?,
+        })
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        let channel = |channel: f64| (channel * 255.0).round() as u8;
+        format!(
+            "#{:02x}{:02x}{:02x}",
+            channel(color.red),
+            channel(color.green),
+            channel(color.blue)
+        )
+    }
+}
+
+/// Everything besides the size that changes how a page is rendered
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderSettings {
+    pub fit_mode: FitMode,
+    pub invert: bool,
+    pub trim_margins: bool,
+    pub levels: Levels,
+    // Poppler's print rendering keeps thin staff lines at small sizes, but is slower
+    pub print_rendering: bool,
+    // Pixels between pages drawn side by side
+    pub page_gap: i32,
+    // Flipped horizontally, for reading the pages in a mirror
+    pub mirror: bool,
+    // Shows through pages without a background of their own, e.g. a paper tone
+    pub background: Color,
+    // Pixels per point at the natural size of a page, more than one on HiDPI displays
+    pub render_scale: f64,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            fit_mode: FitMode::default(),
+            invert: false,
+            trim_margins: false,
+            levels: Levels::default(),
+            print_rendering: false,
+            page_gap: 0,
+            mirror: false,
+            background: Color::default(),
+            render_scale: 1.0,
+        }
+    }
+}
+
+/// Brightness from -100 to 100 and contrast from 0 to 100, all zero keeps the page unchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Levels {
+    pub brightness: i32,
+    pub contrast: i32,
+}
+
+impl Levels {
+    pub fn is_neutral(&self) -> bool {
+        self.brightness == 0 && self.contrast == 0
+    }
+}
+
+/// The part of a page that gets drawn, in page coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageRegion {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl PageRegion {
+    pub fn full(page: &dyn PageSource) -> Self {
+        let (width, height) = page.size();
+        PageRegion {
+            x: 0.0,
+            y: 0.0,
+            width,
+            height,
+        }
+    }
+
+    // Moving the region the other way moves the page by the offset within its area
+    pub fn moved_by(self, offset: Offset) -> Self {
+        PageRegion {
+            x: self.x - offset.x,
+            y: self.y - offset.y,
+            ..self
+        }
+    }
+}
+
+/// Moves a page within its area in points, to line up scans that are slightly shifted
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Offset {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Offset {
+    pub fn is_none(&self) -> bool {
+        self.x == 0.0 && self.y == 0.0
+    }
+}
+
+/// A part of a page chosen by the reader, relative to the page size from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Crop {
+    // Smaller selections are rather accidental clicks
+    const MIN_SIZE: f64 = 0.05;
+
+    pub fn from_corners(start: (f64, f64), end: (f64, f64)) -> Option<Self> {
+        let crop = Crop {
+            x: start.0.min(end.0),
+            y: start.1.min(end.1),
+            width: (end.0 - start.0).abs(),
+            height: (end.1 - start.1).abs(),
+        };
+        (crop.width >= Self::MIN_SIZE && crop.height >= Self::MIN_SIZE).then_some(crop)
+    }
+
+    pub fn region(&self, page: &dyn PageSource) -> PageRegion {
+        let (width, height) = page.size();
+        PageRegion {
+            x: self.x * width,
+            y: self.y * height,
+            width: self.width * width,
+            height: self.height * height,
+        }
+    }
+}
+
+/// Clockwise rotation of a single page in degrees, one of 0, 90, 180 and 270
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Rotation(u32);
+
+impl Rotation {
+    pub fn rotated_clockwise(self) -> Rotation {
+        Rotation((self.0 + 90) % 360)
+    }
+
+    pub fn is_none(self) -> bool {
+        self.0 % 360 == 0
+    }
+
+    fn is_sideways(self) -> bool {
+        self.0 % 180 == 90
+    }
+}
+
+/// Shows a page rotated, so everything drawing it only sees the rotated size
+pub struct RotatedPage {
+    pub page: Rc<dyn PageSource>,
+    pub rotation: Rotation,
+}
+
+impl PageSource for RotatedPage {
+    fn size(&self) -> (f64, f64) {
+        let (width, height) = self.page.size();
+        if self.rotation.is_sideways() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    fn render(&self, context: &Context) {
+        self.rotate(context);
+        self.page.render(context);
+    }
+
+    fn render_for_printing(&self, context: &Context) {
+        self.rotate(context);
+        self.page.render_for_printing(context);
+    }
+}
+
+impl RotatedPage {
+    fn rotate(&self, context: &Context) {
+        let (width, height) = self.page.size();
+        // Move the corner that ends up top left to the origin
+        match self.rotation.0 % 360 {
+            90 => context.translate(height, 0.0),
+            180 => context.translate(width, height),
+            270 => context.translate(0.0, width),
+            _ => {}
+        }
+        context.rotate((self.rotation.0 % 360) as f64 * PI / 180.0);
+    }
+}
+
+pub fn rotate_page(page: Rc<dyn PageSource>, rotation: Rotation) -> Rc<dyn PageSource> {
+    if rotation.is_none() {
+        page
+    } else {
+        Rc::new(RotatedPage { page, rotation })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderSize {
+    pub width: i32,
+    pub height: i32,
+}
+
+impl RenderSize {
+    // Low resolution used until the full resolution is rendered
+    pub const PREVIEW: RenderSize = RenderSize {
+        width: 100,
+        height: 100,
+    };
+
+    // Widgets have no size before they are shown for the first time
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn scaled(&self, scale: f64) -> RenderSize {
+        RenderSize {
+            width: (self.width as f64 * scale).round() as i32,
+            height: (self.height as f64 * scale).round() as i32,
+        }
+    }
+
+    pub fn covers(&self, other: RenderSize, fit_mode: FitMode) -> bool {
+        match fit_mode {
+            FitMode::Height | FitMode::Native => self.height >= other.height,
+            FitMode::Width => self.width >= other.width,
+        }
+    }
+}
+
+/// Raw pixels of a cairo `Rgb24` surface, which can be sent between threads
+pub struct Pixels {
+    pub width: i32,
+    pub height: i32,
+    pub stride: usize,
+    pub data: Vec<u8>,
+}
+
+// Cairo stores Rgb24 pixels as native endian 32 bit integers with an unused upper byte
+#[cfg(target_endian = "little")]
+const RGB24_MEMORY_FORMAT: MemoryFormat = MemoryFormat::B8g8r8x8;
+#[cfg(target_endian = "big")]
+const RGB24_MEMORY_FORMAT: MemoryFormat = MemoryFormat::X8r8g8b8;
+
+const GUTTER_COLOR: f64 = 0.6;
+
+// Page sizes are given in points, of which there are 72 per inch
+const POINTS_PER_INCH: f64 = 72.0;
+
+// Scaled to the page area like any other page, so only its aspect ratio matters
+const PLACEHOLDER_SIZE: RenderSize = RenderSize {
+    width: 420,
+    height: 594,
+};
+
+/// Renders the pages side by side into one texture of the given size, e.g. a whole spread.
+/// The pages are scaled to fit, as the fit mode of the settings asks for.
+pub fn draw_pages_to_texture(
+    pages: &[Rc<dyn PageSource>],
+    size: RenderSize,
+    settings: &RenderSettings,
+) -> Texture {
+    pixels_to_texture(draw_pages_to_pixels(pages, size, settings))
+}
+
+/// Renders the pages side by side at the given resolution and writes them to a PNG file
+pub fn export_pages_to_png(
+    pages: &[Rc<dyn PageSource>],
+    dpi: f64,
+    settings: &RenderSettings,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let height = pages.iter().map(|page| page.size().1).fold(0.0, f64::max) * dpi / POINTS_PER_INCH;
+    // The width follows from the height and the aspect ratio of the pages
+    let size = RenderSize {
+        width: 0,
+        height: height.round() as i32,
+    };
+    let settings = RenderSettings {
+        fit_mode: FitMode::Height,
+        ..settings.clone()
+    };
+    draw_pages_to_texture(pages, size, &settings).save_to_png(path)?;
+    Ok(())
+}
+
+/// Whether the pixels all have the same color, like a page that poppler failed to draw.
+/// Only a grid of pixels is compared, so large pages stay cheap to check.
+pub fn is_blank(pixels: &Pixels) -> bool {
+    const SAMPLES_PER_SIDE: usize = 64;
+    let step_x = (pixels.width as usize / SAMPLES_PER_SIDE).max(1);
+    let step_y = (pixels.height as usize / SAMPLES_PER_SIDE).max(1);
+    // The upper byte of the native endian integer is unused
+    let color = |x: usize, y: usize| {
+        let offset = y * pixels.stride + x * 4;
+        let bytes = [
+            pixels.data[offset],
+            pixels.data[offset + 1],
+            pixels.data[offset + 2],
+            pixels.data[offset + 3],
+        ];
+        u32::from_ne_bytes(bytes) & 0x00FF_FFFF
+    };
+    let first = color(0, 0);
+    (0..pixels.height as usize).step_by(step_y).all(|y| {
+        (0..pixels.width as usize)
+            .step_by(step_x)
+            .all(|x| color(x, y) == first)
+    })
+}
+
+pub fn pixels_to_texture(pixels: Pixels) -> Texture {
+    MemoryTexture::new(
+        pixels.width,
+        pixels.height,
+        RGB24_MEMORY_FORMAT,
+        &Bytes::from_owned(pixels.data),
+        pixels.stride,
+    )
+    .upcast()
+}
+
+pub fn draw_pages_to_pixels(
+    pages: &[Rc<dyn PageSource>],
+    size: RenderSize,
+    settings: &RenderSettings,
+) -> Pixels {
+    let pages: Vec<(Rc<dyn PageSource>, PageRegion)> = pages
         .iter()
-        .map(|page| page.size())
-        .map(|(w, h)| w / h)
-        .sum();
-    let area_width = (total_width_normalized * area_height as f64 + 0.5) as i32;
+        .map(|page| {
+            let region = if settings.trim_margins {
+                find_content_region(page)
+            } else {
+                PageRegion::full(page)
+            };
+            (Rc::clone(page), region)
+        })
+        .collect();
+    draw_page_regions_to_pixels(&pages, size, settings)
+}
+
+/// Like `draw_pages_to_pixels`, but with the regions already known, e.g. from a cache
+pub fn draw_page_regions_to_pixels(
+    pages: &[(Rc<dyn PageSource>, PageRegion)],
+    size: RenderSize,
+    settings: &RenderSettings,
+) -> Pixels {
+    let start = Instant::now();
+    let total_width_normalized = total_width_normalized(pages);
+    let total_gap = total_gap(pages, settings);
+    let area_height = match settings.fit_mode {
+        FitMode::Height | FitMode::Native => size.height,
+        FitMode::Width => ((size.width - total_gap) as f64 / total_width_normalized + 0.5) as i32,
+    };
+    let area_height = i32::max(100, area_height);
+    let area_width = (total_width_normalized * area_height as f64 + 0.5) as i32 + total_gap;
 
-    let surface = ImageSurface::create(cairo::Format::Rgb24, area_width, area_height).unwrap();
+    let mut surface = ImageSurface::create(cairo::Format::Rgb24, area_width, area_height).unwrap();
     let context = Context::new(&surface).unwrap();
-    draw_pages(pages, &context, area_width, area_height);
+    draw_pages(pages, &context, area_width, area_height, settings);
+    // The surface data can only be accessed without any context referencing it
+    drop(context);
+
+    if !settings.levels.is_neutral() {
+        adjust_levels(&mut surface, settings.levels);
+    }
+    if settings.invert {
+        invert_colors(&mut surface);
+    }
+
+    let pixels = surface_to_pixels(surface);
+    // Enabled with RUST_LOG=music_reader::draw=trace, to find out which renders are slow
+    trace!(
+        "Rendered {} page(s) for {:?} at {}x{} in {} ms",
+        pages.len(),
+        size,
+        area_width,
+        area_height,
+        start.elapsed().as_millis()
+    );
+    pixels
+}
+
+fn surface_to_pixels(mut surface: ImageSurface) -> Pixels {
+    surface.flush();
+    let width = surface.width();
+    let height = surface.height();
+    let stride = surface.stride() as usize;
+    let data = surface.data().unwrap().to_vec();
+    Pixels {
+        width,
+        height,
+        stride,
+        data,
+    }
+}
+
+/// Stands in for a page that failed to render, with a warning sign and the page number
+pub fn draw_placeholder_to_texture(page_number: PageNumber) -> Texture {
+    const BACKGROUND: f64 = 0.8;
+    let surface = ImageSurface::create(
+        cairo::Format::Rgb24,
+        PLACEHOLDER_SIZE.width,
+        PLACEHOLDER_SIZE.height,
+    )
+    .unwrap();
+    let context = Context::new(&surface).unwrap();
+    let width = PLACEHOLDER_SIZE.width as f64;
+    let top = PLACEHOLDER_SIZE.height as f64 * 0.3;
+    let center_x = width / 2.0;
+    context.set_source_rgb(BACKGROUND, BACKGROUND, BACKGROUND);
+    context.paint().unwrap();
+
+    context.set_source_rgb(0.45, 0.45, 0.45);
+    context.move_to(center_x, top);
+    context.line_to(center_x + 60.0, top + 100.0);
+    context.line_to(center_x - 60.0, top + 100.0);
+    context.close_path();
+    context.fill().unwrap();
+    // The exclamation mark is cut out of the triangle in the background color
+    context.set_source_rgb(BACKGROUND, BACKGROUND, BACKGROUND);
+    context.rectangle(center_x - 5.0, top + 35.0, 10.0, 38.0);
+    context.rectangle(center_x - 5.0, top + 80.0, 10.0, 10.0);
+    context.fill().unwrap();
+
+    context.set_source_rgb(0.3, 0.3, 0.3);
+    context.select_font_face("Sans", FontSlant::Normal, FontWeight::Bold);
+    context.set_font_size(20.0);
+    let text = format!("Page {} could not be rendered", page_number + 1);
+    if let Ok(extents) = context.text_extents(&text) {
+        context.move_to(
+            (width - extents.width()) / 2.0 - extents.x_bearing(),
+            top + 150.0,
+        );
+        let _ = context.show_text(&text);
+    }
+    drop(context);
+
+    pixels_to_texture(surface_to_pixels(surface))
+}
+
+/// Finds the bounding box of everything that isn't white, including a small padding.
+/// This renders the page, so callers should cache the result.
+pub fn find_content_region(page: &dyn PageSource) -> PageRegion {
+    const DETECTION_HEIGHT: f64 = 300.0;
+    const WHITE_THRESHOLD: u8 = 230;
+    const PADDING: f64 = 0.02;
+
+    let full_region = PageRegion::full(page);
+    let scale = DETECTION_HEIGHT / full_region.height;
+    let width = (full_region.width * scale).ceil() as i32;
+    let height = DETECTION_HEIGHT as i32;
+
+    let mut surface = ImageSurface::create(cairo::Format::Rgb24, width, height).unwrap();
+    let context = Context::new(&surface).unwrap();
+    context.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+    context.paint().unwrap();
+    context.scale(scale, scale);
+    page.render(&context);
+    drop(context);
+    surface.flush();
+
+    let stride = surface.stride() as usize;
+    let data = surface.data().unwrap();
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let pixel = &data[y * stride + x * 4..y * stride + x * 4 + 3];
+            if pixel.iter().all(|&channel| channel >= WHITE_THRESHOLD) {
+                continue;
+            }
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((min_x, min_y, max_x, max_y)) => {
+                    (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+                }
+            });
+        }
+    }
 
-    let mut stream: Vec<u8> = Vec::new();
-    surface.write_to_png(&mut stream).unwrap();
-    Texture::from_bytes(&Bytes::from(&stream)).unwrap()
+    let Some((min_x, min_y, max_x, max_y)) = bounds else {
+        // Nothing to trim on an empty page
+        return full_region;
+    };
+    let padding_x = full_region.width * PADDING;
+    let padding_y = full_region.height * PADDING;
+    let x = f64::max(0.0, min_x as f64 / scale - padding_x);
+    let y = f64::max(0.0, min_y as f64 / scale - padding_y);
+    let right = f64::min(full_region.width, (max_x + 1) as f64 / scale + padding_x);
+    let bottom = f64::min(full_region.height, (max_y + 1) as f64 / scale + padding_y);
+    PageRegion {
+        x,
+        y,
+        width: right - x,
+        height: bottom - y,
+    }
+}
+
+// Brightness shifts every channel, contrast steepens an S-curve around middle grey.
+// The curve keeps black and white in place, so even the highest contrast doesn't clip everything.
+fn adjust_levels(surface: &mut ImageSurface, levels: Levels) {
+    let steepness = levels.contrast as f64 / 10.0;
+    let lookup: Vec<u8> = (0..=255)
+        .map(|channel| {
+            let value = (channel as f64 / 255.0 + levels.brightness as f64 / 100.0).clamp(0.0, 1.0);
+            let value = if steepness > 0.0 {
+                0.5 + 0.5 * (steepness * (value - 0.5)).tanh() / (steepness * 0.5).tanh()
+            } else {
+                value
+            };
+            (value * 255.0).round() as u8
+        })
+        .collect();
+    surface.flush();
+    {
+        let mut data = surface.data().unwrap();
+        for channel in data.iter_mut() {
+            *channel = lookup[*channel as usize];
+        }
+    }
+    surface.mark_dirty();
 }
 
-fn draw_pages(pages: &[Rc<Page>], context: &Context, area_width: i32, area_height: i32) {
+/// Shades the click zones, so they can be checked while adjusting them in the config
+pub fn draw_click_zones(context: &Context, width: f64, height: f64, zones: &ClickZones) {
+    let edge_size = zones.edge_size(height);
+    let double_turn_width = zones.double_turn_width(width);
+    let below_edge = height - edge_size;
+    let rectangles = [
+        // Fullscreen toggle and closing corner
+        (0.0, 0.0, width - edge_size, edge_size, (0.2, 0.4, 1.0)),
+        (
+            width - edge_size,
+            0.0,
+            edge_size,
+            edge_size,
+            (1.0, 0.2, 0.2),
+        ),
+        // Turning a single page, the center divides backward from forward
+        (
+            double_turn_width,
+            edge_size,
+            width / 2.0 - double_turn_width,
+            below_edge,
+            (0.2, 0.8, 0.2),
+        ),
+        (
+            width / 2.0,
+            edge_size,
+            width / 2.0 - double_turn_width,
+            below_edge,
+            (0.2, 0.8, 0.2),
+        ),
+        // Turning two pages
+        (
+            0.0,
+            edge_size,
+            double_turn_width,
+            below_edge,
+            (1.0, 0.7, 0.1),
+        ),
+        (
+            width - double_turn_width,
+            edge_size,
+            double_turn_width,
+            below_edge,
+            (1.0, 0.7, 0.1),
+        ),
+    ];
+    for (x, y, rectangle_width, rectangle_height, (red, green, blue)) in rectangles
+        .into_iter()
+        .filter(|&(_, _, rectangle_width, _, _)| rectangle_width > 0.0)
+    {
+        context.rectangle(x, y, rectangle_width, rectangle_height);
+        context.set_source_rgba(red, green, blue, 0.2);
+        context.fill_preserve().unwrap();
+        context.set_source_rgba(red, green, blue, 0.8);
+        context.stroke().unwrap();
+    }
+}
+
+// White becomes dark grey and black becomes light grey, which is easier on the eyes than pure black
+fn invert_colors(surface: &mut ImageSurface) {
+    const DARKEST: u32 = 30;
+    const LIGHTEST: u32 = 220;
+    surface.flush();
+    {
+        let mut data = surface.data().unwrap();
+        // Rgb24 ignores the unused fourth byte, so it can be transformed with the others
+        for channel in data.iter_mut() {
+            let inverted = 255 - *channel as u32;
+            *channel = (DARKEST + inverted * (LIGHTEST - DARKEST) / 255) as u8;
+        }
+    }
+    surface.mark_dirty();
+}
+
+fn draw_pages(
+    pages: &[(Rc<dyn PageSource>, PageRegion)],
+    context: &Context,
+    area_width: i32,
+    area_height: i32,
+    settings: &RenderSettings,
+) {
     if pages.is_empty() {
         return;
     }
+    let total_gap = total_gap(pages, settings) as f64;
     let area_width = area_width as f64;
     let area_height = area_height as f64;
 
-    // Total width if height of every page was 1
-    let total_width_normalized: f64 = pages
+    // Every page gets the same scale, so the tallest one fills the height
+    // and shorter ones keep their size relative to it
+    let height_to_scale_to = f64::min(
+        (area_width - total_gap) / total_width_normalized(pages),
+        area_height,
+    );
+    let scale = height_to_scale_to / max_region_height(pages);
+    // The natural size at most, the rest of the area stays white around the pages
+    let scale = if settings.fit_mode == FitMode::Native {
+        f64::min(scale, settings.render_scale)
+    } else {
+        scale
+    };
+    let total_width: f64 = pages
         .iter()
-        .map(|page| page.size())
-        .map(|(w, h)| w / h)
-        .sum();
-    // let height_to_scale_to = f64::min(area_width / total_width_normalized, area_height);
-    let height_to_scale_to = area_height;
-    let total_width = total_width_normalized * height_to_scale_to;
+        .map(|(_, region)| region.width * scale)
+        .sum::<f64>()
+        + total_gap;
 
-    context.set_source_rgba(1.0, 1.0, 1.0, 1.0);
-    context.translate(
-        (area_width - total_width) / 2.0,
-        (area_height - height_to_scale_to) / 2.0,
-    );
-    context.save().unwrap();
+    // Poppler sometimes crops white border, and shorter pages leave a gap, draw it manually
+    let background = settings.background;
+    context.set_source_rgb(background.red, background.green, background.blue);
+    context.rectangle(0.0, 0.0, area_width, area_height);
+    context.fill().unwrap();
 
-    for page in pages {
-        let (page_width, page_height) = page.size();
-        let scale = height_to_scale_to / page_height;
-        let scaled_width = page_width * scale;
+    // The whole area is flipped, so pages side by side also swap places like in a mirror
+    if settings.mirror {
+        context.translate(area_width, 0.0);
+        context.scale(-1.0, 1.0);
+    }
+    context.translate((area_width - total_width) / 2.0, 0.0);
+    context.save().unwrap();
 
-        debug!(
-            "drawing with size: {}, {}",
-            scaled_width, height_to_scale_to
-        );
+    for (index, (page, region)) in pages.iter().enumerate() {
+        let scaled_width = region.width * scale;
+        let scaled_height = region.height * scale;
 
-        // context.translate(total_width_of_rendered_pages, 0.0);
-        // Poppler sometimes crops white border, draw it manually
-        context.rectangle(0.0, 0.0, scaled_width, height_to_scale_to);
-        context.fill().unwrap();
+        trace!("Drawing page at {:.0}x{:.0}", scaled_width, scaled_height);
 
+        context.translate(0.0, (area_height - scaled_height) / 2.0);
+        // Only the region may be visible, the rest of the page would overlap the next one
+        context.rectangle(0.0, 0.0, scaled_width, scaled_height);
+        context.clip();
         context.scale(scale, scale);
-        page.render(context);
+        context.translate(-region.x, -region.y);
+        if settings.print_rendering {
+            page.render_for_printing(context);
+        } else {
+            page.render(context);
+        }
 
         context.restore().unwrap();
         context.translate(scaled_width, 0.0);
+        // A darker gutter, so facing pages don't read as one
+        if index + 1 < pages.len() && settings.page_gap > 0 {
+            let gap = settings.page_gap as f64;
+            context.set_source_rgb(GUTTER_COLOR, GUTTER_COLOR, GUTTER_COLOR);
+            context.rectangle(0.0, 0.0, gap, area_height);
+            context.fill().unwrap();
+            context.translate(gap, 0.0);
+        }
         context.save().unwrap();
     }
 }
+
+fn total_gap(pages: &[(Rc<dyn PageSource>, PageRegion)], settings: &RenderSettings) -> i32 {
+    settings.page_gap * pages.len().saturating_sub(1) as i32
+}
+
+fn max_region_height(pages: &[(Rc<dyn PageSource>, PageRegion)]) -> f64 {
+    pages
+        .iter()
+        .map(|(_, region)| region.height)
+        .fold(0.0, f64::max)
+}
+
+// Total width if the tallest page had a height of 1
+fn total_width_normalized(pages: &[(Rc<dyn PageSource>, PageRegion)]) -> f64 {
+    let max_height = max_region_height(pages);
+    pages
+        .iter()
+        .map(|(_, region)| region.width / max_height)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A black rectangle of the given size in points
+    struct SolidPage {
+        width: f64,
+        height: f64,
+    }
+
+    impl PageSource for SolidPage {
+        fn size(&self) -> (f64, f64) {
+            (self.width, self.height)
+        }
+
+        fn render(&self, context: &Context) {
+            context.set_source_rgb(0.0, 0.0, 0.0);
+            context.rectangle(0.0, 0.0, self.width, self.height);
+            context.fill().unwrap();
+        }
+    }
+
+    fn is_dark(pixels: &Pixels, x: usize, y: usize) -> bool {
+        let offset = y * pixels.stride + x * 4;
+        let bytes = [
+            pixels.data[offset],
+            pixels.data[offset + 1],
+            pixels.data[offset + 2],
+            pixels.data[offset + 3],
+        ];
+        u32::from_ne_bytes(bytes) & 0x00FF_FFFF == 0
+    }
+
+    #[test]
+    fn pages_of_different_heights_are_centered() {
+        let tall: Rc<dyn PageSource> = Rc::new(SolidPage {
+            width: 40.0,
+            height: 80.0,
+        });
+        let short: Rc<dyn PageSource> = Rc::new(SolidPage {
+            width: 40.0,
+            height: 40.0,
+        });
+        let pages: Vec<(Rc<dyn PageSource>, PageRegion)> = [tall, short]
+            .into_iter()
+            .map(|page| {
+                let region = PageRegion::full(page.as_ref());
+                (page, region)
+            })
+            .collect();
+        // Small pages keep their size, which leaves room around them in the 200x200 area
+        let settings = RenderSettings {
+            fit_mode: FitMode::Native,
+            ..RenderSettings::default()
+        };
+        let size = RenderSize {
+            width: 200,
+            height: 200,
+        };
+        let pixels = draw_page_regions_to_pixels(&pages, size, &settings);
+        assert_eq!((pixels.width, pixels.height), (200, 200));
+
+        // Both pages together are 80 wide, so they start at 60 and meet at 100
+        assert!(!is_dark(&pixels, 50, 100));
+        assert!(is_dark(&pixels, 65, 100));
+        assert!(is_dark(&pixels, 135, 100));
+        assert!(!is_dark(&pixels, 150, 100));
+
+        // The tall page spans from 60 to 140, the short one from 80 to 120
+        assert!(!is_dark(&pixels, 80, 55));
+        assert!(is_dark(&pixels, 80, 65));
+        assert!(is_dark(&pixels, 80, 135));
+        assert!(!is_dark(&pixels, 80, 145));
+        assert!(!is_dark(&pixels, 120, 75));
+        assert!(is_dark(&pixels, 120, 85));
+        assert!(is_dark(&pixels, 120, 115));
+        assert!(!is_dark(&pixels, 120, 125));
+    }
+
+    #[test]
+    fn natural_size_follows_render_scale() {
+        let page: Rc<dyn PageSource> = Rc::new(SolidPage {
+            width: 40.0,
+            height: 80.0,
+        });
+        let region = PageRegion::full(page.as_ref());
+        let settings = RenderSettings {
+            fit_mode: FitMode::Native,
+            render_scale: 2.0,
+            ..RenderSettings::default()
+        };
+        let size = RenderSize {
+            width: 200,
+            height: 200,
+        };
+        let pixels = draw_page_regions_to_pixels(&[(page, region)], size, &settings);
+
+        // Two pixels per point, so the page is 80 wide and spans from 60 to 140
+        assert!(!is_dark(&pixels, 55, 100));
+        assert!(is_dark(&pixels, 65, 100));
+        assert!(is_dark(&pixels, 135, 100));
+        assert!(!is_dark(&pixels, 145, 100));
+    }
+}
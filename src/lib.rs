@@ -0,0 +1,14 @@
+//! Rendering and caching of pages, usable without the window of the music reader.
+//! A document is opened with `document::open_documents`, `cache::spawn_sync_cache` keeps its
+//! pages around the reader rendered, and `draw` turns pages into textures directly.
+
+pub mod cache;
+pub mod config;
+pub mod document;
+pub mod draw;
+pub mod outline;
+pub mod page_map;
+pub mod render_worker;
+pub mod setlist;
+
+pub const APP_ID: &str = "de.frajul.music-reader";
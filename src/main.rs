@@ -1,41 +1,206 @@
-mod cache;
-mod draw;
+mod annotations;
+mod batch;
+mod metronome;
+mod notes;
+mod pedal;
+mod practice_log;
+mod prefs;
+mod remote;
+mod search;
+mod state;
+mod thumbnails;
 mod ui;
 
-use clap::Parser;
+use batch::PageRange;
+use clap::{Parser, Subcommand};
+use config::Config;
 use env_logger::Env;
 use gtk::prelude::*;
 use gtk::Application;
 use log::debug;
+use music_reader::{cache, config, document, draw, outline, page_map, setlist, APP_ID};
+use pedal::{MidiTrigger, PedalMapping};
 use std::cell::RefCell;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::rc::Rc;
 use ui::Ui;
 
-const APP_ID: &str = "de.frajul.music-reader";
-
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(author, version, about, args_conflicts_with_subcommands = true)]
 struct Cli {
-    file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    file: Vec<PathBuf>,
+    /// Play the pieces listed in this file in order, one path per line or as JSON array
+    #[arg(long, conflicts_with = "file")]
+    setlist: Option<PathBuf>,
+    /// Open the file at this page instead of where reading stopped last time
+    #[arg(long, requires = "file", value_parser = clap::value_parser!(u32).range(1..))]
+    page: Option<u32>,
+    /// Number of pages to keep in the page cache [default: 30]
+    #[arg(long, value_parser = parse_cache_pages)]
+    cache_pages: Option<usize>,
+    /// Memory in megabytes the full resolution pages in the cache may take [default: 512]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    cache_memory_mb: Option<u32>,
+    /// Number of pages around the current ones to render in advance [default: 3]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    prefetch: Option<u32>,
+    /// Render every page in full resolution after opening, so no page has to wait during a performance
+    #[arg(long)]
+    precache_all: bool,
+    /// Start in fullscreen, e.g. for a music stand
+    #[arg(long)]
+    fullscreen: bool,
+    /// Turn pages instantly instead of sliding them
+    #[arg(long)]
+    instant_page_turns: bool,
+    /// Number of pages to advance in spreads, 1 keeps the right page in view as the new left page [default: 2]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+    spread_step: Option<u8>,
+    /// Turn pages with a MIDI foot pedal whose name contains this text
+    #[arg(long)]
+    midi_device: Option<String>,
+    /// MIDI message turning to the next page, as note:<n> or cc:<n>
+    #[arg(long, default_value = "cc:64")]
+    midi_next: MidiTrigger,
+    /// MIDI message turning to the previous page, as note:<n> or cc:<n>
+    #[arg(long, default_value = "cc:67")]
+    midi_previous: MidiTrigger,
+    /// Accept next, prev and goto <page> commands, one per line, over TCP on this address
+    #[arg(long, value_name = "ADDR:PORT")]
+    listen: Option<SocketAddr>,
+    /// Read documents from right to left, unless changed for a document
+    #[arg(long)]
+    right_to_left: bool,
+    /// Resolution of rendered pages relative to the screen, defaults to the display scale factor
+    #[arg(long, value_parser = parse_render_scale)]
+    render_scale: Option<f64>,
+    /// Warn about pages that are rendered in a single color, e.g. because of broken resources
+    #[arg(long)]
+    detect_blank_pages: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render pages to PNG files without opening a window
+    Render {
+        file: PathBuf,
+        /// Pages to render, like 1-5 or 3 [default: all pages]
+        #[arg(long)]
+        pages: Option<PageRange>,
+        /// Resolution of the images in dots per inch
+        #[arg(long, default_value_t = 150.0, value_parser = parse_dpi)]
+        dpi: f64,
+        /// Folder the images are saved in, named after the file and the page number
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+    },
+}
+
+fn parse_cache_pages(value: &str) -> Result<usize, String> {
+    let cache_pages: usize = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid number of pages", value))?;
+    if cache_pages < 2 {
+        return Err("The cache has to hold at least 2 pages".to_string());
+    }
+    Ok(cache_pages)
+}
+
+fn parse_render_scale(value: &str) -> Result<f64, String> {
+    let render_scale: f64 = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid scale", value))?;
+    if render_scale.is_nan() || render_scale <= 0.0 {
+        return Err("The render scale has to be positive".to_string());
+    }
+    Ok(render_scale)
+}
+
+fn parse_dpi(value: &str) -> Result<f64, String> {
+    let dpi: f64 = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid resolution", value))?;
+    if dpi.is_nan() || dpi <= 0.0 {
+        return Err("The resolution has to be positive".to_string());
+    }
+    Ok(dpi)
 }
 
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
     let cli = Cli::parse();
     debug!("Parse args");
+    if let Some(Command::Render {
+        file,
+        pages,
+        dpi,
+        out,
+    }) = cli.command.as_ref()
+    {
+        if let Err(e) = batch::render_to_png(file, *pages, *dpi, out) {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = config::load();
     let app = Application::builder().application_id(APP_ID).build();
 
     app.connect_activate(move |app| {
-        let ui = build_ui(app);
-        if let Some(file) = cli.file.as_ref() {
-            ui::load_document(file, Rc::clone(&ui));
+        let ui = build_ui(app, &config);
+        // The cache needs room for both pages of a spread
+        ui.borrow_mut().cache_pages = cli.cache_pages.unwrap_or(config.cache_pages).max(2);
+        let cache_memory_mb = cli
+            .cache_memory_mb
+            .map(|cache_memory_mb| cache_memory_mb as usize)
+            .unwrap_or(config.cache_memory_mb);
+        ui.borrow_mut().cache_memory = cache_memory_mb * ui::BYTES_PER_MB;
+        ui.borrow_mut().prefetch_pages = cli
+            .prefetch
+            .map(|prefetch| prefetch as usize)
+            .unwrap_or(config.prefetch_pages)
+            .max(1);
+        ui.borrow_mut().precache_all = cli.precache_all;
+        ui.borrow_mut().animate_page_turns = !cli.instant_page_turns;
+        ui.borrow_mut().spread_step = cli
+            .spread_step
+            .map(|spread_step| spread_step as usize)
+            .unwrap_or(config.spread_step)
+            .clamp(1, 2);
+        ui.borrow_mut().render_scale = cli.render_scale.or(config
+            .render_scale
+            .filter(|&render_scale| render_scale > 0.0));
+        ui.borrow_mut().right_to_left = cli.right_to_left;
+        ui.borrow_mut().detect_blank_pages = cli.detect_blank_pages;
+        if let Some(device_name) = cli.midi_device.as_ref() {
+            let mapping = PedalMapping {
+                next: cli.midi_next,
+                previous: cli.midi_previous,
+            };
+            ui::connect_pedal(Rc::clone(&ui), device_name, mapping);
+        }
+        if let Some(address) = cli.listen {
+            ui::connect_remote(Rc::clone(&ui), address);
+        }
+        if let Some(setlist) = cli.setlist.as_ref() {
+            ui::load_setlist_or_show_error(setlist, Rc::clone(&ui));
+        } else if let Some(page) = cli.page {
+            let page_number = page as usize - 1;
+            ui::load_document_at_page_or_show_error(&cli.file, page_number, Rc::clone(&ui));
+        } else if !cli.file.is_empty() {
+            ui::load_document_or_show_error(&cli.file, Rc::clone(&ui));
+        }
+        if cli.fullscreen {
+            ui::toggle_fullscreen(&ui.borrow());
         }
     });
 
     app.run_with_args(&[] as &[&str]);
 }
 
-fn build_ui(app: &Application) -> Rc<RefCell<Ui>> {
-    Ui::build(app)
+fn build_ui(app: &Application, config: &Config) -> Rc<RefCell<Ui>> {
+    Ui::build(app, config)
 }
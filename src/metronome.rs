@@ -0,0 +1,81 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_channel::{Receiver, Sender};
+use log::{debug, error};
+use rodio::{source::SineWave, OutputStream, OutputStreamHandle, Source};
+
+const CLICK_DURATION: Duration = Duration::from_millis(30);
+const CLICK_VOLUME: f32 = 0.4;
+const ACCENT_FREQUENCY: f32 = 1760.0;
+const BEAT_FREQUENCY: f32 = 880.0;
+
+/// Position of a beat within its bar, the accented first beat is 0
+pub type Beat = u32;
+
+/// Keeps time on a dedicated thread, so rendering pages can't delay the clicks.
+/// The metronome stops once this is dropped.
+pub struct Metronome {
+    running: Arc<AtomicBool>,
+}
+
+impl Metronome {
+    pub fn start(bpm: u32, beats_per_bar: u32) -> (Metronome, Receiver<Beat>) {
+        let running = Arc::new(AtomicBool::new(true));
+        let (sender, beats) = async_channel::unbounded();
+        let interval = Duration::from_secs_f64(60.0 / bpm.max(1) as f64);
+        let thread_running = Arc::clone(&running);
+        thread::spawn(move || run(interval, beats_per_bar.max(1), thread_running, sender));
+        (Metronome { running }, beats)
+    }
+}
+
+impl Drop for Metronome {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+fn run(interval: Duration, beats_per_bar: u32, running: Arc<AtomicBool>, beats: Sender<Beat>) {
+    // The beats are still shown without a sound device
+    let audio = OutputStream::try_default()
+        .map_err(|e| error!("Metronome continues without sound: {}", e))
+        .ok();
+    let mut beat = 0;
+    let mut next_beat_time = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        if let Some((_stream, handle)) = audio.as_ref() {
+            play_click(handle, beat == 0);
+        }
+        if beats.send_blocking(beat).is_err() {
+            break;
+        }
+        beat = (beat + 1) % beats_per_bar;
+        // Sleeping until a fixed time keeps small delays from adding up
+        next_beat_time += interval;
+        if let Some(remaining) = next_beat_time.checked_duration_since(Instant::now()) {
+            thread::sleep(remaining);
+        }
+    }
+    debug!("Metronome stopped");
+}
+
+fn play_click(handle: &OutputStreamHandle, accented: bool) {
+    let frequency = if accented {
+        ACCENT_FREQUENCY
+    } else {
+        BEAT_FREQUENCY
+    };
+    let click = SineWave::new(frequency)
+        .take_duration(CLICK_DURATION)
+        .amplify(CLICK_VOLUME);
+    if let Err(e) = handle.play_raw(click) {
+        error!("Failed playing metronome click: {}", e);
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error};
+
+use crate::cache::PageNumber;
+
+const NOTES_EXTENSIONS: &[&str] = &["txt", "md"];
+
+/// Lyrics or performance notes, read from a file named like the document, e.g. `song.txt`.
+/// Lines like `[page 3]` start the notes of a page, which last until the next marker.
+pub struct Notes {
+    // Shown on every page before the first marker, or everywhere without markers
+    general: String,
+    pages: BTreeMap<PageNumber, String>,
+}
+
+impl Notes {
+    fn parse(content: &str) -> Notes {
+        let mut general = String::new();
+        let mut pages = BTreeMap::new();
+        let mut current: Option<PageNumber> = None;
+        for line in content.lines() {
+            if let Some(page_number) = parse_page_marker(line) {
+                current = Some(page_number);
+                pages.entry(page_number).or_insert_with(String::new);
+                continue;
+            }
+            let text = match current {
+                Some(page_number) => pages.entry(page_number).or_default(),
+                None => &mut general,
+            };
+            text.push_str(line);
+            text.push('\n');
+        }
+        Notes { general, pages }
+    }
+
+    pub fn text_for_page(&self, page_number: PageNumber) -> String {
+        let page_text = self
+            .pages
+            .range(..=page_number)
+            .next_back()
+            .map(|(_, text)| text.trim())
+            .unwrap_or_default();
+        let general = self.general.trim();
+        match (general.is_empty(), page_text.is_empty()) {
+            (_, true) => general.to_string(),
+            (true, false) => page_text.to_string(),
+            (false, false) => format!("{}\n\n{}", general, page_text),
+        }
+    }
+}
+
+// Page numbers start at 1 like in the page indicator
+fn parse_page_marker(line: &str) -> Option<PageNumber> {
+    let marker = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (word, number) = marker.trim().split_once(char::is_whitespace)?;
+    if !word.eq_ignore_ascii_case("page") {
+        return None;
+    }
+    number.trim().parse::<PageNumber>().ok()?.checked_sub(1)
+}
+
+fn notes_path(file: &Path) -> Option<PathBuf> {
+    NOTES_EXTENSIONS
+        .iter()
+        .map(|extension| file.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+pub fn load(file: &Path) -> Option<Notes> {
+    let path = notes_path(file)?;
+    debug!("Loading notes from {}", path.display());
+    match fs::read_to_string(&path) {
+        Ok(content) => Some(Notes::parse(&content)),
+        Err(e) => {
+            error!("Failed reading notes {}: {}", path.display(), e);
+            None
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::ffi::CStr;
+
+use glib::translate::ToGlibPtr;
+use poppler::{ffi, Document};
+
+use crate::cache::PageNumber;
+
+/// An entry of the table of contents, flattened with its nesting depth
+#[derive(Debug, Clone)]
+pub struct OutlineEntry {
+    pub title: String,
+    pub page_number: PageNumber,
+    pub depth: usize,
+}
+
+pub fn read_outline(document: &Document) -> Vec<OutlineEntry> {
+    let mut entries = Vec::new();
+    // The safe bindings don't expose the action union, so the index is walked through ffi
+    unsafe {
+        let iter = ffi::poppler_index_iter_new(document.to_glib_none().0);
+        if !iter.is_null() {
+            read_entries(document, iter, 0, &mut entries);
+            ffi::poppler_index_iter_free(iter);
+        }
+    }
+    entries
+}
+
+unsafe fn read_entries(
+    document: &Document,
+    iter: *mut ffi::PopplerIndexIter,
+    depth: usize,
+    entries: &mut Vec<OutlineEntry>,
+) {
+    loop {
+        let action = ffi::poppler_index_iter_get_action(iter);
+        if !action.is_null() {
+            if let Some(entry) = read_entry(document, action, depth) {
+                entries.push(entry);
+            }
+            ffi::poppler_action_free(action);
+        }
+
+        let child = ffi::poppler_index_iter_get_child(iter);
+        if !child.is_null() {
+            read_entries(document, child, depth + 1, entries);
+            ffi::poppler_index_iter_free(child);
+        }
+
+        if ffi::poppler_index_iter_next(iter) == glib::ffi::GFALSE {
+            break;
+        }
+    }
+}
+
+// Only entries pointing to a page of the same document can be navigated to
+unsafe fn read_entry(
+    document: &Document,
+    action: *mut ffi::PopplerAction,
+    depth: usize,
+) -> Option<OutlineEntry> {
+    if (*action).type_ != ffi::POPPLER_ACTION_GOTO_DEST {
+        return None;
+    }
+    let goto_dest = (*action).goto_dest;
+    if goto_dest.dest.is_null() {
+        return None;
+    }
+
+    let page_num = if (*goto_dest.dest).type_ == ffi::POPPLER_DEST_NAMED {
+        let dest = ffi::poppler_document_find_dest(
+            document.to_glib_none().0,
+            (*goto_dest.dest).named_dest,
+        );
+        if dest.is_null() {
+            return None;
+        }
+        let page_num = (*dest).page_num;
+        ffi::poppler_dest_free(dest);
+        page_num
+    } else {
+        (*goto_dest.dest).page_num
+    };
+
+    let title = if goto_dest.title.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(goto_dest.title)
+            .to_string_lossy()
+            .into_owned()
+    };
+    Some(OutlineEntry {
+        title,
+        // Poppler counts pages from 1
+        page_number: (page_num.max(1) - 1) as PageNumber,
+        depth,
+    })
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::{
+    cache::PageNumber,
+    document::{DocumentSource, PageSource},
+    outline::OutlineEntry,
+};
+
+/// The custom page order of a document is read from a file next to it, e.g. `medley.pdf.pages`
+pub fn page_map_path(file: &Path) -> PathBuf {
+    let mut path = OsString::from(file.as_os_str());
+    path.push(".pages");
+    PathBuf::from(path)
+}
+
+/// Pages are numbered from 1 and separated by commas, spaces or lines.
+/// Ranges like 3-5 include both ends, lines starting with # are comments.
+fn parse_page_map(content: &str) -> Result<Vec<PageNumber>> {
+    let mut pages = Vec::new();
+    let entries = content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|entry| !entry.is_empty());
+    for entry in entries {
+        let parse_page = |page: &str| match page.parse::<PageNumber>() {
+            Ok(page) if page > 0 => Ok(page - 1),
+            _ => Err(anyhow!("'{}' is not a valid page number", page)),
+        };
+        match entry.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse_page(first)?, parse_page(last)?);
+                if first > last {
+                    bail!("The range {} ends before it starts", entry);
+                }
+                pages.extend(first..=last);
+            }
+            None => pages.push(parse_page(entry)?),
+        }
+    }
+    if pages.is_empty() {
+        bail!("The page map lists no pages");
+    }
+    Ok(pages)
+}
+
+/// Page maps of the files that have one
+pub fn load_page_maps(files: &[PathBuf]) -> Result<HashMap<PathBuf, Vec<PageNumber>>> {
+    let mut page_maps = HashMap::new();
+    for file in files {
+        let path = page_map_path(file);
+        if !path.is_file() {
+            continue;
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed reading page map {}", path.display()))?;
+        let pages = parse_page_map(&content)
+            .with_context(|| format!("Failed parsing page map {}", path.display()))?;
+        page_maps.insert(file.clone(), pages);
+    }
+    Ok(page_maps)
+}
+
+/// Shows the pages of a document in the order of its page map, pages may repeat or be left out
+pub struct MappedDocument {
+    document: Rc<dyn DocumentSource>,
+    pages: Vec<PageNumber>,
+}
+
+impl MappedDocument {
+    pub fn new(document: Rc<dyn DocumentSource>, pages: Vec<PageNumber>) -> Result<Self> {
+        let num_pages = document.n_pages();
+        if let Some(page) = pages.iter().find(|&&page| page >= num_pages) {
+            bail!(
+                "The page map lists page {}, but the document only has {} pages",
+                page + 1,
+                num_pages
+            );
+        }
+        Ok(MappedDocument { document, pages })
+    }
+}
+
+impl DocumentSource for MappedDocument {
+    fn n_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page(&self, page_number: PageNumber) -> Option<Rc<dyn PageSource>> {
+        self.document.page(*self.pages.get(page_number)?)
+    }
+
+    // Entries lead to where their page is shown first, pages left out lose their entries
+    fn outline(&self) -> Vec<OutlineEntry> {
+        self.document
+            .outline()
+            .into_iter()
+            .filter_map(|entry| {
+                let page_number = self
+                    .pages
+                    .iter()
+                    .position(|&page| page == entry.page_number)?;
+                Some(OutlineEntry {
+                    page_number,
+                    ..entry
+                })
+            })
+            .collect()
+    }
+
+    fn title(&self) -> Option<String> {
+        self.document.title()
+    }
+
+    // Both the position in the page map and the page of the document
+    fn page_label(&self, page_number: PageNumber) -> Option<String> {
+        let page = *self.pages.get(page_number)?;
+        let label = self
+            .document
+            .page_label(page)
+            .unwrap_or_else(|| (page + 1).to_string());
+        Some(format!("{} (p. {})", page_number + 1, label))
+    }
+}
@@ -0,0 +1,108 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use async_channel::Receiver;
+use log::{debug, info};
+use midir::{MidiInput, MidiInputConnection};
+
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PedalAction {
+    Next,
+    Previous,
+}
+
+/// A MIDI message that triggers a page turn, written as `note:<n>` or `cc:<n>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiTrigger {
+    Note(u8),
+    ControlChange(u8),
+}
+
+impl FromStr for MidiTrigger {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, number) = value
+            .split_once(':')
+            .ok_or_else(|| format!("'{}' has to look like note:<n> or cc:<n>", value))?;
+        let number: u8 = number
+            .parse()
+            .map_err(|_| format!("'{}' is not a valid MIDI number", number))?;
+        match kind {
+            "note" => Ok(MidiTrigger::Note(number)),
+            "cc" => Ok(MidiTrigger::ControlChange(number)),
+            _ => Err(format!("Unknown MIDI message kind '{}'", kind)),
+        }
+    }
+}
+
+impl MidiTrigger {
+    fn matches(&self, message: &[u8]) -> bool {
+        let [status, number, value] = message else {
+            return false;
+        };
+        // Only presses count, releases are note on with velocity 0 or low controller values
+        match *self {
+            MidiTrigger::Note(note) => status & 0xF0 == NOTE_ON && *number == note && *value > 0,
+            MidiTrigger::ControlChange(controller) => {
+                status & 0xF0 == CONTROL_CHANGE && *number == controller && *value >= 64
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PedalMapping {
+    pub next: MidiTrigger,
+    pub previous: MidiTrigger,
+}
+
+impl PedalMapping {
+    fn action(&self, message: &[u8]) -> Option<PedalAction> {
+        if self.next.matches(message) {
+            Some(PedalAction::Next)
+        } else if self.previous.matches(message) {
+            Some(PedalAction::Previous)
+        } else {
+            None
+        }
+    }
+}
+
+/// The connection has to be kept alive for as long as actions should be received
+pub fn connect(
+    device_name: &str,
+    mapping: PedalMapping,
+) -> Result<(MidiInputConnection<()>, Receiver<PedalAction>)> {
+    let input = MidiInput::new("music-reader")?;
+    let port = input
+        .ports()
+        .into_iter()
+        .find(|port| {
+            input
+                .port_name(port)
+                .map(|name| name.contains(device_name))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| anyhow!("No MIDI device named '{}' found", device_name))?;
+    info!("Connecting to MIDI device {}", input.port_name(&port)?);
+
+    let (sender, receiver) = async_channel::unbounded();
+    let connection = input
+        .connect(
+            &port,
+            "music-reader-pedal",
+            move |_timestamp, message, _| {
+                debug!("Received MIDI message {:?}", message);
+                if let Some(action) = mapping.action(message) {
+                    let _ = sender.send_blocking(action);
+                }
+            },
+            (),
+        )
+        .map_err(|e| anyhow!("Failed connecting to MIDI device: {}", e))?;
+    Ok((connection, receiver))
+}
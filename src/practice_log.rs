@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{debug, error};
+
+use crate::{state, APP_ID};
+
+// Without page turns for this long, the reader is assumed to have stopped practicing
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Seconds of practice per document
+type PracticeLog = HashMap<String, u64>;
+
+fn practice_log_path() -> PathBuf {
+    glib::user_data_dir().join(APP_ID).join("practice_log.json")
+}
+
+fn read_practice_log() -> PracticeLog {
+    fs::read_to_string(practice_log_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_practice_log(practice_log: &PracticeLog) -> Result<()> {
+    let path = practice_log_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(practice_log)?)?;
+    Ok(())
+}
+
+fn add_practice_time(path: &Path, duration: Duration) {
+    debug!("Adding {}s of practice to {:?}", duration.as_secs(), path);
+    let mut practice_log = read_practice_log();
+    *practice_log.entry(state::document_key(path)).or_default() += duration.as_secs();
+    if let Err(e) = write_practice_log(&practice_log) {
+        error!("Failed saving practice log: {}", e);
+    }
+}
+
+/// All practiced documents, the longest practiced first
+pub fn practice_times() -> Vec<(PathBuf, Duration)> {
+    let mut practice_times: Vec<(PathBuf, Duration)> = read_practice_log()
+        .into_iter()
+        .map(|(key, seconds)| (PathBuf::from(key), Duration::from_secs(seconds)))
+        .collect();
+    practice_times.sort_by(|(_, a), (_, b)| b.cmp(a));
+    practice_times
+}
+
+pub fn practice_time(path: impl AsRef<Path>) -> Duration {
+    let seconds = read_practice_log()
+        .get(&state::document_key(path.as_ref()))
+        .copied()
+        .unwrap_or(0);
+    Duration::from_secs(seconds)
+}
+
+struct Session {
+    file: PathBuf,
+    start: Instant,
+    last_activity: Instant,
+}
+
+impl Session {
+    // Time after the last activity only counts up to the inactivity timeout
+    fn duration(&self, now: Instant) -> Duration {
+        now.min(self.last_activity + INACTIVITY_TIMEOUT)
+            .saturating_duration_since(self.start)
+    }
+}
+
+/// Measures how long a document is read, pausing while the reader is inactive
+#[derive(Default)]
+pub struct PracticeTimer {
+    session: Option<Session>,
+}
+
+impl PracticeTimer {
+    pub fn record_activity(&mut self, file: &Path) {
+        let now = Instant::now();
+        match self.session.as_mut() {
+            Some(session)
+                if session.file == file && now - session.last_activity < INACTIVITY_TIMEOUT =>
+            {
+                session.last_activity = now;
+            }
+            _ => {
+                self.pause();
+                self.session = Some(Session {
+                    file: file.to_path_buf(),
+                    start: now,
+                    last_activity: now,
+                });
+            }
+        }
+    }
+
+    pub fn pause(&mut self) {
+        if let Some(session) = self.session.take() {
+            add_practice_time(&session.file, session.duration(Instant::now()));
+        }
+    }
+
+    // Saves the time so far without ending the session, e.g. before showing the totals
+    pub fn save(&mut self) {
+        let now = Instant::now();
+        if let Some(session) = self.session.as_mut() {
+            add_practice_time(&session.file, session.duration(now));
+            session.start = now.min(session.last_activity + INACTIVITY_TIMEOUT);
+        }
+    }
+}
+
+pub fn format_practice_time(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes < 60 {
+        format!("{} min", minutes)
+    } else {
+        format!("{} h {} min", minutes / 60, minutes % 60)
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::PageNumber,
+    draw::{FitMode, Offset, Rotation, ViewMode},
+    state, APP_ID,
+};
+
+/// How a document is displayed, restored when it is opened again.
+/// Settings that were never changed for the document follow the config and command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocumentPrefs {
+    pub view_mode: Option<ViewMode>,
+    pub fit_mode: Option<FitMode>,
+    pub invert: Option<bool>,
+    pub print_rendering: Option<bool>,
+    pub mirror: bool,
+    pub right_to_left: Option<bool>,
+    pub cover_page: bool,
+    pub rotations: HashMap<PageNumber, Rotation>,
+    pub offsets: HashMap<PageNumber, Offset>,
+    // Only pages that were left zoomed in
+    pub page_views: HashMap<PageNumber, PageView>,
+}
+
+/// The zoom and scroll offset in pixels a page was looked at with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PageView {
+    pub zoom: f64,
+    pub scroll: (f64, f64),
+}
+
+type Prefs = HashMap<String, DocumentPrefs>;
+
+fn prefs_file_path() -> PathBuf {
+    glib::user_data_dir().join(APP_ID).join("prefs.json")
+}
+
+fn read_prefs() -> Prefs {
+    fs::read_to_string(prefs_file_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_prefs(prefs: &Prefs) -> Result<()> {
+    let path = prefs_file_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(prefs)?)?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> DocumentPrefs {
+    read_prefs()
+        .remove(&state::document_key(path.as_ref()))
+        .unwrap_or_default()
+}
+
+pub fn update(path: impl AsRef<Path>, update: impl FnOnce(&mut DocumentPrefs)) {
+    let mut prefs = read_prefs();
+    let document_prefs = prefs.entry(state::document_key(path.as_ref())).or_default();
+    update(document_prefs);
+    debug!("Saving {:?} for {:?}", document_prefs, path.as_ref());
+    if let Err(e) = write_prefs(&prefs) {
+        error!("Failed saving prefs: {}", e);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    io::{BufRead, BufReader},
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    thread,
+};
+
+use anyhow::{Context as _, Result};
+use async_channel::{Receiver, Sender};
+use log::{debug, info, warn};
+
+use crate::cache::PageNumber;
+
+/// Sent as one line of text per command, e.g. `echo next | nc <host> <port>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteCommand {
+    Next,
+    Previous,
+    GoTo(PageNumber),
+}
+
+impl FromStr for RemoteCommand {
+    type Err = String;
+
+    // Pages are numbered from 1 like in the window
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["next"] => Ok(RemoteCommand::Next),
+            ["prev"] | ["previous"] => Ok(RemoteCommand::Previous),
+            ["goto", page] => match page.parse::<PageNumber>() {
+                Ok(page) if page > 0 => Ok(RemoteCommand::GoTo(page - 1)),
+                _ => Err(format!("'{}' is not a valid page number", page)),
+            },
+            _ => Err(format!("Unknown command '{}'", value.trim())),
+        }
+    }
+}
+
+/// Accepts any number of connections, each on a thread of its own.
+/// Binding happens right away, so an address in use is reported to the caller.
+pub fn listen(address: SocketAddr) -> Result<Receiver<RemoteCommand>> {
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("Failed listening on {}", address))?;
+    info!("Listening for remote commands on {}", address);
+    let (sender, receiver) = async_channel::unbounded();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || read_commands(stream, sender));
+                }
+                Err(e) => warn!("Failed accepting remote connection: {}", e),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+fn read_commands(stream: TcpStream, sender: Sender<RemoteCommand>) {
+    let peer = stream.peer_addr().ok();
+    debug!("Remote {:?} connected", peer);
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(command) => {
+                // The ui is gone once nothing receives anymore
+                if sender.send_blocking(command).is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("Ignoring remote command from {:?}: {}", peer, e),
+        }
+    }
+    debug!("Remote {:?} disconnected", peer);
+}
@@ -0,0 +1,130 @@
+use std::{collections::HashMap, path::PathBuf, thread};
+
+use anyhow::{anyhow, Result};
+use async_channel::{Receiver, Sender};
+use log::{debug, error};
+
+use crate::{
+    cache::PageNumber,
+    document::{self, OpenOptions},
+    draw::{self, Crop, Offset, PageRegion, Pixels, RenderSettings, RenderSize, Rotation},
+};
+
+#[derive(Debug)]
+pub struct RenderRequest {
+    pub page_number: PageNumber,
+    pub size: RenderSize,
+    pub settings: RenderSettings,
+    pub rotation: Rotation,
+    pub crop: Option<Crop>,
+    pub offset: Offset,
+}
+
+type RenderReply = Sender<Result<Pixels>>;
+
+// Both pages of a spread are rendered at the same time, more threads would mostly
+// cost memory for their copies of the documents
+const RENDER_THREADS: usize = 2;
+
+/// Rasterizes pages on dedicated threads.
+/// Documents are not `Send` and poppler can't render one document from several threads,
+/// so every thread opens its own copies from the file paths.
+pub struct RenderWorker {
+    requests: Sender<(RenderRequest, RenderReply)>,
+}
+
+impl RenderWorker {
+    pub fn spawn(files: Vec<PathBuf>, options: OpenOptions) -> Self {
+        let (requests, receiver) = async_channel::unbounded();
+        for _ in 0..RENDER_THREADS {
+            let files = files.clone();
+            let options = options.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || run(files, options, receiver));
+        }
+        RenderWorker { requests }
+    }
+
+    async fn submit(&self, request: RenderRequest) -> Result<Receiver<Result<Pixels>>> {
+        let (reply, response) = async_channel::bounded(1);
+        self.requests
+            .send((request, reply))
+            .await
+            .map_err(|_| anyhow!("The render threads have stopped"))?;
+        Ok(response)
+    }
+
+    pub async fn render(&self, request: RenderRequest) -> Result<Pixels> {
+        let response = self.submit(request).await?;
+        response
+            .recv()
+            .await
+            .map_err(|_| anyhow!("The render threads have stopped"))?
+    }
+
+    // All requests are queued before waiting, so idle threads pick them up in parallel
+    pub async fn render_all(&self, requests: Vec<RenderRequest>) -> Vec<Result<Pixels>> {
+        let mut responses = Vec::new();
+        for request in requests {
+            responses.push(self.submit(request).await);
+        }
+        let mut results = Vec::new();
+        for response in responses {
+            results.push(match response {
+                Ok(response) => response
+                    .recv()
+                    .await
+                    .map_err(|_| anyhow!("The render threads have stopped"))
+                    .and_then(|result| result),
+                Err(e) => Err(e),
+            });
+        }
+        results
+    }
+}
+
+fn run(
+    files: Vec<PathBuf>,
+    options: OpenOptions,
+    requests: Receiver<(RenderRequest, RenderReply)>,
+) {
+    let document = match document::open_documents(&files, &options) {
+        Ok(document) => document,
+        Err(e) => {
+            error!("Render thread failed opening documents: {:#}", e);
+            return;
+        }
+    };
+    // Finding the content is expensive, so it is only done once per page
+    let mut content_regions: HashMap<(PageNumber, Rotation), PageRegion> = HashMap::new();
+
+    // Stops once the worker, and with it the sending side, is dropped.
+    // The threads share the queue, whichever is idle takes the next request.
+    while let Ok((request, reply)) = requests.recv_blocking() {
+        debug!("Rendering {:?}", request);
+        let result = document
+            .page(request.page_number)
+            .map(|page| {
+                let page = draw::rotate_page(page, request.rotation);
+                // A crop of the reader replaces the trimmed margins
+                let region = if let Some(crop) = request.crop {
+                    crop.region(page.as_ref())
+                } else if request.settings.trim_margins {
+                    *content_regions
+                        .entry((request.page_number, request.rotation))
+                        .or_insert_with(|| draw::find_content_region(&page))
+                } else {
+                    PageRegion::full(&page)
+                };
+                let region = region.moved_by(request.offset);
+                draw::draw_page_regions_to_pixels(
+                    &[(page, region)],
+                    request.size,
+                    &request.settings,
+                )
+            })
+            .ok_or_else(|| anyhow!("Page {} does not exist", request.page_number));
+        let _ = reply.send_blocking(result);
+    }
+    debug!("Render thread stopped");
+}
@@ -0,0 +1,43 @@
+use std::{path::PathBuf, thread};
+
+use async_channel::Receiver;
+use log::{debug, error};
+
+use crate::{
+    cache::PageNumber,
+    document::{self, OpenOptions},
+};
+
+/// Looks for the text on every page on a thread of its own, as long documents take a while.
+/// The pages containing it are sent in order, dropping the receiver stops the search.
+pub fn spawn_search(
+    files: Vec<PathBuf>,
+    options: OpenOptions,
+    text: String,
+) -> Receiver<PageNumber> {
+    let (sender, receiver) = async_channel::unbounded();
+    thread::spawn(move || {
+        // Documents are not `Send`, so the thread opens its own copies like the render threads
+        let document = match document::open_documents(&files, &options) {
+            Ok(document) => document,
+            Err(e) => {
+                error!("Search thread failed opening documents: {:#}", e);
+                return;
+            }
+        };
+        for page_number in 0..document.n_pages() {
+            if sender.is_closed() {
+                debug!("Search for {:?} cancelled", text);
+                return;
+            }
+            let contains_text = document
+                .page(page_number)
+                .is_some_and(|page| page.contains_text(&text));
+            if contains_text && sender.send_blocking(page_number).is_err() {
+                return;
+            }
+        }
+        debug!("Search for {:?} finished", text);
+    });
+    receiver
+}
@@ -0,0 +1,146 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{bail, Context as _, Result};
+use cairo::{Context, FontSlant, FontWeight};
+use log::error;
+
+use crate::{
+    cache::PageNumber,
+    document::{DocumentSource, PageSource},
+};
+
+// Dividers have the size of an A4 page in points
+const DIVIDER_WIDTH: f64 = 595.0;
+const DIVIDER_HEIGHT: f64 = 842.0;
+
+/// An ordered list of pieces, read from a text file with one path per line or a JSON array.
+/// Relative paths are resolved against the folder of the setlist.
+pub struct Setlist {
+    pub pieces: Vec<PathBuf>,
+}
+
+impl Setlist {
+    pub fn load(path: impl AsRef<Path>) -> Result<Setlist> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed reading setlist {}", path.display()))?;
+        let pieces: Vec<PathBuf> = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&content)
+                .with_context(|| format!("Failed parsing setlist {}", path.display()))?
+        } else {
+            content
+                .lines()
+                .map(str::trim)
+                // Lines starting with # are comments
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(PathBuf::from)
+                .collect()
+        };
+        if pieces.is_empty() {
+            bail!("The setlist {} contains no pieces", path.display());
+        }
+
+        let folder = path.parent().unwrap_or(Path::new(""));
+        let pieces = pieces.into_iter().map(|piece| folder.join(piece)).collect();
+        Ok(Setlist { pieces })
+    }
+}
+
+/// Every PDF next to the file, sorted by name, to play a folder of pieces like a setlist
+pub fn folder_pieces(file: &Path) -> Result<Vec<PathBuf>> {
+    let folder = file
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut pieces: Vec<PathBuf> = fs::read_dir(folder)
+        .with_context(|| format!("Failed reading folder {}", folder.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+        })
+        .collect();
+    pieces.sort();
+    Ok(pieces)
+}
+
+pub fn piece_title(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// A single page between two pieces, announcing the next one
+pub struct Divider {
+    page: Rc<DividerPage>,
+}
+
+struct DividerPage {
+    finished_title: String,
+    next_title: String,
+}
+
+impl Divider {
+    pub fn new(finished_piece: &Path, next_piece: &Path) -> Self {
+        Divider {
+            page: Rc::new(DividerPage {
+                finished_title: piece_title(finished_piece),
+                next_title: piece_title(next_piece),
+            }),
+        }
+    }
+}
+
+impl DocumentSource for Divider {
+    fn n_pages(&self) -> usize {
+        1
+    }
+
+    fn page(&self, page_number: PageNumber) -> Option<Rc<dyn PageSource>> {
+        if page_number == 0 {
+            Some(Rc::clone(&self.page) as Rc<dyn PageSource>)
+        } else {
+            None
+        }
+    }
+}
+
+impl PageSource for DividerPage {
+    fn size(&self) -> (f64, f64) {
+        (DIVIDER_WIDTH, DIVIDER_HEIGHT)
+    }
+
+    fn render(&self, context: &Context) {
+        context.set_source_rgb(0.3, 0.3, 0.3);
+        context.select_font_face("Sans", FontSlant::Normal, FontWeight::Normal);
+        show_centered_text(context, "End of", 20.0, 300.0);
+        show_centered_text(context, &self.finished_title, 28.0, 340.0);
+        context.set_source_rgb(0.0, 0.0, 0.0);
+        show_centered_text(context, "Next:", 20.0, 480.0);
+        context.select_font_face("Sans", FontSlant::Normal, FontWeight::Bold);
+        show_centered_text(context, &self.next_title, 36.0, 530.0);
+    }
+}
+
+fn show_centered_text(context: &Context, text: &str, font_size: f64, y: f64) {
+    context.set_font_size(font_size);
+    let result = context.text_extents(text).and_then(|extents| {
+        context.move_to(
+            (DIVIDER_WIDTH - extents.width()) / 2.0 - extents.x_bearing(),
+            y,
+        );
+        context.show_text(text)
+    });
+    if let Err(e) = result {
+        error!("Failed drawing divider text: {}", e);
+    }
+}
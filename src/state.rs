@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::PageNumber,
+    draw::{Crop, Levels},
+    APP_ID,
+};
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    documents: HashMap<String, DocumentState>,
+    #[serde(default)]
+    recent_files: Vec<PathBuf>,
+    #[serde(default)]
+    levels: Levels,
+    // Opacity of the black overlay above the pages
+    #[serde(default)]
+    dim: f64,
+}
+
+const MAX_NUM_RECENT_FILES: usize = 10;
+
+#[derive(Default, Serialize, Deserialize)]
+struct DocumentState {
+    #[serde(default)]
+    last_page: Option<usize>,
+    #[serde(default)]
+    bookmarks: Vec<usize>,
+    #[serde(default)]
+    crops: HashMap<PageNumber, Crop>,
+}
+
+fn state_file_path() -> PathBuf {
+    glib::user_data_dir().join(APP_ID).join("state.json")
+}
+
+pub fn document_key(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path.to_string_lossy().into_owned()
+}
+
+fn read_state() -> State {
+    fs::read_to_string(state_file_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_state(state: &State) -> Result<()> {
+    let path = state_file_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(state)?)?;
+    Ok(())
+}
+
+fn update_document_state(path: &Path, update: impl FnOnce(&mut DocumentState)) {
+    let mut state = read_state();
+    update(state.documents.entry(document_key(path)).or_default());
+    if let Err(e) = write_state(&state) {
+        error!("Failed saving state: {}", e);
+    }
+}
+
+pub fn load_levels() -> Levels {
+    read_state().levels
+}
+
+pub fn save_levels(levels: Levels) {
+    let mut state = read_state();
+    state.levels = levels;
+    if let Err(e) = write_state(&state) {
+        error!("Failed saving state: {}", e);
+    }
+}
+
+pub fn load_dim() -> f64 {
+    read_state().dim
+}
+
+pub fn save_dim(dim: f64) {
+    let mut state = read_state();
+    state.dim = dim;
+    if let Err(e) = write_state(&state) {
+        error!("Failed saving state: {}", e);
+    }
+}
+
+pub fn load_position(path: impl AsRef<Path>) -> Option<usize> {
+    read_state()
+        .documents
+        .get(&document_key(path.as_ref()))
+        .and_then(|document| document.last_page)
+}
+
+pub fn save_position(path: impl AsRef<Path>, page: usize) {
+    debug!("Saving position {} for {:?}", page, path.as_ref());
+    update_document_state(path.as_ref(), |document| document.last_page = Some(page));
+}
+
+pub fn recent_files() -> Vec<PathBuf> {
+    read_state().recent_files
+}
+
+pub fn add_recent_file(path: impl AsRef<Path>) {
+    let path = path
+        .as_ref()
+        .canonicalize()
+        .unwrap_or_else(|_| path.as_ref().to_path_buf());
+    let mut state = read_state();
+    state
+        .recent_files
+        .retain(|recent_file| *recent_file != path);
+    state.recent_files.insert(0, path);
+    state.recent_files.truncate(MAX_NUM_RECENT_FILES);
+    if let Err(e) = write_state(&state) {
+        error!("Failed saving state: {}", e);
+    }
+}
+
+pub fn load_bookmarks(path: impl AsRef<Path>) -> Vec<usize> {
+    read_state()
+        .documents
+        .remove(&document_key(path.as_ref()))
+        .map(|document| document.bookmarks)
+        .unwrap_or_default()
+}
+
+pub fn load_crops(path: impl AsRef<Path>) -> HashMap<PageNumber, Crop> {
+    read_state()
+        .documents
+        .remove(&document_key(path.as_ref()))
+        .map(|document| document.crops)
+        .unwrap_or_default()
+}
+
+pub fn save_crops(path: impl AsRef<Path>, crops: &HashMap<PageNumber, Crop>) {
+    update_document_state(path.as_ref(), |document| document.crops = crops.clone());
+}
+
+pub fn save_bookmarks(path: impl AsRef<Path>, bookmarks: &[usize]) {
+    debug!("Saving bookmarks {:?} for {:?}", bookmarks, path.as_ref());
+    update_document_state(path.as_ref(), |document| {
+        document.bookmarks = bookmarks.to_vec()
+    });
+}
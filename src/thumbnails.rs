@@ -0,0 +1,48 @@
+use std::{collections::BTreeMap, rc::Rc};
+
+use gtk::gdk::Texture;
+use log::debug;
+
+use crate::{
+    cache::PageNumber,
+    document::ConcatenatedDocument,
+    draw::{self, RenderSettings, RenderSize},
+};
+
+pub const THUMBNAIL_HEIGHT: i32 = 120;
+
+/// Thumbnails are kept apart from the page cache, so they never get evicted
+pub struct ThumbnailCache {
+    document: Rc<ConcatenatedDocument>,
+    thumbnails: BTreeMap<PageNumber, Rc<Texture>>,
+}
+
+impl ThumbnailCache {
+    pub fn new(document: Rc<ConcatenatedDocument>) -> Self {
+        ThumbnailCache {
+            document,
+            thumbnails: BTreeMap::new(),
+        }
+    }
+
+    pub fn next_missing_page(&self) -> Option<PageNumber> {
+        (0..self.document.n_pages()).find(|page_number| !self.thumbnails.contains_key(page_number))
+    }
+
+    pub fn get_or_render(&mut self, page_number: PageNumber) -> Option<Rc<Texture>> {
+        if let Some(thumbnail) = self.thumbnails.get(&page_number) {
+            return Some(Rc::clone(thumbnail));
+        }
+
+        debug!("Rendering thumbnail of page {}", page_number);
+        let page = self.document.page(page_number)?;
+        let size = RenderSize {
+            width: THUMBNAIL_HEIGHT,
+            height: THUMBNAIL_HEIGHT,
+        };
+        let texture = draw::draw_pages_to_texture(&[page], size, &RenderSettings::default());
+        let thumbnail = Rc::new(texture);
+        self.thumbnails.insert(page_number, Rc::clone(&thumbnail));
+        Some(thumbnail)
+    }
+}
@@ -1,36 +1,267 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    net::SocketAddr,
     path::{Path, PathBuf},
     rc::Rc,
     time::{Duration, Instant},
 };
 
+use anyhow::Result;
 use gtk::{
-    glib, Application, ApplicationWindow, Box, Button, FileChooserAction, FileChooserDialog,
-    HeaderBar, Label, Overlay, Picture, ResponseType,
+    gdk, glib, Application, ApplicationWindow, Box, Button, ButtonsType, Dialog, DrawingArea,
+    Entry, FileChooserAction, FileChooserDialog, FileFilter, FlowBox, HeaderBar, Label, MenuButton,
+    MessageDialog, MessageType, Overlay, PasswordEntry, Picture, PolicyType, Popover, ProgressBar,
+    ResponseType, Revealer, RevealerTransitionType, Scale, ScrolledWindow, SearchBar, SearchEntry,
+    SpinButton, Spinner, Stack, StackTransitionType, ToggleButton, WidgetPaintable,
 };
-use log::debug;
+use log::{debug, error, warn};
 
+use crate::annotations::{self, PageAnnotations, Stroke};
 use crate::cache::{self, PageNumber, SyncCacheCommandSender};
-use glib::clone;
+use crate::config::{self, ClickZones, Config};
+use crate::document::{self, ConcatenatedDocument, OpenOptions, PageSource, PasswordRequired};
+use crate::draw::{
+    self, Color, Crop, FitMode, Levels, Offset, RenderSettings, RenderSize, Rotation, ViewMode,
+};
+use crate::metronome::{Beat, Metronome};
+use crate::notes::{self, Notes};
+use crate::outline::OutlineEntry;
+use crate::page_map;
+use crate::pedal::{self, PedalAction, PedalMapping};
+use crate::practice_log::{self, PracticeTimer};
+use crate::prefs::{self, DocumentPrefs, PageView};
+use crate::remote::{self, RemoteCommand};
+use crate::search;
+use crate::setlist::{self, Setlist};
+use crate::state;
+use crate::thumbnails::{ThumbnailCache, THUMBNAIL_HEIGHT};
+use glib::{clone, timeout_future};
 use gtk::prelude::*;
 
+const MAX_ZOOM_FACTOR: f64 = 5.0;
+const PAGE_TURN_PAGES: &str = "pages";
+const PAGE_TURN_SNAPSHOT: &str = "snapshot";
+const LAYOUT_PAGES: &str = "pages";
+const LAYOUT_CONTINUOUS: &str = "continuous";
+const CONTINUOUS_SPACING: i32 = 8;
+const NOTES_WIDTH: i32 = 300;
+// Pages around the visible ones that keep their pictures while scrolling continuously
+const CONTINUOUS_MARGIN_PAGES: usize = 2;
+const DEFAULT_AUTO_TURN_SECONDS: u32 = 10;
+pub const BYTES_PER_MB: usize = 1024 * 1024;
+const DEFAULT_METRONOME_BPM: u32 = 100;
+const DEFAULT_BEATS_PER_BAR: u32 = 4;
+const BEAT_FLASH_DURATION: Duration = Duration::from_millis(100);
+const DIMMED_BEAT_OPACITY: f64 = 0.3;
+// Enough to undo a few accidental jumps, older positions are forgotten
+const MAX_NAVIGATION_HISTORY: usize = 50;
+// The pages stay readable when dimmed the most
+const MAX_DIM: f64 = 0.8;
+const DIM_STEP: f64 = 0.1;
+const NOTIFICATION_DURATION: Duration = Duration::from_secs(3);
+const PAGE_TURN_BUTTONS_DURATION: Duration = Duration::from_secs(4);
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(200);
+// Horizontal speed in pixels per second a swipe needs to turn a page
+const SWIPE_VELOCITY_THRESHOLD: f64 = 800.0;
+const EXPORT_DPI_OPTIONS: [f64; 3] = [150.0, 300.0, 600.0];
+const DEFAULT_EXPORT_DPI: f64 = 300.0;
+const EXPORT_ALL_PAGES: &str = "all";
+
 pub struct Ui {
     window: ApplicationWindow,
     bottom_bar: gtk::Box,
     header_bar: gtk::HeaderBar,
-    page_indicator: gtk::Label,
+    page_indicator: gtk::Button,
+    // How much of the document is rendered in full resolution
+    cache_progress: ProgressBar,
+    // Render times and cache numbers, for tuning the cache settings
+    stats_label: Label,
     pub app_wrapper: Overlay,
+    scroller: ScrolledWindow,
+    layout_stack: Stack,
+    // Shows all pages below each other instead of turning them
+    continuous: bool,
+    continuous_button: ToggleButton,
+    continuous_scroller: ScrolledWindow,
+    continuous_pages: Box,
+    continuous_pictures: Vec<Picture>,
+    // Applied once the pages are laid out, their positions are unknown before
+    pending_continuous_scroll: Option<PageNumber>,
+    // Continuous scrolling always fits the width, the previous mode is restored afterwards
+    fit_mode_before_continuous: Option<FitMode>,
     pub image_container: Box,
     pub image_left: Picture,
     pub image_right: Picture,
+    // Holds the row of image_container and, in the grid, the second row of pages below
+    pages_box: Box,
+    grid_row: Box,
+    image_bottom_left: Picture,
+    image_bottom_right: Picture,
     pub document_canvas: Option<DocumentCanvas>,
+    // Pages are otherwise only rendered by the cache, this is used for exports
+    document: Option<Rc<ConcatenatedDocument>>,
     pub last_touch_time: Option<Instant>,
+    pub cache_pages: usize,
+    // In bytes, limits the full resolution pages in the cache
+    pub cache_memory: usize,
+    pub prefetch_pages: usize,
+    pub animate_page_turns: bool,
+    // Renders every page after loading instead of only the surrounding ones
+    pub precache_all: bool,
+    pub spread_step: usize,
+    // As in the config file, changed by the settings dialog
+    config: Config,
+    // Every newly loaded document starts with these
+    default_view_mode: ViewMode,
+    default_fit_mode: FitMode,
+    default_invert: bool,
+    default_print_rendering: bool,
+    page_background: Color,
+    // Overrides the scale factor of the window for rendering
+    pub render_scale: Option<f64>,
+    pub right_to_left: bool,
+    // Warns about pages that came out blank, for diagnosing broken documents
+    pub detect_blank_pages: bool,
+    levels: Levels,
+    page_turn_stack: Stack,
+    page_turn_snapshot: Picture,
+    pending_page_turn: Option<StackTransitionType>,
+    fade_resolution_upgrades: bool,
+    resolution_steps: Vec<i32>,
+    center_single_pages: bool,
+    edge_feedback: bool,
+    pub zoom_factor: f64,
+    zoom_at_gesture_begin: f64,
+    scroll_at_drag_begin: (f64, f64),
+    // Applied once the scrolled area has grown to the restored zoom
+    pending_scroll: (Option<f64>, Option<f64>),
+    sidebar: Revealer,
+    notes_panel: Revealer,
+    notes_view: gtk::TextView,
+    // Lyrics or cues read from a file next to the document
+    notes: Option<Notes>,
+    thumbnail_box: FlowBox,
+    thumbnail_pictures: Vec<Picture>,
+    thumbnail_cache: Option<ThumbnailCache>,
+    rendering_thumbnails: bool,
+    auto_turn_button: Button,
+    auto_turn_interval: u32,
+    auto_turn_source: Option<glib::SourceId>,
+    metronome: Option<Metronome>,
+    metronome_button: Button,
+    metronome_indicator: Label,
+    metronome_bpm: u32,
+    metronome_beats_per_bar: u32,
+    loading_spinner: Spinner,
+    outline_button: MenuButton,
+    notification: Revealer,
+    page_turn_buttons: Revealer,
+    page_turn_buttons_serial: Rc<Cell<u32>>,
+    notification_label: Label,
+    // Identifies the latest notification, so older timeouts don't hide it early
+    notification_serial: Rc<Cell<u32>>,
+    click_zones: ClickZones,
+    click_zones_overlay: DrawingArea,
+    // Hides the pages during breaks, covering everything else on the pages
+    blank_overlay: DrawingArea,
+    // Darkens the pages without rendering them again, unlike the levels
+    dim_overlay: DrawingArea,
+    dim_scale: Scale,
+    // Guides to opening a file until the first one is loaded
+    empty_state: Box,
+    search_bar: SearchBar,
+    search_entry: SearchEntry,
+    search_status: Label,
+    search_text: String,
+    // Pages containing the text in ascending order, more arrive while searching
+    search_hits: Vec<PageNumber>,
+    searching: bool,
+    // Identifies the latest search, the hits of older ones are dropped
+    search_serial: Rc<Cell<u32>>,
+    // Drawing on the pages disables page turning by touch
+    annotating: bool,
+    // The next pages are shown while the pages are held down
+    peeking: bool,
+    annotation_area: DrawingArea,
+    current_stroke: Option<(PageNumber, Stroke)>,
+    // The arrow keys nudge one of the visible pages instead of their usual actions
+    adjusting: bool,
+    adjusting_right_page: bool,
+    // Dragging on the pages selects the region to crop them to
+    cropping: bool,
+    crop_button: ToggleButton,
+    // The page with both corners of the selection, relative to the page
+    crop_selection: Option<(PageNumber, (f64, f64), (f64, f64))>,
+    stroke_start: (f64, f64),
+    // Identifies the latest resize, only the last one of a drag-resize re-renders
+    resize_serial: Rc<Cell<u32>>,
+    // Only remembered for the session, so the render thread can open encrypted files
+    passwords: HashMap<PathBuf, String>,
+    // Read when loading, so every thread opening the files sees the same order
+    page_maps: HashMap<PathBuf, Vec<PageNumber>>,
+    loaded_files: Option<LoadedFiles>,
+    // Loaded before the current files, to switch back and forth between two pieces
+    previous_files: Option<LoadedFiles>,
+    practice_timer: PracticeTimer,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct LoadedFiles {
+    paths: Vec<PathBuf>,
+    with_dividers: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NavigationDirection {
+    Forward,
+    Backward,
 }
 
 pub struct DocumentCanvas {
     pub current_page_number: usize,
+    // Unknown after jumps, which don't tell where reading continues
+    pub navigation_direction: Option<NavigationDirection>,
+    // Set by a page turn that could not go past the first or last page
+    pub hit_edge: Option<NavigationDirection>,
     pub num_pages: Option<usize>,
+    pub view_mode: ViewMode,
+    pub spread_step: usize,
+    // Pages around the current ones that are rendered in advance
+    pub prefetch_pages: usize,
+    // Prefetched pages beyond the budget of the cache would only evict each other
+    pub cache_pages: usize,
+    pub cache_memory: usize,
+    pub render_settings: RenderSettings,
+    pub render_scale: f64,
+    // The next page is on the left, the page numbers shown stay ascending
+    pub right_to_left: bool,
+    // The first page is shown alone, so spreads pair up like in the printed book
+    pub cover_page: bool,
+    pub bookmarks: Vec<PageNumber>,
+    pub annotations: PageAnnotations,
+    // Only pages that are not upright have an entry
+    pub rotations: HashMap<PageNumber, Rotation>,
+    pub offsets: HashMap<PageNumber, Offset>,
+    // Chosen by the reader, they replace the trimmed margins of their page
+    pub crops: HashMap<PageNumber, Crop>,
+    // Forward navigation stops at the loop end, turning automatically starts over
+    pub loop_start: Option<PageNumber>,
+    pub loop_end: Option<PageNumber>,
+    // Only set for single file documents, positions are stored per file
+    pub state_path: Option<PathBuf>,
+    // From the metadata or the file name, unset for several files
+    pub title: Option<String>,
+    // First page and title of every piece, only set for several files
+    pub pieces: Vec<(PageNumber, String)>,
+    // Last page read in every piece, switching back continues there
+    piece_positions: HashMap<usize, PageNumber>,
+    page_views: HashMap<PageNumber, PageView>,
+    // Pages shown before every page change, undoing moves them to the undone pages
+    history: Vec<PageNumber>,
+    undone_history: Vec<PageNumber>,
+    // Empty if the pages are simply numbered
+    pub page_labels: Vec<String>,
     page_cache_sender: SyncCacheCommandSender,
 }
 
@@ -38,65 +269,669 @@ impl DocumentCanvas {
     pub fn new(page_cache_sender: SyncCacheCommandSender) -> Self {
         DocumentCanvas {
             current_page_number: 0,
+            navigation_direction: None,
+            hit_edge: None,
             num_pages: None,
+            view_mode: ViewMode::Spread,
+            spread_step: 2,
+            prefetch_pages: 3,
+            cache_pages: 30,
+            cache_memory: 512 * BYTES_PER_MB,
+            render_settings: RenderSettings::default(),
+            render_scale: 1.0,
+            right_to_left: false,
+            cover_page: false,
+            bookmarks: Vec::new(),
+            annotations: PageAnnotations::new(),
+            rotations: HashMap::new(),
+            offsets: HashMap::new(),
+            crops: HashMap::new(),
+            loop_start: None,
+            loop_end: None,
+            state_path: None,
+            title: None,
+            pieces: Vec::new(),
+            piece_positions: HashMap::new(),
+            page_views: HashMap::new(),
+            history: Vec::new(),
+            undone_history: Vec::new(),
+            page_labels: Vec::new(),
             page_cache_sender,
         }
     }
 
+    pub fn close(&self) {
+        self.page_cache_sender.shut_down();
+    }
+
+    pub fn save_position(&self) {
+        if let Some(path) = self.state_path.as_ref() {
+            state::save_position(path, self.current_page_number);
+        }
+    }
+
+    // Only documents opened on their own remember their display settings
+    fn update_prefs(&self, update: impl FnOnce(&mut DocumentPrefs)) {
+        if let Some(path) = self.state_path.as_ref() {
+            prefs::update(path, update);
+        }
+    }
+
+    fn restore_prefs(&mut self, prefs: DocumentPrefs) {
+        if let Some(view_mode) = prefs.view_mode {
+            self.view_mode = view_mode;
+        }
+        self.update_render_settings(|settings| {
+            settings.fit_mode = prefs.fit_mode.unwrap_or(settings.fit_mode);
+            settings.invert = prefs.invert.unwrap_or(settings.invert);
+            settings.print_rendering = prefs.print_rendering.unwrap_or(settings.print_rendering);
+            settings.mirror = prefs.mirror;
+        });
+        if let Some(right_to_left) = prefs.right_to_left {
+            self.right_to_left = right_to_left;
+        }
+        self.cover_page = prefs.cover_page;
+        self.set_rotations(prefs.rotations);
+        self.set_offsets(prefs.offsets);
+        self.page_views = prefs.page_views;
+    }
+
+    pub fn page_view(&self) -> Option<PageView> {
+        self.page_views.get(&self.current_page_number).copied()
+    }
+
+    // Only written when the view changed, as this is called on every page turn
+    pub fn set_page_view(&mut self, page_number: PageNumber, page_view: Option<PageView>) {
+        if self.page_views.get(&page_number) == page_view.as_ref() {
+            return;
+        }
+        match page_view {
+            Some(page_view) => self.page_views.insert(page_number, page_view),
+            None => self.page_views.remove(&page_number),
+        };
+        let page_views = self.page_views.clone();
+        self.update_prefs(|prefs| prefs.page_views = page_views);
+    }
+
+    pub fn toggle_view_mode(&mut self) {
+        self.set_view_mode(match self.view_mode {
+            ViewMode::Single => ViewMode::Spread,
+            ViewMode::Spread => ViewMode::Grid,
+            ViewMode::Grid => ViewMode::Single,
+        });
+        let view_mode = self.view_mode;
+        self.update_prefs(|prefs| prefs.view_mode = Some(view_mode));
+    }
+
+    pub fn set_view_mode(&mut self, view_mode: ViewMode) {
+        self.view_mode = view_mode;
+        // The grid starts at a multiple of four
+        self.go_to_page(self.current_page_number);
+    }
+
+    // Cached pages rendered with other settings are rendered again
+    pub fn update_render_settings(&mut self, update: impl FnOnce(&mut RenderSettings)) {
+        update(&mut self.render_settings);
+        self.page_cache_sender
+            .set_render_settings(self.render_settings.clone());
+    }
+
+    // Applies to the running cache, which evicts pages beyond smaller limits
+    pub fn set_cache_limits(&mut self, cache_pages: usize, cache_memory: usize) {
+        self.cache_pages = cache_pages;
+        self.cache_memory = cache_memory;
+        self.page_cache_sender
+            .set_cache_limits(cache_pages, cache_memory);
+    }
+
+    // Pages are rendered in physical pixels, so they stay sharp on HiDPI displays
+    pub fn set_render_scale(&mut self, render_scale: f64) {
+        self.render_scale = render_scale;
+        self.page_cache_sender.set_render_scale(render_scale);
+        self.update_render_settings(|settings| settings.render_scale = render_scale);
+    }
+
+    pub fn toggle_fit_mode(&mut self) {
+        self.update_render_settings(|settings| {
+            settings.fit_mode = match settings.fit_mode {
+                FitMode::Height => FitMode::Width,
+                FitMode::Width => FitMode::Native,
+                FitMode::Native => FitMode::Height,
+            }
+        });
+        let fit_mode = self.render_settings.fit_mode;
+        self.update_prefs(|prefs| prefs.fit_mode = Some(fit_mode));
+    }
+
+    pub fn toggle_invert(&mut self) {
+        self.update_render_settings(|settings| settings.invert = !settings.invert);
+        let invert = self.render_settings.invert;
+        self.update_prefs(|prefs| prefs.invert = Some(invert));
+    }
+
+    pub fn toggle_mirror(&mut self) {
+        self.update_render_settings(|settings| settings.mirror = !settings.mirror);
+        let mirror = self.render_settings.mirror;
+        self.update_prefs(|prefs| prefs.mirror = mirror);
+    }
+
+    pub fn toggle_print_rendering(&mut self) {
+        self.update_render_settings(|settings| {
+            settings.print_rendering = !settings.print_rendering
+        });
+        let print_rendering = self.render_settings.print_rendering;
+        self.update_prefs(|prefs| prefs.print_rendering = Some(print_rendering));
+    }
+
+    pub fn toggle_right_to_left(&mut self) {
+        self.right_to_left = !self.right_to_left;
+        let right_to_left = self.right_to_left;
+        self.update_prefs(|prefs| prefs.right_to_left = Some(right_to_left));
+    }
+
+    pub fn toggle_cover_page(&mut self) {
+        self.cover_page = !self.cover_page;
+        let cover_page = self.cover_page;
+        self.update_prefs(|prefs| prefs.cover_page = cover_page);
+        // The current spread is paired differently now
+        self.go_to_page(self.current_page_number);
+    }
+
+    pub fn set_rotations(&mut self, rotations: HashMap<PageNumber, Rotation>) {
+        self.page_cache_sender.set_rotations(rotations.clone());
+        self.rotations = rotations;
+    }
+
+    // Rotates the left page of a spread, as it is the one the page number refers to
+    pub fn rotate_current_page(&mut self) {
+        let mut rotations = self.rotations.clone();
+        let rotation = rotations
+            .remove(&self.current_page_number)
+            .unwrap_or_default()
+            .rotated_clockwise();
+        if !rotation.is_none() {
+            rotations.insert(self.current_page_number, rotation);
+        }
+        self.update_prefs(|prefs| prefs.rotations = rotations.clone());
+        self.set_rotations(rotations);
+    }
+
+    pub fn set_offsets(&mut self, offsets: HashMap<PageNumber, Offset>) {
+        self.page_cache_sender.set_offsets(offsets.clone());
+        self.offsets = offsets;
+    }
+
+    // Nudges the page by the change, none resets it to where the document has it
+    pub fn nudge_page(&mut self, page_number: PageNumber, change: Option<(f64, f64)>) {
+        let mut offsets = self.offsets.clone();
+        let offset = offsets.remove(&page_number).unwrap_or_default();
+        if let Some((x, y)) = change {
+            let offset = Offset {
+                x: offset.x + x,
+                y: offset.y + y,
+            };
+            if !offset.is_none() {
+                offsets.insert(page_number, offset);
+            }
+        }
+        self.update_prefs(|prefs| prefs.offsets = offsets.clone());
+        self.set_offsets(offsets);
+    }
+
+    pub fn set_crops(&mut self, crops: HashMap<PageNumber, Crop>) {
+        self.page_cache_sender.set_crops(crops.clone());
+        self.crops = crops;
+    }
+
+    // None shows the whole page again
+    pub fn set_crop(&mut self, page_number: PageNumber, crop: Option<Crop>) {
+        let mut crops = self.crops.clone();
+        match crop {
+            Some(crop) => crops.insert(page_number, crop),
+            None => crops.remove(&page_number),
+        };
+        if let Some(path) = self.state_path.as_ref() {
+            state::save_crops(path, &crops);
+        }
+        self.set_crops(crops);
+    }
+
+    // While selecting a crop the pages are shown whole, so the selection maps onto the page
+    pub fn show_whole_pages(&self, whole: bool) {
+        if whole {
+            self.page_cache_sender.set_crops(HashMap::new());
+            self.page_cache_sender.set_render_settings(RenderSettings {
+                trim_margins: false,
+                ..self.render_settings.clone()
+            });
+        } else {
+            self.page_cache_sender.set_crops(self.crops.clone());
+            self.page_cache_sender
+                .set_render_settings(self.render_settings.clone());
+        }
+    }
+
+    pub fn set_levels(&mut self, levels: Levels) {
+        self.update_render_settings(|settings| settings.levels = levels);
+    }
+
+    pub fn toggle_trim_margins(&mut self) {
+        self.update_render_settings(|settings| settings.trim_margins = !settings.trim_margins);
+    }
+
+    fn shows_single_page(&self) -> bool {
+        self.view_mode == ViewMode::Single || self.num_pages == Some(1) || self.shows_cover_page()
+    }
+
+    // The cover or an odd last page, whose neighbours are shown in pairs
+    fn is_alone_in_spread(&self) -> bool {
+        self.view_mode == ViewMode::Spread
+            && self.num_pages.unwrap_or(0) > 1
+            && !self.has_right_page()
+    }
+
+    // The grid has no cover page, it would leave a gap in one of its rows
+    fn shows_cover_page(&self) -> bool {
+        self.cover_page && self.view_mode == ViewMode::Spread && self.current_page_number == 0
+    }
+
+    fn page_step(&self) -> usize {
+        match self.view_mode {
+            ViewMode::Single => 1,
+            // The cover has no right page to step over
+            ViewMode::Spread if self.shows_cover_page() => 1,
+            ViewMode::Spread => self.spread_step,
+            ViewMode::Grid => 4,
+        }
+    }
+
+    // Stepping a single page in spreads keeps the previous right page in view
+    pub fn toggle_spread_step(&mut self) {
+        self.spread_step = if self.spread_step == 1 { 2 } else { 1 };
+    }
+
+    pub fn is_at_last_page(&self) -> bool {
+        let last_page_number = self.num_pages.unwrap_or(0).saturating_sub(1);
+        self.current_page_number + self.page_step() > last_page_number
+    }
+
+    pub fn set_loop_start(&mut self) {
+        self.loop_start = Some(self.current_page_number);
+    }
+
+    // The loop ends with the last visible page
+    pub fn set_loop_end(&mut self) {
+        let last_visible_page_number = if self.has_right_page() {
+            self.current_page_number + 1
+        } else {
+            self.current_page_number
+        };
+        self.loop_end = Some(last_visible_page_number);
+    }
+
+    pub fn clear_loop(&mut self) {
+        self.loop_start = None;
+        self.loop_end = None;
+    }
+
+    pub fn active_loop(&self) -> Option<(PageNumber, PageNumber)> {
+        match (self.loop_start, self.loop_end) {
+            (Some(start), Some(end)) if start <= end => Some((start, end)),
+            _ => None,
+        }
+    }
+
+    pub fn is_at_loop_end(&self) -> bool {
+        self.active_loop()
+            .is_some_and(|(_, end)| self.current_page_number + self.page_step() > end)
+    }
+
+    pub fn restart_loop(&mut self) {
+        if let Some((start, _)) = self.active_loop() {
+            self.current_page_number = start;
+        }
+    }
+
     pub fn increase_page_number(&mut self) {
-        if self.current_page_number >= self.num_pages.unwrap_or(0).saturating_sub(1) {
+        self.navigation_direction = Some(NavigationDirection::Forward);
+        if self.is_at_last_page() {
+            self.hit_edge = Some(NavigationDirection::Forward);
+            return;
+        }
+        if self.is_at_loop_end() {
             return;
         }
 
-        self.current_page_number += 1;
+        self.current_page_number += self.page_step();
     }
 
     pub fn decrease_page_number(&mut self) {
-        self.current_page_number = self.current_page_number.saturating_sub(1);
+        self.navigation_direction = Some(NavigationDirection::Backward);
+        if self.current_page_number == 0 {
+            self.hit_edge = Some(NavigationDirection::Backward);
+        }
+        self.current_page_number = self.current_page_number.saturating_sub(self.page_step());
     }
 
-    pub fn cache_initial_pages(&self, area_height: i32) {
-        self.page_cache_sender.send_priority_cache_commands(
-            &[self.current_page_number, self.current_page_number + 1],
-            area_height,
-        );
+    pub fn set_page_number(&mut self, page_number: PageNumber) {
+        self.navigation_direction = None;
+        self.current_page_number = page_number.min(self.num_pages.unwrap_or(0).saturating_sub(1));
     }
 
-    pub fn cache_surrounding_pages(&self, area_height: i32) {
-        self.page_cache_sender.send_cache_commands(
-            &[
-                self.current_page_number.saturating_sub(2),
-                self.current_page_number.saturating_sub(1),
-                self.current_page_number,
-                self.current_page_number + 1,
-                self.current_page_number + 2,
-                self.current_page_number + 3,
-            ],
-            area_height,
-        );
+    // Spreads always start with an even left page, or an odd one after a cover page
+    pub fn go_to_page(&mut self, page_number: PageNumber) {
+        match self.view_mode {
+            ViewMode::Single => self.set_page_number(page_number),
+            ViewMode::Spread if self.cover_page && page_number > 0 => {
+                self.set_page_number(page_number - (page_number + 1) % 2)
+            }
+            ViewMode::Spread if self.cover_page => self.set_page_number(0),
+            ViewMode::Spread => self.set_page_number(page_number - page_number % 2),
+            ViewMode::Grid => self.set_page_number(page_number - page_number % 4),
+        }
+    }
+
+    fn push_history(&mut self, page_number: PageNumber) {
+        self.history.push(page_number);
+        if self.history.len() > MAX_NAVIGATION_HISTORY {
+            self.history.remove(0);
+        }
+        self.undone_history.clear();
+    }
+
+    // Returns false if there is nothing to undo
+    pub fn undo_navigation(&mut self) -> bool {
+        let Some(page_number) = self.history.pop() else {
+            return false;
+        };
+        self.undone_history.push(self.current_page_number);
+        self.set_page_number(page_number);
+        true
+    }
+
+    pub fn redo_navigation(&mut self) -> bool {
+        let Some(page_number) = self.undone_history.pop() else {
+            return false;
+        };
+        self.history.push(self.current_page_number);
+        self.set_page_number(page_number);
+        true
+    }
+
+    pub fn page_label(&self, page_number: PageNumber) -> String {
+        self.page_labels
+            .get(page_number)
+            .cloned()
+            .unwrap_or_else(|| (page_number + 1).to_string())
+    }
+
+    // Labels are matched first, so the printed page numbers can be entered
+    pub fn find_page(&self, text: &str) -> Option<PageNumber> {
+        self.page_labels
+            .iter()
+            .position(|label| label.eq_ignore_ascii_case(text))
+            .or_else(|| text.parse::<usize>().ok()?.checked_sub(1))
+    }
+
+    fn current_piece_index(&self) -> Option<usize> {
+        self.pieces
+            .iter()
+            .rposition(|(first_page_number, _)| *first_page_number <= self.current_page_number)
+    }
+
+    pub fn current_piece_title(&self) -> Option<&str> {
+        self.current_piece_index()
+            .map(|piece_index| self.pieces[piece_index].1.as_str())
+    }
+
+    // One-based number of the current piece and the number of pieces
+    pub fn piece_status(&self) -> Option<(usize, usize)> {
+        self.current_piece_index()
+            .map(|piece_index| (piece_index + 1, self.pieces.len()))
     }
 
-    pub fn request_to_draw_pages(&self) {
-        if self.num_pages == Some(1) {
+    fn switch_to_piece(&mut self, piece_index: usize) {
+        let Some(&(first_page_number, _)) = self.pieces.get(piece_index) else {
+            return;
+        };
+        if let Some(current_piece_index) = self.current_piece_index() {
+            self.piece_positions
+                .insert(current_piece_index, self.current_page_number);
+        }
+        let page_number = self
+            .piece_positions
+            .get(&piece_index)
+            .copied()
+            .unwrap_or(first_page_number);
+        self.set_page_number(page_number);
+    }
+
+    // Picks up reading a piece at one of its own pages
+    pub fn continue_piece(&mut self, piece_index: usize, local_page_number: PageNumber) {
+        if let Some(&(first_page_number, _)) = self.pieces.get(piece_index) {
+            self.set_page_number(first_page_number + local_page_number);
+        }
+    }
+
+    pub fn next_piece(&mut self) {
+        if let Some(piece_index) = self.current_piece_index() {
+            self.switch_to_piece(piece_index + 1);
+        }
+    }
+
+    pub fn previous_piece(&mut self) {
+        if let Some(piece_index) = self
+            .current_piece_index()
+            .and_then(|piece_index| piece_index.checked_sub(1))
+        {
+            self.switch_to_piece(piece_index);
+        }
+    }
+
+    pub fn add_stroke(&mut self, page_number: PageNumber, stroke: Stroke) {
+        self.annotations
+            .entry(page_number)
+            .or_default()
+            .push(stroke);
+        if let Some(path) = self.state_path.as_ref() {
+            annotations::save(path, &self.annotations);
+        }
+    }
+
+    pub fn is_bookmarked(&self) -> bool {
+        self.bookmarks.contains(&self.current_page_number)
+    }
+
+    pub fn toggle_bookmark(&mut self) {
+        match self.bookmarks.binary_search(&self.current_page_number) {
+            Ok(index) => {
+                self.bookmarks.remove(index);
+            }
+            Err(index) => self.bookmarks.insert(index, self.current_page_number),
+        }
+        if let Some(path) = self.state_path.as_ref() {
+            state::save_bookmarks(path, &self.bookmarks);
+        }
+    }
+
+    pub fn next_bookmark(&mut self) {
+        let next = self
+            .bookmarks
+            .iter()
+            .find(|&&bookmark| bookmark > self.current_page_number)
+            .or(self.bookmarks.first());
+        if let Some(&page_number) = next {
+            self.set_page_number(page_number);
+        }
+    }
+
+    pub fn prev_bookmark(&mut self) {
+        let prev = self
+            .bookmarks
+            .iter()
+            .rev()
+            .find(|&&bookmark| bookmark < self.current_page_number)
+            .or(self.bookmarks.last());
+        if let Some(&page_number) = prev {
+            self.set_page_number(page_number);
+        }
+    }
+
+    // Pages of a spread share the area width besides the gap, also when one is shown alone
+    fn page_size(&self, area: RenderSize) -> RenderSize {
+        let width = if self.view_mode == ViewMode::Single || self.num_pages == Some(1) {
+            area.width
+        } else {
+            (area.width - self.render_settings.page_gap) / 2
+        };
+        // The rows of the grid share the height the same way
+        let height = if self.view_mode == ViewMode::Grid {
+            (area.height - self.render_settings.page_gap) / 2
+        } else {
+            area.height
+        };
+        RenderSize { width, height }.scaled(self.render_scale)
+    }
+
+    // The pages of the second row of the grid, from the left
+    pub fn grid_row_page(&self, index: usize) -> Option<PageNumber> {
+        let page_number = self.current_page_number + 2 + index;
+        (self.view_mode == ViewMode::Grid && index < 2 && page_number < self.num_pages.unwrap_or(0))
+            .then_some(page_number)
+    }
+
+    // Returns the estimated memory all pages take in full resolution
+    pub fn cache_all_pages(&self, area: RenderSize) -> usize {
+        let size = self.page_size(area);
+        let num_pages = self.num_pages.unwrap_or(0);
+        let pages: Vec<PageNumber> = (0..num_pages).collect();
+        self.page_cache_sender
+            .send_background_cache_commands(&pages, size);
+        (size.width.max(1) * size.height.max(1)) as usize * 4 * num_pages
+    }
+
+    pub fn cache_initial_pages(&self, area: RenderSize) {
+        if area.is_empty() {
+            return;
+        }
+        let visible_page_numbers = self.visible_page_numbers();
+        self.page_cache_sender
+            .set_num_visible_pages(visible_page_numbers.len());
+        self.page_cache_sender
+            .send_priority_cache_commands(&visible_page_numbers, self.page_size(area));
+    }
+
+    // Prefetches more pages in the direction the reader is moving
+    pub fn cache_surrounding_pages(&self, area: RenderSize) {
+        if area.is_empty() {
+            return;
+        }
+        let size = self.page_size(area);
+        // Rendered in one go, so the pages of a spread or grid get sharp at the same time
+        let visible_page_numbers = self.visible_page_numbers();
+        self.page_cache_sender
+            .set_num_visible_pages(visible_page_numbers.len());
+        if visible_page_numbers.len() > 1 {
+            self.page_cache_sender
+                .send_priority_cache_commands(&visible_page_numbers, size);
+        }
+        // Estimated from the area, as the shape of the pages is not known yet
+        let page_memory = (size.width.max(1) * size.height.max(1)) as usize * 4;
+        let max_pages = self.cache_pages.min(self.cache_memory / page_memory).max(3);
+        let prefetch_pages = self.prefetch_pages.min((max_pages - 1) / 2).max(1);
+        let (pages_behind, pages_ahead) = match self.navigation_direction {
+            Some(NavigationDirection::Forward) => (1, prefetch_pages + 2),
+            Some(NavigationDirection::Backward) => (prefetch_pages + 1, prefetch_pages - 1),
+            None => (prefetch_pages - 1, prefetch_pages),
+        };
+        // The second row of the grid is counted as visible, not as ahead
+        let pages_ahead = match self.view_mode {
+            ViewMode::Grid => pages_ahead + 2,
+            _ => pages_ahead,
+        };
+        let first_page_number = self.current_page_number.saturating_sub(pages_behind);
+        let pages: Vec<PageNumber> =
+            (first_page_number..=self.current_page_number + pages_ahead).collect();
+        self.page_cache_sender.send_cache_commands(&pages, size);
+    }
+
+    // An odd last page in a spread has no right neighbour and is shown alone
+    fn has_right_page(&self) -> bool {
+        !self.shows_single_page() && self.current_page_number + 1 < self.num_pages.unwrap_or(0)
+    }
+
+    pub fn request_to_draw_pages(&self, area: RenderSize) {
+        let size = self.page_size(area);
+        if !self.has_right_page() {
             self.page_cache_sender.send_retrieve_command(
                 cache::RetrievePagesCommand::GetCurrentPage {
                     page_number: self.current_page_number,
+                    size,
                 },
             )
         } else {
             self.page_cache_sender.send_retrieve_command(
                 cache::RetrievePagesCommand::GetCurrentTwoPages {
                     page_left_number: self.current_page_number,
+                    size,
                 },
             )
         }
+        for page_number in (0..2).filter_map(|index| self.grid_row_page(index)) {
+            self.request_page(page_number);
+        }
+    }
+
+    // Shows the following pages without turning to them, until the current pages are drawn again
+    pub fn request_to_draw_next_pages(&self, area: RenderSize) -> bool {
+        let page_number = self.current_page_number + self.page_step();
+        let num_pages = self.num_pages.unwrap_or(0);
+        // The next grid would only replace half of the pages
+        if page_number >= num_pages || self.view_mode == ViewMode::Grid {
+            return false;
+        }
+        let size = self.page_size(area);
+        let command = if self.view_mode == ViewMode::Single || page_number + 1 >= num_pages {
+            cache::RetrievePagesCommand::GetCurrentPage { page_number, size }
+        } else {
+            cache::RetrievePagesCommand::GetCurrentTwoPages {
+                page_left_number: page_number,
+                size,
+            }
+        };
+        self.page_cache_sender.send_retrieve_command(command);
+        true
+    }
+
+    pub fn request_page(&self, page_number: PageNumber) {
+        self.page_cache_sender
+            .send_retrieve_command(cache::RetrievePagesCommand::GetPage { page_number });
+    }
+
+    pub fn cache_pages_with_size(&self, pages: &[PageNumber], size: RenderSize) {
+        self.page_cache_sender
+            .send_cache_commands(pages, size.scaled(self.render_scale));
+    }
+
+    // The pages that are shown at once, rendered in one go so they get sharp together
+    pub fn visible_page_numbers(&self) -> Vec<PageNumber> {
+        let num_visible_pages = match self.view_mode {
+            ViewMode::Grid => 4,
+            _ if self.has_right_page() => 2,
+            _ => 1,
+        };
+        // The last grid or spread may be cut short by the end of the document
+        let end = (self.current_page_number + num_visible_pages)
+            .min(self.num_pages.unwrap_or(0))
+            .max(self.current_page_number + 1);
+        (self.current_page_number..end).collect()
     }
 
     pub fn is_left_page(&self, page_number: PageNumber) -> bool {
         page_number == self.current_page_number
     }
     pub fn is_right_page(&self, page_number: PageNumber) -> bool {
-        page_number == self.current_page_number + 1
+        self.has_right_page() && page_number == self.current_page_number + 1
     }
 }
 
@@ -106,10 +941,13 @@ pub fn toggle_fullscreen(ui: &Ui) {
             ui.header_bar.hide();
             ui.bottom_bar.hide();
             ui.window.fullscreen();
-            ui.document_canvas
-                .as_ref()
-                .unwrap()
-                .cache_surrounding_pages(ui.image_container.height() + ui.header_bar.height());
+            // Also called at startup, before any document is loaded
+            if let Some(doc) = ui.document_canvas.as_ref() {
+                doc.cache_surrounding_pages(RenderSize {
+                    width: ui.scroller.width(),
+                    height: ui.scroller.height() + ui.header_bar.height(),
+                });
+            }
         }
         false => {
             ui.header_bar.show();
@@ -119,86 +957,2275 @@ pub fn toggle_fullscreen(ui: &Ui) {
     }
 }
 
+// Page numbers are zero-based internally, but shown one-based
+fn format_page_status(doc: &DocumentCanvas, view_mode: ViewMode) -> String {
+    let current_page_number = doc.current_page_number;
+    let num_pages = doc.num_pages.unwrap_or(0);
+    if view_mode == ViewMode::Grid && current_page_number + 1 < num_pages {
+        let last_page_number = usize::min(current_page_number + 3, num_pages - 1);
+        return format!(
+            "{}-{} / {}",
+            doc.page_label(current_page_number),
+            doc.page_label(last_page_number),
+            num_pages
+        );
+    }
+    // The last page of a spread and the cover page can be shown alone
+    let shows_single_page = view_mode == ViewMode::Single
+        || num_pages == 1
+        || current_page_number + 1 >= num_pages
+        || (doc.cover_page && current_page_number == 0);
+    if shows_single_page {
+        format!("{} / {}", doc.page_label(current_page_number), num_pages)
+    } else {
+        format!(
+            "{}-{} / {}",
+            doc.page_label(current_page_number),
+            doc.page_label(current_page_number + 1),
+            num_pages
+        )
+    }
+}
+
 fn update_page_status(ui: &Ui) {
     let page_status = match &ui.document_canvas {
+        Some(doc) if ui.continuous => {
+            // The pages coming into view are requested while scrolling
+            format_page_status(doc, ViewMode::Single)
+        }
         Some(doc) => {
-            doc.request_to_draw_pages();
-
-            if doc.num_pages.unwrap_or(0) == 1 {
-                format!(
-                    "{} / {}",
-                    doc.current_page_number,
-                    doc.num_pages.unwrap_or(0)
-                )
-            } else {
-                format!(
-                    "{}-{} / {}",
-                    doc.current_page_number + 1,
-                    doc.current_page_number + 2,
-                    doc.num_pages.unwrap_or(0)
-                )
-            }
+            doc.request_to_draw_pages(render_area(ui));
+            update_grid_row(ui, doc);
+            show_loading_spinner(ui);
+            format_page_status(doc, doc.view_mode)
         }
         None => "No document loaded!".to_string(),
     };
+    let page_status = match ui
+        .document_canvas
+        .as_ref()
+        .and_then(DocumentCanvas::piece_status)
+    {
+        Some((piece_number, num_pieces)) => {
+            format!("Doc {}/{} · {}", piece_number, num_pieces, page_status)
+        }
+        None => page_status,
+    };
+    let page_status = match &ui.document_canvas {
+        Some(doc) if doc.is_bookmarked() => format!("★ {}", page_status),
+        _ => page_status,
+    };
+    let page_status = match ui
+        .document_canvas
+        .as_ref()
+        .and_then(|doc| doc.active_loop())
+    {
+        Some((start, end)) => format!("{} ⟲ {}-{}", page_status, start + 1, end + 1),
+        None => page_status,
+    };
     ui.page_indicator.set_label(page_status.as_str());
+
+    let title = match ui
+        .document_canvas
+        .as_ref()
+        .and_then(|doc| doc.current_piece_title().or(doc.title.as_deref()))
+    {
+        Some(title) => format!("{} - Music Reader", title),
+        None => "Music Reader".to_string(),
+    };
+    ui.window.set_title(Some(&title));
+    update_notes(ui);
 }
 
-fn process_right_click(ui: &mut Ui, _x: f64, _y: f64) {
-    if ui.document_canvas.is_none() {
+fn update_notes(ui: &Ui) {
+    let (Some(notes), Some(doc)) = (ui.notes.as_ref(), ui.document_canvas.as_ref()) else {
         return;
+    };
+    let text = notes.text_for_page(doc.current_page_number);
+    // Replacing the same text would scroll the notes back up
+    let buffer = ui.notes_view.buffer();
+    if buffer.text(&buffer.start_iter(), &buffer.end_iter(), false) != text {
+        buffer.set_text(&text);
     }
-
-    ui.document_canvas.as_mut().unwrap().decrease_page_number();
-    update_page_status(ui);
 }
 
-fn process_left_click(ui: &mut Ui, x: f64, y: f64) {
-    if let Some(last_touch_time) = ui.last_touch_time {
-        if last_touch_time.elapsed() < Duration::from_millis(100) {
-            // Prevent accidental double touching
-            return;
-        }
-    }
-
-    ui.last_touch_time = Some(Instant::now());
-    if ui.document_canvas.is_none() {
+fn toggle_notes(ui: &Ui) {
+    if ui.notes.is_none() {
+        ui.notify("No notes for this document");
         return;
     }
-    let edge_touch_area_size = f64::min(100.0, ui.app_wrapper.height() as f64 / 10.0);
+    ui.notes_panel
+        .set_reveal_child(!ui.notes_panel.reveals_child());
+}
 
-    let center = ui.app_wrapper.width() / 2;
-    if y < edge_touch_area_size {
-        if x > ui.app_wrapper.width() as f64 - edge_touch_area_size {
-            ui.window.close();
-            return;
-        }
-        toggle_fullscreen(ui);
-    } else if x > center as f64 {
-        if x < ui.app_wrapper.width() as f64 * 0.75 {
-            ui.document_canvas.as_mut().unwrap().increase_page_number();
-        } else {
-            ui.document_canvas.as_mut().unwrap().increase_page_number();
-            ui.document_canvas.as_mut().unwrap().increase_page_number();
-        }
-    } else if x < center as f64 {
-        if x > ui.app_wrapper.width() as f64 * 0.25 {
-            ui.document_canvas.as_mut().unwrap().decrease_page_number();
-        } else {
-            ui.document_canvas.as_mut().unwrap().decrease_page_number();
-            ui.document_canvas.as_mut().unwrap().decrease_page_number();
+// Spins while pages are retrieved, but only becomes visible if that takes noticeably long
+fn show_loading_spinner(ui: &Ui) {
+    ui.loading_spinner.start();
+    let spinner = ui.loading_spinner.clone();
+    glib::timeout_add_local_once(Duration::from_millis(150), move || {
+        if spinner.is_spinning() {
+            spinner.set_visible(true);
         }
+    });
+}
+
+fn hide_loading_spinner(ui: &Ui) {
+    ui.loading_spinner.stop();
+    ui.loading_spinner.set_visible(false);
+}
+
+fn update_cache_stats(ui: &Ui, stats: cache::CacheStats) {
+    let requests = stats.hits + stats.misses;
+    let hit_rate = if requests > 0 {
+        stats.hits * 100 / requests
+    } else {
+        0
+    };
+    ui.stats_label.set_label(&format!(
+        "Last render {} ms\nHits {}% of {}\n{} pages cached\n{} MB",
+        stats.last_render_time.as_millis(),
+        hit_rate,
+        requests,
+        stats.cached_pages,
+        stats.memory_usage / BYTES_PER_MB
+    ));
+}
+
+fn toggle_stats(ui: &Ui) {
+    ui.stats_label.set_visible(!ui.stats_label.is_visible());
+}
+
+fn update_cache_progress(ui: &Ui, cached_pages: usize, total_pages: usize) {
+    let fraction = if total_pages == 0 {
+        0.0
+    } else {
+        cached_pages as f64 / total_pages as f64
+    };
+    ui.cache_progress.set_fraction(fraction.min(1.0));
+    ui.cache_progress.set_tooltip_text(Some(&format!(
+        "{} of {} pages cached",
+        cached_pages, total_pages
+    )));
+}
+
+fn toggle_fit_mode(ui: &mut Ui) {
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.toggle_fit_mode();
+        let message = match doc.render_settings.fit_mode {
+            FitMode::Height => "Fit to height",
+            FitMode::Width => "Fit to width",
+            FitMode::Native => "Fit to height without enlarging",
+        };
+        ui.notify(message);
     }
+    apply_fit_mode(ui);
     update_page_status(ui);
 }
 
-impl Ui {
-    pub fn build(app: &Application) -> Rc<RefCell<Ui>> {
+// Fit to width renders taller pages, which have to scroll instead of shrink
+fn apply_fit_mode(ui: &Ui) {
+    let can_shrink = !is_fit_to_width(ui);
+    ui.image_left.set_can_shrink(can_shrink);
+    ui.image_right.set_can_shrink(can_shrink);
+    ui.image_bottom_left.set_can_shrink(can_shrink);
+    ui.image_bottom_right.set_can_shrink(can_shrink);
+}
+
+fn toggle_invert(ui: &mut Ui) {
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.toggle_invert();
+    }
+    update_page_status(ui);
+}
+
+fn toggle_print_rendering(ui: &mut Ui) {
+    let Some(doc) = ui.document_canvas.as_mut() else {
+        return;
+    };
+    doc.toggle_print_rendering();
+    if doc.render_settings.print_rendering {
+        ui.notify("Rendering for print");
+    } else {
+        ui.notify("Rendering for the screen");
+    }
+}
+
+// Levels apply to every document and are remembered across sessions
+fn set_levels(ui: &mut Ui, levels: Levels) {
+    ui.levels = levels;
+    state::save_levels(levels);
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.set_levels(levels);
+    }
+    update_page_status(ui);
+}
+
+fn build_levels_popover(ui: Rc<RefCell<Ui>>) -> Popover {
+    let levels = ui.borrow().levels;
+    let brightness = Scale::with_range(gtk::Orientation::Horizontal, -100.0, 100.0, 1.0);
+    brightness.set_value(levels.brightness as f64);
+    brightness.add_mark(0.0, gtk::PositionType::Bottom, None);
+    let contrast = Scale::with_range(gtk::Orientation::Horizontal, 0.0, 100.0, 1.0);
+    contrast.set_value(levels.contrast as f64);
+
+    let content = Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .width_request(240)
+        .build();
+    content.append(&Label::builder().label("Brightness").xalign(0.0).build());
+    content.append(&brightness);
+    content.append(&Label::builder().label("Contrast").xalign(0.0).build());
+    content.append(&contrast);
+
+    brightness.connect_value_changed(clone!(@weak ui => move |scale| {
+        let levels = Levels {
+            brightness: scale.value() as i32,
+            ..ui.borrow().levels
+        };
+        set_levels(&mut ui.borrow_mut(), levels);
+    }));
+    contrast.connect_value_changed(clone!(@weak ui => move |scale| {
+        let levels = Levels {
+            contrast: scale.value() as i32,
+            ..ui.borrow().levels
+        };
+        set_levels(&mut ui.borrow_mut(), levels);
+    }));
+    Popover::builder().child(&content).build()
+}
+
+const SETTINGS_ROW_HEIGHT: i32 = 56;
+const VIEW_MODES: [ViewMode; 3] = [ViewMode::Single, ViewMode::Spread, ViewMode::Grid];
+const FIT_MODES: [FitMode; 3] = [FitMode::Height, FitMode::Width, FitMode::Native];
+
+// Applies the settings like at startup, the open document only takes the changed ones
+fn change_config(ui: &mut Ui, change: impl FnOnce(&mut Config)) {
+    let previous = ui.config.clone();
+    change(&mut ui.config);
+    let config = ui.config.clone();
+    if config.cache_pages != previous.cache_pages {
+        ui.cache_pages = config.cache_pages.max(2);
+    }
+    if config.cache_memory_mb != previous.cache_memory_mb {
+        ui.cache_memory = config.cache_memory_mb * BYTES_PER_MB;
+    }
+    if config.prefetch_pages != previous.prefetch_pages {
+        ui.prefetch_pages = config.prefetch_pages.max(1);
+    }
+    if config.spread_step != previous.spread_step {
+        ui.spread_step = config.spread_step.clamp(1, 2);
+    }
+    ui.default_view_mode = config.view_mode;
+    ui.default_fit_mode = config.fit_mode;
+    ui.default_invert = config.invert;
+    ui.default_print_rendering = config.print_rendering;
+    ui.page_background = config.page_background;
+    ui.fade_resolution_upgrades = config.fade_resolution_upgrades;
+    ui.center_single_pages = config.center_single_pages;
+    ui.edge_feedback = config.edge_feedback;
+    ui.page_turn_buttons.set_visible(config.page_turn_buttons);
+    ui.click_zones = config.click_zones;
+    let click_zones = config.click_zones;
+    ui.click_zones_overlay
+        .set_draw_func(move |_, context, width, height| {
+            draw::draw_click_zones(context, width as f64, height as f64, &click_zones);
+        });
+    let page_gap = config.page_gap.max(0);
+    for container in [&ui.pages_box, &ui.image_container, &ui.grid_row] {
+        container.set_spacing(page_gap);
+    }
+
+    let prefetch_pages = ui.prefetch_pages;
+    let spread_step = ui.spread_step;
+    let (cache_pages, cache_memory, precache_all) =
+        (ui.cache_pages, ui.cache_memory, ui.precache_all);
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.prefetch_pages = prefetch_pages;
+        if config.cache_pages != previous.cache_pages
+            || config.cache_memory_mb != previous.cache_memory_mb
+        {
+            // Like when loading, every page still fits if all of them are cached
+            let cache_pages = if precache_all {
+                cache_pages.max(doc.num_pages.unwrap_or(0))
+            } else {
+                cache_pages
+            };
+            doc.set_cache_limits(cache_pages, cache_memory);
+        }
+        // Toggling the step while reading only changes the open document
+        if config.spread_step != previous.spread_step {
+            doc.spread_step = spread_step;
+        }
+        if config.view_mode != previous.view_mode {
+            doc.set_view_mode(config.view_mode);
+        }
+        let mut settings = doc.render_settings.clone();
+        if config.fit_mode != previous.fit_mode {
+            settings.fit_mode = config.fit_mode;
+        }
+        if config.invert != previous.invert {
+            settings.invert = config.invert;
+        }
+        if config.print_rendering != previous.print_rendering {
+            settings.print_rendering = config.print_rendering;
+        }
+        settings.background = config.page_background;
+        settings.page_gap = page_gap;
+        // Pages are only rendered again if they would look different
+        if settings != doc.render_settings {
+            doc.update_render_settings(|render_settings| *render_settings = settings);
+        }
+    }
+    apply_fit_mode(ui);
+    update_page_status(ui);
+    render_resized_pages(ui);
+}
+
+// Groups the rows of the settings dialog under a heading
+fn settings_group(content: &Box, title: &str) -> Box {
+    let heading = Label::builder()
+        .label(title)
+        .xalign(0.0)
+        .margin_top(18)
+        .margin_bottom(6)
+        .build();
+    heading.add_css_class("heading");
+    let rows = Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    content.append(&heading);
+    content.append(&gtk::Frame::builder().child(&rows).build());
+    rows
+}
+
+// Rows and controls are large enough to be used with a finger
+fn settings_row(rows: &Box, title: &str, control: &impl IsA<gtk::Widget>) {
+    let row = Box::builder()
+        .spacing(24)
+        .height_request(SETTINGS_ROW_HEIGHT)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    row.append(
+        &Label::builder()
+            .label(title)
+            .xalign(0.0)
+            .hexpand(true)
+            .build(),
+    );
+    control.set_valign(gtk::Align::Center);
+    control.set_height_request(SETTINGS_ROW_HEIGHT - 16);
+    row.append(control);
+    rows.append(&row);
+}
+
+fn settings_switch(
+    ui: Rc<RefCell<Ui>>,
+    rows: &Box,
+    title: &str,
+    active: bool,
+    change: impl Fn(&mut Config, bool) + 'static,
+) {
+    let switch = gtk::Switch::builder().active(active).build();
+    switch.connect_active_notify(clone!(@weak ui => move |switch| {
+        let active = switch.is_active();
+        change_config(&mut ui.borrow_mut(), |config| change(config, active));
+    }));
+    settings_row(rows, title, &switch);
+}
+
+fn settings_spin_button(
+    ui: Rc<RefCell<Ui>>,
+    rows: &Box,
+    title: &str,
+    (min, max, value): (f64, f64, f64),
+    change: impl Fn(&mut Config, f64) + 'static,
+) {
+    let spin_button = SpinButton::with_range(min, max, 1.0);
+    spin_button.set_value(value);
+    spin_button.connect_value_changed(clone!(@weak ui => move |spin_button| {
+        let value = spin_button.value();
+        change_config(&mut ui.borrow_mut(), |config| change(config, value));
+    }));
+    settings_row(rows, title, &spin_button);
+}
+
+fn settings_drop_down(
+    ui: Rc<RefCell<Ui>>,
+    rows: &Box,
+    title: &str,
+    (labels, selected): (&[&str], usize),
+    change: impl Fn(&mut Config, usize) + 'static,
+) {
+    let drop_down = gtk::DropDown::from_strings(labels);
+    drop_down.set_selected(selected as u32);
+    drop_down.connect_selected_notify(clone!(@weak ui => move |drop_down| {
+        let selected = drop_down.selected() as usize;
+        change_config(&mut ui.borrow_mut(), |config| change(config, selected));
+    }));
+    settings_row(rows, title, &drop_down);
+}
+
+// Changes apply right away and are written to the config file when the dialog is closed
+pub fn show_settings_dialog(ui: Rc<RefCell<Ui>>) {
+    let config = ui.borrow().config.clone();
+    let dialog = Dialog::builder()
+        .title("Settings")
+        .transient_for(&ui.borrow().window)
+        .modal(true)
+        .default_width(480)
+        .default_height(640)
+        .build();
+    dialog.add_button("_Close", ResponseType::Close);
+
+    let content = Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .margin_start(18)
+        .margin_end(18)
+        .margin_bottom(18)
+        .build();
+
+    let rows = settings_group(&content, "Pages");
+    let view_mode = VIEW_MODES
+        .iter()
+        .position(|&view_mode| view_mode == config.view_mode)
+        .unwrap_or(0);
+    settings_drop_down(
+        Rc::clone(&ui),
+        &rows,
+        "View",
+        (&["Single page", "Spread", "Grid"], view_mode),
+        |config, selected| config.view_mode = VIEW_MODES[selected],
+    );
+    let fit_mode = FIT_MODES
+        .iter()
+        .position(|&fit_mode| fit_mode == config.fit_mode)
+        .unwrap_or(0);
+    settings_drop_down(
+        Rc::clone(&ui),
+        &rows,
+        "Fit",
+        (&["Height", "Width", "Without enlarging"], fit_mode),
+        |config, selected| config.fit_mode = FIT_MODES[selected],
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Invert colors",
+        config.invert,
+        |config, active| config.invert = active,
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Render for print",
+        config.print_rendering,
+        |config, active| config.print_rendering = active,
+    );
+    let background = config.page_background;
+    let color_button = gtk::ColorButton::with_rgba(&gdk::RGBA::new(
+        background.red as f32,
+        background.green as f32,
+        background.blue as f32,
+        1.0,
+    ));
+    color_button.set_use_alpha(false);
+    color_button.connect_color_set(clone!(@weak ui => move |color_button| {
+        let rgba = color_button.rgba();
+        let background = Color {
+            red: rgba.red() as f64,
+            green: rgba.green() as f64,
+            blue: rgba.blue() as f64,
+        };
+        change_config(&mut ui.borrow_mut(), |config| config.page_background = background);
+    }));
+    settings_row(&rows, "Page background", &color_button);
+    settings_spin_button(
+        Rc::clone(&ui),
+        &rows,
+        "Gap between pages",
+        (0.0, 200.0, config.page_gap as f64),
+        |config, value| config.page_gap = value as i32,
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Center single pages",
+        config.center_single_pages,
+        |config, active| config.center_single_pages = active,
+    );
+
+    let rows = settings_group(&content, "Page turns");
+    settings_drop_down(
+        Rc::clone(&ui),
+        &rows,
+        "Turn spreads by",
+        (
+            &["One page", "Two pages"],
+            config.spread_step.clamp(1, 2) - 1,
+        ),
+        |config, selected| config.spread_step = selected + 1,
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Buttons at the edges",
+        config.page_turn_buttons,
+        |config, active| config.page_turn_buttons = active,
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Turn two pages at the outer sides",
+        config.click_zones.double_turns,
+        |config, active| config.click_zones.double_turns = active,
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Tell when the first or last page is reached",
+        config.edge_feedback,
+        |config, active| config.edge_feedback = active,
+    );
+    settings_switch(
+        Rc::clone(&ui),
+        &rows,
+        "Fade in sharper pages",
+        config.fade_resolution_upgrades,
+        |config, active| config.fade_resolution_upgrades = active,
+    );
+
+    let rows = settings_group(&content, "Cache");
+    settings_spin_button(
+        Rc::clone(&ui),
+        &rows,
+        "Pages rendered ahead",
+        (1.0, 20.0, config.prefetch_pages as f64),
+        |config, value| config.prefetch_pages = value as usize,
+    );
+    settings_spin_button(
+        Rc::clone(&ui),
+        &rows,
+        "Pages kept",
+        (2.0, 1000.0, config.cache_pages as f64),
+        |config, value| config.cache_pages = value as usize,
+    );
+    settings_spin_button(
+        Rc::clone(&ui),
+        &rows,
+        "Memory in MB",
+        (1.0, 16384.0, config.cache_memory_mb as f64),
+        |config, value| config.cache_memory_mb = value as usize,
+    );
+
+    let scroller = ScrolledWindow::builder()
+        .hscrollbar_policy(PolicyType::Never)
+        .vexpand(true)
+        .child(&content)
+        .build();
+    dialog.content_area().append(&scroller);
+
+    dialog.connect_response(clone!(@weak ui => move |dialog, _response| {
+        if let Err(e) = config::save(&ui.borrow().config) {
+            error!("Failed saving config: {:#}", e);
+            ui.borrow().notify("The settings could not be saved");
+        }
+        dialog.destroy();
+    }));
+    dialog.present();
+}
+
+// The lower page stays in image_left, which is moved to the right for right to left documents
+fn apply_reading_direction(ui: &Ui) {
+    let right_to_left = ui
+        .document_canvas
+        .as_ref()
+        .map(|doc| doc.right_to_left)
+        .unwrap_or(ui.right_to_left);
+    // Every page is mirrored on its own, the pages also swap places like in a mirror
+    let mirror = ui
+        .document_canvas
+        .as_ref()
+        .is_some_and(|doc| doc.render_settings.mirror);
+    if right_to_left != mirror {
+        ui.image_container
+            .reorder_child_after(&ui.image_left, Some(&ui.image_right));
+        ui.grid_row
+            .reorder_child_after(&ui.image_bottom_left, Some(&ui.image_bottom_right));
+    } else {
+        ui.image_container
+            .reorder_child_after(&ui.image_right, Some(&ui.image_left));
+        ui.grid_row
+            .reorder_child_after(&ui.image_bottom_right, Some(&ui.image_bottom_left));
+    }
+}
+
+fn toggle_right_to_left(ui: &mut Ui) {
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.toggle_right_to_left();
+    }
+    apply_reading_direction(ui);
+}
+
+fn toggle_mirror(ui: &mut Ui) {
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.toggle_mirror();
+    }
+    apply_reading_direction(ui);
+}
+
+fn toggle_cover_page(ui: &mut Ui) {
+    navigate(ui, DocumentCanvas::toggle_cover_page);
+    match ui.document_canvas.as_ref().map(|doc| doc.cover_page) {
+        Some(true) => ui.notify("The first page is shown alone"),
+        Some(false) => ui.notify("The first page starts a spread"),
+        None => {}
+    }
+}
+
+fn toggle_search(ui: &Ui) {
+    let search_mode = !ui.search_bar.is_search_mode();
+    ui.search_bar.set_search_mode(search_mode);
+    if search_mode {
+        ui.search_entry.grab_focus();
+    }
+}
+
+// An empty text only clears the previous search
+fn start_search(ui: Rc<RefCell<Ui>>, text: String) {
+    let serial = ui.borrow().search_serial.get() + 1;
+    ui.borrow().search_serial.set(serial);
+    ui.borrow_mut().search_hits.clear();
+    ui.borrow_mut().search_text = text.clone();
+    ui.borrow_mut().searching = false;
+    update_search_status(&ui.borrow());
+    if text.is_empty() {
+        return;
+    }
+    let Some(loaded_files) = ui.borrow().loaded_files.clone() else {
+        ui.borrow().notify("No document loaded!");
+        return;
+    };
+    ui.borrow_mut().searching = true;
+    update_search_status(&ui.borrow());
+
+    let options = OpenOptions {
+        with_dividers: loaded_files.with_dividers,
+        passwords: ui.borrow().passwords.clone(),
+        page_maps: ui.borrow().page_maps.clone(),
+    };
+    let hits = search::spawn_search(loaded_files.paths, options, text);
+    let search_serial = Rc::clone(&ui.borrow().search_serial);
+    glib::spawn_future_local(clone!(@weak ui => async move {
+        while let Ok(page_number) = hits.recv().await {
+            // Dropping the receiver stops the search of text that is no longer looked for
+            if search_serial.get() != serial {
+                return;
+            }
+            let is_first_hit = ui.borrow().search_hits.is_empty();
+            ui.borrow_mut().search_hits.push(page_number);
+            if is_first_hit {
+                navigate(&mut ui.borrow_mut(), |doc| doc.go_to_page(page_number));
+            }
+            update_search_status(&ui.borrow());
+        }
+        if search_serial.get() == serial {
+            ui.borrow_mut().searching = false;
+            update_search_status(&ui.borrow());
+        }
+    }));
+}
+
+fn update_search_status(ui: &Ui) {
+    let status = match ui.search_hits.len() {
+        _ if ui.search_text.is_empty() => String::new(),
+        0 if ui.searching => "Searching…".to_string(),
+        0 => "Not found".to_string(),
+        1 => "1 page".to_string(),
+        hits => format!("{} pages", hits),
+    };
+    // More pages may still be found
+    let status = if ui.searching && !ui.search_hits.is_empty() {
+        format!("{}…", status)
+    } else {
+        status
+    };
+    ui.search_status.set_label(&status);
+}
+
+// Continues behind the visible pages and starts over at the other end
+fn go_to_search_hit(ui: &mut Ui, forward: bool) {
+    let Some(doc) = ui.document_canvas.as_ref() else {
+        return;
+    };
+    let visible_page_numbers = doc.visible_page_numbers();
+    let first_visible = visible_page_numbers[0];
+    let last_visible = *visible_page_numbers.last().unwrap();
+    let hit = if forward {
+        ui.search_hits
+            .iter()
+            .find(|&&page_number| page_number > last_visible)
+            .or(ui.search_hits.first())
+    } else {
+        ui.search_hits
+            .iter()
+            .rev()
+            .find(|&&page_number| page_number < first_visible)
+            .or(ui.search_hits.last())
+    };
+    let Some(&page_number) = hit else {
+        return;
+    };
+    navigate(ui, |doc| doc.go_to_page(page_number));
+}
+
+fn toggle_view_mode(ui: &mut Ui) {
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.toggle_view_mode();
+    }
+    update_page_status(ui);
+    render_resized_pages(ui);
+}
+
+// Pages past the end of the document leave their place in the grid empty
+fn update_grid_row(ui: &Ui, doc: &DocumentCanvas) {
+    ui.grid_row.set_visible(doc.view_mode == ViewMode::Grid);
+    for (index, picture) in [&ui.image_bottom_left, &ui.image_bottom_right]
+        .into_iter()
+        .enumerate()
+    {
+        if doc.grid_row_page(index).is_none() {
+            picture.set_paintable(None::<&gdk::Paintable>);
+        }
+    }
+}
+
+fn grid_row_picture(ui: &Ui, page_number: PageNumber) -> Option<&Picture> {
+    let doc = ui.document_canvas.as_ref()?;
+    if doc.grid_row_page(0) == Some(page_number) {
+        Some(&ui.image_bottom_left)
+    } else if doc.grid_row_page(1) == Some(page_number) {
+        Some(&ui.image_bottom_right)
+    } else {
+        None
+    }
+}
+
+fn is_zoomed(ui: &Ui) -> bool {
+    ui.zoom_factor > 1.0
+}
+
+fn is_fit_to_width(ui: &Ui) -> bool {
+    ui.document_canvas
+        .as_ref()
+        .map(|doc| doc.render_settings.fit_mode == FitMode::Width)
+        .unwrap_or(false)
+}
+
+// The area available for pages, including the zoom
+fn render_area(ui: &Ui) -> RenderSize {
+    RenderSize {
+        width: (ui.scroller.width() as f64 * ui.zoom_factor) as i32,
+        height: (ui.scroller.height() as f64 * ui.zoom_factor) as i32,
+    }
+}
+
+fn apply_zoom(ui: &Ui) {
+    if is_zoomed(ui) {
+        let area = render_area(ui);
+        ui.pages_box.set_size_request(area.width, area.height);
+    } else {
+        ui.pages_box.set_size_request(-1, -1);
+    }
+    apply_single_page_width(ui);
+}
+
+// Without the width of its share, a lone page would grow to the height of the area
+fn apply_single_page_width(ui: &Ui) {
+    let centered = ui.center_single_pages
+        && ui
+            .document_canvas
+            .as_ref()
+            .is_some_and(|doc| doc.is_alone_in_spread());
+    let width = if centered {
+        (render_area(ui).width - ui.image_container.spacing()) / 2
+    } else {
+        -1
+    };
+    ui.image_left.set_size_request(width, -1);
+}
+
+fn process_resize(ui: Rc<RefCell<Ui>>) {
+    let serial = ui.borrow().resize_serial.get().wrapping_add(1);
+    ui.borrow().resize_serial.set(serial);
+    glib::timeout_add_local_once(
+        RESIZE_DEBOUNCE,
+        clone!(@weak ui => move || {
+            if ui.borrow().resize_serial.get() != serial {
+                return;
+            }
+            if ui.borrow().continuous {
+                populate_continuous_pages(&mut ui.borrow_mut());
+            } else {
+                render_resized_pages(&ui.borrow());
+            }
+        }),
+    );
+}
+
+fn set_continuous(ui: &mut Ui, continuous: bool) {
+    if ui.continuous == continuous {
+        return;
+    }
+    ui.continuous = continuous;
+    if continuous {
+        reset_zoom(ui);
+        if let Some(doc) = ui.document_canvas.as_mut() {
+            ui.fit_mode_before_continuous = Some(doc.render_settings.fit_mode);
+            doc.update_render_settings(|settings| settings.fit_mode = FitMode::Width);
+        }
+        ui.layout_stack.set_visible_child_name(LAYOUT_CONTINUOUS);
+        populate_continuous_pages(ui);
+    } else {
+        if let (Some(doc), Some(fit_mode)) = (
+            ui.document_canvas.as_mut(),
+            ui.fit_mode_before_continuous.take(),
+        ) {
+            doc.update_render_settings(|settings| settings.fit_mode = fit_mode);
+        }
+        ui.layout_stack.set_visible_child_name(LAYOUT_PAGES);
+        // The pictures hold on to their textures, which the cache may already have evicted
+        while let Some(child) = ui.continuous_pages.first_child() {
+            ui.continuous_pages.remove(&child);
+        }
+        ui.continuous_pictures.clear();
+    }
+    update_page_status(ui);
+}
+
+// Every page gets the full width, until the textures arrive their size comes from the page
+fn populate_continuous_pages(ui: &mut Ui) {
+    while let Some(child) = ui.continuous_pages.first_child() {
+        ui.continuous_pages.remove(&child);
+    }
+    ui.continuous_pictures.clear();
+    let (Some(document), Some(doc)) = (ui.document.as_ref(), ui.document_canvas.as_ref()) else {
+        return;
+    };
+    let width = ui.app_wrapper.width() as f64;
+    for page_number in 0..document.n_pages() {
+        let rotation = doc.rotations.get(&page_number).copied().unwrap_or_default();
+        let (page_width, page_height) = document
+            .page(page_number)
+            .map(|page| {
+                let page = draw::rotate_page(page, rotation);
+                match doc.crops.get(&page_number) {
+                    Some(crop) => {
+                        let region = crop.region(page.as_ref());
+                        (region.width, region.height)
+                    }
+                    None => page.size(),
+                }
+            })
+            .unwrap_or((1.0, 1.0));
+        let picture = Picture::builder()
+            .width_request(width as i32)
+            .height_request((width * page_height / page_width) as i32)
+            .build();
+        ui.continuous_pages.append(&picture);
+        ui.continuous_pictures.push(picture);
+    }
+    ui.pending_continuous_scroll = Some(doc.current_page_number);
+}
+
+fn continuous_page_top(ui: &Ui, page_number: PageNumber) -> Option<f64> {
+    let bounds = ui
+        .continuous_pictures
+        .get(page_number)?
+        .compute_bounds(&ui.continuous_pages)?;
+    Some(bounds.y() as f64)
+}
+
+// Takes the topmost visible page as the current one and requests the pages around it
+fn update_continuous_view(ui: &mut Ui) {
+    if !ui.continuous {
+        return;
+    }
+    let adjustment = ui.continuous_scroller.vadjustment();
+    let top = adjustment.value() as f32;
+    let bottom = (adjustment.value() + adjustment.page_size()) as f32;
+    let visible_page_numbers: Vec<PageNumber> = ui
+        .continuous_pictures
+        .iter()
+        .enumerate()
+        .filter(|(_, picture)| {
+            picture
+                .compute_bounds(&ui.continuous_pages)
+                .is_some_and(|bounds| bounds.y() + bounds.height() > top && bounds.y() < bottom)
+        })
+        .map(|(page_number, _)| page_number)
+        .collect();
+    let (Some(&first_visible), Some(&last_visible)) =
+        (visible_page_numbers.first(), visible_page_numbers.last())
+    else {
+        return;
+    };
+    let Some(doc) = ui.document_canvas.as_mut() else {
+        return;
+    };
+    if doc.current_page_number != first_visible {
+        doc.current_page_number = first_visible;
+        doc.save_position();
+    }
+
+    let first_kept = first_visible.saturating_sub(CONTINUOUS_MARGIN_PAGES);
+    let last_kept = (last_visible + CONTINUOUS_MARGIN_PAGES).min(ui.continuous_pictures.len() - 1);
+    for (page_number, picture) in ui.continuous_pictures.iter().enumerate() {
+        if !(first_kept..=last_kept).contains(&page_number) {
+            picture.set_paintable(None::<&gdk::Paintable>);
+        } else if picture.paintable().is_none() {
+            doc.request_page(page_number);
+        }
+    }
+    let kept_page_numbers: Vec<PageNumber> = (first_kept..=last_kept).collect();
+    let size = RenderSize {
+        width: ui.continuous_pages.width(),
+        height: ui.continuous_pictures[first_visible].height(),
+    };
+    doc.cache_pages_with_size(&kept_page_numbers, size);
+    update_page_status(ui);
+}
+
+fn process_continuous_layout(ui: Rc<RefCell<Ui>>) {
+    let page_number = ui.borrow_mut().pending_continuous_scroll.take();
+    if let Some(top) =
+        page_number.and_then(|page_number| continuous_page_top(&ui.borrow(), page_number))
+    {
+        let adjustment = ui.borrow().continuous_scroller.vadjustment();
+        adjustment.set_value(top);
+    }
+    update_continuous_view(&mut ui.borrow_mut());
+}
+
+fn scroll_to_continuous_page(ui: &Ui, page_number: PageNumber) {
+    let Some(top) = continuous_page_top(ui, page_number) else {
+        return;
+    };
+    // Setting the value right away would update the view while the ui is borrowed
+    let adjustment = ui.continuous_scroller.vadjustment();
+    glib::idle_add_local_once(move || adjustment.set_value(top));
+}
+
+// Visible pages that are too small for the new size get upgraded by the cache
+fn render_resized_pages(ui: &Ui) {
+    if let Some(doc) = ui.document_canvas.as_ref() {
+        let area = render_area(ui);
+        debug!("Rendering pages for the new size {:?}", area);
+        apply_single_page_width(ui);
+        doc.cache_initial_pages(area);
+        doc.cache_surrounding_pages(area);
+    }
+}
+
+// Pages rendered for the previous scale would look soft or oversized,
+// unless the render scale was set by the reader
+fn process_scale_factor_change(ui: &mut Ui, scale_factor: i32) {
+    if ui.render_scale.is_some() {
+        return;
+    }
+    let render_scale = scale_factor as f64;
+    match ui.document_canvas.as_mut() {
+        Some(doc) if doc.render_scale != render_scale => doc.set_render_scale(render_scale),
+        _ => return,
+    }
+    if ui.continuous {
+        populate_continuous_pages(ui);
+    } else {
+        render_resized_pages(ui);
+    }
+}
+
+fn render_zoomed_pages(ui: &Ui) {
+    if let Some(doc) = ui.document_canvas.as_ref() {
+        // The container is not yet reallocated, so the zoomed area is computed directly
+        doc.cache_surrounding_pages(render_area(ui));
+    }
+}
+
+fn reset_zoom(ui: &mut Ui) {
+    ui.zoom_factor = 1.0;
+    apply_zoom(ui);
+}
+
+fn process_zoom_begin(ui: &mut Ui) {
+    ui.zoom_at_gesture_begin = ui.zoom_factor;
+}
+
+fn process_zoom_change(ui: &mut Ui, scale: f64) {
+    ui.zoom_factor = f64::clamp(ui.zoom_at_gesture_begin * scale, 1.0, MAX_ZOOM_FACTOR);
+    apply_zoom(ui);
+}
+
+// The area inside the picture that is covered by the page, as x, y, width and height
+fn page_area(picture: &Picture, relative_to: &DrawingArea) -> Option<(f64, f64, f64, f64)> {
+    if !picture.is_visible() || picture.opacity() == 0.0 {
+        return None;
+    }
+    let bounds = picture.compute_bounds(relative_to)?;
+    let (x, y) = (bounds.x() as f64, bounds.y() as f64);
+    let (width, height) = (bounds.width() as f64, bounds.height() as f64);
+    let aspect_ratio = picture.paintable()?.intrinsic_aspect_ratio();
+    if aspect_ratio <= 0.0 {
+        return Some((x, y, width, height));
+    }
+    // The page keeps its aspect ratio and is centered in the picture
+    let page_width = f64::min(width, height * aspect_ratio);
+    let page_height = page_width / aspect_ratio;
+    Some((
+        x + (width - page_width) / 2.0,
+        y + (height - page_height) / 2.0,
+        page_width,
+        page_height,
+    ))
+}
+
+// The visible pages with their area on the annotation overlay
+fn visible_page_areas(ui: &Ui) -> Vec<(PageNumber, (f64, f64, f64, f64))> {
+    let Some(doc) = ui.document_canvas.as_ref() else {
+        return Vec::new();
+    };
+    let mut areas = Vec::new();
+    if let Some(area) = page_area(&ui.image_left, &ui.annotation_area) {
+        areas.push((doc.current_page_number, area));
+    }
+    if doc.is_right_page(doc.current_page_number + 1) {
+        if let Some(area) = page_area(&ui.image_right, &ui.annotation_area) {
+            areas.push((doc.current_page_number + 1, area));
+        }
+    }
+    for (index, picture) in [&ui.image_bottom_left, &ui.image_bottom_right]
+        .into_iter()
+        .enumerate()
+    {
+        if let (Some(page_number), Some(area)) = (
+            doc.grid_row_page(index),
+            page_area(picture, &ui.annotation_area),
+        ) {
+            areas.push((page_number, area));
+        }
+    }
+    areas
+}
+
+fn draw_annotations(ui: &Ui, context: &cairo::Context) {
+    let Some(doc) = ui.document_canvas.as_ref() else {
+        return;
+    };
+    context.set_source_rgba(0.8, 0.1, 0.1, 0.9);
+    context.set_line_width(2.5);
+    context.set_line_cap(cairo::LineCap::Round);
+    context.set_line_join(cairo::LineJoin::Round);
+    for (page_number, (x, y, width, height)) in visible_page_areas(ui) {
+        let current_stroke = ui
+            .current_stroke
+            .iter()
+            .filter(|(stroke_page_number, _)| *stroke_page_number == page_number)
+            .map(|(_, stroke)| stroke);
+        let strokes = doc
+            .annotations
+            .get(&page_number)
+            .into_iter()
+            .flatten()
+            .chain(current_stroke);
+        for stroke in strokes {
+            for (index, &(point_x, point_y)) in stroke.points.iter().enumerate() {
+                let (point_x, point_y) = (x + point_x * width, y + point_y * height);
+                if index == 0 {
+                    context.move_to(point_x, point_y);
+                } else {
+                    context.line_to(point_x, point_y);
+                }
+            }
+            if let Err(e) = context.stroke() {
+                error!("Failed drawing annotation: {}", e);
+            }
+        }
+    }
+    draw_crop_selection(ui, context);
+}
+
+fn draw_crop_selection(ui: &Ui, context: &cairo::Context) {
+    let Some((page_number, start, end)) = ui.crop_selection else {
+        return;
+    };
+    let Some((_, (x, y, width, height))) = visible_page_areas(ui)
+        .into_iter()
+        .find(|(visible_page_number, _)| *visible_page_number == page_number)
+    else {
+        return;
+    };
+    context.rectangle(
+        x + start.0.min(end.0) * width,
+        y + start.1.min(end.1) * height,
+        (end.0 - start.0).abs() * width,
+        (end.1 - start.1).abs() * height,
+    );
+    context.set_source_rgba(0.2, 0.4, 1.0, 0.2);
+    if let Err(e) = context.fill_preserve() {
+        error!("Failed drawing crop selection: {}", e);
+    }
+    context.set_source_rgba(0.2, 0.4, 1.0, 0.9);
+    context.set_line_width(2.0);
+    if let Err(e) = context.stroke() {
+        error!("Failed drawing crop selection: {}", e);
+    }
+}
+
+fn set_annotating(ui: &mut Ui, annotating: bool) {
+    ui.annotating = annotating;
+    ui.current_stroke = None;
+    ui.annotation_area.set_can_target(annotating || ui.cropping);
+}
+
+fn set_cropping(ui: &mut Ui, cropping: bool) {
+    ui.cropping = cropping;
+    ui.crop_selection = None;
+    ui.annotation_area.set_can_target(cropping || ui.annotating);
+    if let Some(doc) = ui.document_canvas.as_ref() {
+        doc.show_whole_pages(cropping);
+    }
+    ui.annotation_area.queue_draw();
+    update_page_status(ui);
+}
+
+// The page below the point, with the point relative to that page
+fn page_point(ui: &Ui, x: f64, y: f64) -> Option<(PageNumber, (f64, f64))> {
+    visible_page_areas(ui)
+        .into_iter()
+        .find_map(|(page_number, (area_x, area_y, width, height))| {
+            let (point_x, point_y) = ((x - area_x) / width, (y - area_y) / height);
+            let inside = (0.0..=1.0).contains(&point_x) && (0.0..=1.0).contains(&point_y);
+            inside.then_some((page_number, (point_x, point_y)))
+        })
+}
+
+fn process_crop_begin(ui: &mut Ui, x: f64, y: f64) {
+    ui.stroke_start = (x, y);
+    ui.crop_selection =
+        page_point(ui, x, y).map(|(page_number, point)| (page_number, point, point));
+}
+
+fn process_crop_update(ui: &mut Ui, offset_x: f64, offset_y: f64) {
+    let Some((page_number, start, _)) = ui.crop_selection else {
+        return;
+    };
+    let Some((_, (area_x, area_y, width, height))) = visible_page_areas(ui)
+        .into_iter()
+        .find(|(visible_page_number, _)| *visible_page_number == page_number)
+    else {
+        return;
+    };
+    let (start_x, start_y) = ui.stroke_start;
+    let end = (
+        ((start_x + offset_x - area_x) / width).clamp(0.0, 1.0),
+        ((start_y + offset_y - area_y) / height).clamp(0.0, 1.0),
+    );
+    ui.crop_selection = Some((page_number, start, end));
+    ui.annotation_area.queue_draw();
+}
+
+// A click without dragging shows the whole page again
+fn process_crop_end(ui: &mut Ui) {
+    let Some((page_number, start, end)) = ui.crop_selection.take() else {
+        return;
+    };
+    let crop = Crop::from_corners(start, end);
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.set_crop(page_number, crop);
+    }
+    ui.notify(if crop.is_some() {
+        "Page cropped"
+    } else {
+        "Crop removed"
+    });
+    // Leaving the mode needs the ui, which is borrowed here
+    let crop_button = ui.crop_button.clone();
+    glib::idle_add_local_once(move || crop_button.set_active(false));
+}
+
+fn process_stroke_begin(ui: &mut Ui, x: f64, y: f64) {
+    ui.stroke_start = (x, y);
+    ui.current_stroke = page_point(ui, x, y).map(|(page_number, point)| {
+        (
+            page_number,
+            Stroke {
+                points: vec![point],
+            },
+        )
+    });
+}
+
+fn process_stroke_update(ui: &mut Ui, offset_x: f64, offset_y: f64) {
+    let Some(page_number) = ui
+        .current_stroke
+        .as_ref()
+        .map(|(page_number, _)| *page_number)
+    else {
+        return;
+    };
+    let Some((_, (area_x, area_y, width, height))) = visible_page_areas(ui)
+        .into_iter()
+        .find(|(visible_page_number, _)| *visible_page_number == page_number)
+    else {
+        return;
+    };
+    let (start_x, start_y) = ui.stroke_start;
+    let point = (
+        ((start_x + offset_x - area_x) / width).clamp(0.0, 1.0),
+        ((start_y + offset_y - area_y) / height).clamp(0.0, 1.0),
+    );
+    if let Some((_, stroke)) = ui.current_stroke.as_mut() {
+        stroke.points.push(point);
+    }
+    ui.annotation_area.queue_draw();
+}
+
+fn process_stroke_end(ui: &mut Ui) {
+    if let (Some((page_number, stroke)), Some(doc)) =
+        (ui.current_stroke.take(), ui.document_canvas.as_mut())
+    {
+        doc.add_stroke(page_number, stroke);
+    }
+    ui.annotation_area.queue_draw();
+}
+
+fn process_drag_begin(ui: &mut Ui) {
+    ui.pending_scroll = (None, None);
+    ui.scroll_at_drag_begin = (
+        ui.scroller.hadjustment().value(),
+        ui.scroller.vadjustment().value(),
+    );
+}
+
+fn process_drag_update(ui: &Ui, offset_x: f64, offset_y: f64) {
+    if ui.annotating || (!is_zoomed(ui) && !is_fit_to_width(ui)) {
+        return;
+    }
+    let (start_x, start_y) = ui.scroll_at_drag_begin;
+    ui.scroller.hadjustment().set_value(start_x - offset_x);
+    ui.scroller.vadjustment().set_value(start_y - offset_y);
+}
+
+fn remember_page_view(ui: &mut Ui, page_number: PageNumber) {
+    if ui.continuous {
+        return;
+    }
+    let page_view = is_zoomed(ui).then(|| PageView {
+        zoom: ui.zoom_factor,
+        scroll: (
+            ui.scroller.hadjustment().value(),
+            ui.scroller.vadjustment().value(),
+        ),
+    });
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.set_page_view(page_number, page_view);
+    }
+}
+
+// Pages without a view of their own keep the current zoom
+fn restore_page_view(ui: &mut Ui) {
+    let Some(page_view) = ui
+        .document_canvas
+        .as_ref()
+        .and_then(DocumentCanvas::page_view)
+    else {
+        return;
+    };
+    ui.zoom_factor = page_view.zoom.clamp(1.0, MAX_ZOOM_FACTOR);
+    apply_zoom(ui);
+    let (x, y) = page_view.scroll;
+    ui.pending_scroll = (Some(x), Some(y));
+    apply_pending_scroll(ui);
+}
+
+fn apply_pending_scroll(ui: &mut Ui) {
+    let (x, y) = ui.pending_scroll;
+    ui.pending_scroll = (
+        scroll_if_possible(&ui.scroller.hadjustment(), x),
+        scroll_if_possible(&ui.scroller.vadjustment(), y),
+    );
+}
+
+// Returns the value again while the adjustment can't reach it yet
+fn scroll_if_possible(adjustment: &gtk::Adjustment, value: Option<f64>) -> Option<f64> {
+    let value = value?;
+    if adjustment.upper() - adjustment.page_size() < value {
+        return Some(value);
+    }
+    adjustment.set_value(value);
+    None
+}
+
+// Opens the spread or grid the page belongs to, so the pages stay paired as when turning
+fn jump_to_page(ui: &mut Ui, page_number: PageNumber) {
+    navigate(ui, |doc| doc.go_to_page(page_number));
+}
+
+pub fn show_goto_dialog(ui: Rc<RefCell<Ui>>) {
+    let Some(num_pages) = ui
+        .borrow()
+        .document_canvas
+        .as_ref()
+        .and_then(|doc| doc.num_pages)
+    else {
+        return;
+    };
+    let has_labels = ui
+        .borrow()
+        .document_canvas
+        .as_ref()
+        .is_some_and(|doc| !doc.page_labels.is_empty());
+
+    let dialog = Dialog::builder()
+        .title("Go to page")
+        .transient_for(&ui.borrow().window)
+        .modal(true)
+        .build();
+    dialog.add_button("_Cancel", ResponseType::Cancel);
+    dialog.add_button("_Go", ResponseType::Accept);
+    dialog.set_default_response(ResponseType::Accept);
+
+    let entry = Entry::builder()
+        .input_purpose(if has_labels {
+            gtk::InputPurpose::FreeForm
+        } else {
+            gtk::InputPurpose::Digits
+        })
+        .placeholder_text(if has_labels {
+            format!("Page label or 1 - {}", num_pages)
+        } else {
+            format!("1 - {}", num_pages)
+        })
+        .activates_default(true)
+        .margin_start(12)
+        .margin_end(12)
+        .margin_top(12)
+        .build();
+    let warning = Label::builder().visible(false).margin_top(6).build();
+    warning.add_css_class("warning");
+    dialog.content_area().append(&entry);
+    dialog.content_area().append(&warning);
+
+    dialog.connect_response(clone!(@weak ui => move |dialog, response| {
+        if response != ResponseType::Accept {
+            dialog.destroy();
+            return;
+        }
+        let text = entry.text();
+        let text = text.trim();
+        let Some(page_number) = ui.borrow().document_canvas.as_ref().and_then(|doc| doc.find_page(text)) else {
+            warning.set_label("Please enter a page number");
+            warning.set_visible(true);
+            return;
+        };
+        if page_number >= num_pages {
+            // Let the user confirm the clamped page instead of failing
+            entry.set_text(&num_pages.to_string());
+            warning.set_label(&format!("There is no page {}, the last page is {}", text, num_pages));
+            warning.set_visible(true);
+            return;
+        }
+        navigate(&mut ui.borrow_mut(), |doc| doc.go_to_page(page_number));
+        let label = ui.borrow().document_canvas.as_ref().unwrap().page_label(page_number);
+        ui.borrow().notify(&format!("Jumped to page {}", label));
+        dialog.destroy();
+    }));
+    dialog.present();
+}
+
+fn navigate(ui: &mut Ui, navigation: impl FnOnce(&mut DocumentCanvas)) {
+    let Some(doc) = ui.document_canvas.as_mut() else {
+        return;
+    };
+    let previous_page_number = doc.current_page_number;
+    navigation(doc);
+    if doc.current_page_number != previous_page_number {
+        stop_auto_turn(ui);
+        if ui.continuous {
+            let page_number = ui.document_canvas.as_ref().unwrap().current_page_number;
+            scroll_to_continuous_page(ui, page_number);
+        }
+    }
+    process_page_change(ui, previous_page_number);
+    update_page_status(ui);
+}
+
+fn process_peek_begin(ui: &mut Ui) -> bool {
+    if ui.annotating
+        || ui.cropping
+        || ui.continuous
+        || is_zoomed(ui)
+        || ui.blank_overlay.is_visible()
+    {
+        return false;
+    }
+    let area = render_area(ui);
+    ui.peeking = ui
+        .document_canvas
+        .as_ref()
+        .is_some_and(|doc| doc.request_to_draw_next_pages(area));
+    ui.peeking
+}
+
+fn process_peek_end(ui: &mut Ui) {
+    if !std::mem::take(&mut ui.peeking) {
+        return;
+    }
+    if let Some(doc) = ui.document_canvas.as_ref() {
+        doc.request_to_draw_pages(render_area(ui));
+    }
+}
+
+fn process_swipe(ui: &mut Ui, velocity_x: f64, velocity_y: f64) {
+    if ui.annotating || ui.continuous || is_zoomed(ui) || ui.blank_overlay.is_visible() {
+        return;
+    }
+    // Slow or mostly vertical movements are rather accidental touches
+    if velocity_x.abs() < SWIPE_VELOCITY_THRESHOLD || velocity_x.abs() < velocity_y.abs() {
+        return;
+    }
+    ui.last_touch_time = Some(Instant::now());
+    let right_to_left = ui
+        .document_canvas
+        .as_ref()
+        .is_some_and(|doc| doc.right_to_left);
+    // Pushing the pages away from the side of the next page turns forward
+    let forward = (velocity_x < 0.0) != right_to_left;
+    navigate(ui, |doc| {
+        if forward {
+            doc.increase_page_number()
+        } else {
+            doc.decrease_page_number()
+        }
+    });
+}
+
+// Returns whether the wheel turned pages, otherwise the scrolled window pans the pages
+fn process_scroll(ui: &mut Ui, dx: f64, dy: f64) -> glib::Propagation {
+    if ui.document_canvas.is_none()
+        || ui.annotating
+        || ui.continuous
+        || is_zoomed(ui)
+        || is_fit_to_width(ui)
+        || ui.blank_overlay.is_visible()
+    {
+        return glib::Propagation::Proceed;
+    }
+    let right_to_left = ui
+        .document_canvas
+        .as_ref()
+        .is_some_and(|doc| doc.right_to_left);
+    // Scrolling horizontally moves towards the side of the next page
+    let forward = if dy != 0.0 {
+        dy > 0.0
+    } else {
+        (dx > 0.0) != right_to_left
+    };
+    navigate(ui, |doc| {
+        if forward {
+            doc.increase_page_number()
+        } else {
+            doc.decrease_page_number()
+        }
+    });
+    glib::Propagation::Stop
+}
+
+fn update_auto_turn_button(ui: &Ui) {
+    if ui.auto_turn_source.is_some() {
+        ui.auto_turn_button
+            .set_icon_name("media-playback-pause-symbolic");
+    } else {
+        ui.auto_turn_button
+            .set_icon_name("media-playback-start-symbolic");
+    }
+}
+
+fn toggle_auto_turn(ui: Rc<RefCell<Ui>>) {
+    if ui.borrow().auto_turn_source.is_some() {
+        stop_auto_turn(&mut ui.borrow_mut());
+    } else {
+        start_auto_turn(ui);
+    }
+}
+
+fn start_auto_turn(ui: Rc<RefCell<Ui>>) {
+    if ui.borrow().document_canvas.is_none() || ui.borrow().auto_turn_source.is_some() {
+        return;
+    }
+    let interval = ui.borrow().auto_turn_interval;
+    let source = glib::timeout_add_seconds_local(
+        interval,
+        clone!(@weak ui => @default-return glib::ControlFlow::Break, move || {
+            auto_turn_page(&mut ui.borrow_mut())
+        }),
+    );
+    ui.borrow_mut().auto_turn_source = Some(source);
+    update_auto_turn_button(&ui.borrow());
+}
+
+fn stop_auto_turn(ui: &mut Ui) {
+    if let Some(source) = ui.auto_turn_source.take() {
+        source.remove();
+    }
+    update_auto_turn_button(ui);
+}
+
+// Stops by itself once the last page is shown
+fn auto_turn_page(ui: &mut Ui) -> glib::ControlFlow {
+    let Some(doc) = ui.document_canvas.as_mut() else {
+        ui.auto_turn_source = None;
+        update_auto_turn_button(ui);
+        return glib::ControlFlow::Break;
+    };
+    let previous_page_number = doc.current_page_number;
+    if doc.is_at_loop_end() {
+        doc.restart_loop();
+    } else {
+        doc.increase_page_number();
+    }
+    let reached_end = doc.active_loop().is_none() && doc.is_at_last_page();
+    process_page_change(ui, previous_page_number);
+    update_page_status(ui);
+
+    if reached_end {
+        // Returning Break removes the source, so it must not be removed again
+        ui.auto_turn_source = None;
+        update_auto_turn_button(ui);
+        return glib::ControlFlow::Break;
+    }
+    glib::ControlFlow::Continue
+}
+
+fn set_auto_turn_interval(ui: Rc<RefCell<Ui>>, interval: u32) {
+    ui.borrow_mut().auto_turn_interval = interval;
+    // Restart a running timer, so the new interval applies right away
+    if ui.borrow().auto_turn_source.is_some() {
+        stop_auto_turn(&mut ui.borrow_mut());
+        start_auto_turn(ui);
+    }
+}
+
+fn update_metronome_button(ui: &Ui) {
+    if ui.metronome.is_some() {
+        ui.metronome_button
+            .set_icon_name("media-playback-stop-symbolic");
+        ui.metronome_button
+            .set_tooltip_text(Some("Stop the metronome"));
+    } else {
+        ui.metronome_button.set_icon_name("alarm-symbolic");
+        ui.metronome_button
+            .set_tooltip_text(Some("Start the metronome"));
+    }
+}
+
+fn toggle_metronome(ui: Rc<RefCell<Ui>>) {
+    if ui.borrow().metronome.is_some() {
+        stop_metronome(&mut ui.borrow_mut());
+    } else {
+        start_metronome(ui);
+    }
+}
+
+fn start_metronome(ui: Rc<RefCell<Ui>>) {
+    let (metronome, beats) = Metronome::start(
+        ui.borrow().metronome_bpm,
+        ui.borrow().metronome_beats_per_bar,
+    );
+    ui.borrow_mut().metronome = Some(metronome);
+    ui.borrow().metronome_indicator.set_visible(true);
+    update_metronome_button(&ui.borrow());
+
+    let indicator = ui.borrow().metronome_indicator.clone();
+    glib::spawn_future_local(async move {
+        // Ends once the metronome is dropped and its thread stops sending
+        while let Ok(beat) = beats.recv().await {
+            flash_beat(&indicator, beat);
+        }
+    });
+}
+
+fn stop_metronome(ui: &mut Ui) {
+    ui.metronome = None;
+    ui.metronome_indicator.set_visible(false);
+    update_metronome_button(ui);
+}
+
+// A running metronome has to start over to pick up a new tempo or time signature
+fn restart_metronome(ui: Rc<RefCell<Ui>>) {
+    if ui.borrow().metronome.is_some() {
+        stop_metronome(&mut ui.borrow_mut());
+        start_metronome(ui);
+    }
+}
+
+fn flash_beat(indicator: &Label, beat: Beat) {
+    indicator.set_label(&(beat + 1).to_string());
+    indicator.set_opacity(1.0);
+    glib::timeout_add_local_once(
+        BEAT_FLASH_DURATION,
+        clone!(@weak indicator => move || indicator.set_opacity(DIMMED_BEAT_OPACITY)),
+    );
+}
+
+pub fn connect_pedal(ui: Rc<RefCell<Ui>>, device_name: &str, mapping: PedalMapping) {
+    let (connection, actions) = match pedal::connect(device_name, mapping) {
+        Ok(pedal) => pedal,
+        Err(e) => {
+            warn!("Continuing without pedal: {:#}", e);
+            return;
+        }
+    };
+    glib::spawn_future_local(clone!(@weak ui => async move {
+        // Keep the connection open as long as actions are processed
+        let _connection = connection;
+        while let Ok(action) = actions.recv().await {
+            match action {
+                PedalAction::Next => navigate(&mut ui.borrow_mut(), DocumentCanvas::increase_page_number),
+                PedalAction::Previous => navigate(&mut ui.borrow_mut(), DocumentCanvas::decrease_page_number),
+            }
+        }
+    }));
+}
+
+pub fn connect_remote(ui: Rc<RefCell<Ui>>, address: SocketAddr) {
+    let commands = match remote::listen(address) {
+        Ok(commands) => commands,
+        Err(e) => {
+            warn!("Continuing without remote control: {:#}", e);
+            return;
+        }
+    };
+    glib::spawn_future_local(clone!(@weak ui => async move {
+        while let Ok(command) = commands.recv().await {
+            match command {
+                RemoteCommand::Next => navigate(&mut ui.borrow_mut(), DocumentCanvas::increase_page_number),
+                RemoteCommand::Previous => navigate(&mut ui.borrow_mut(), DocumentCanvas::decrease_page_number),
+                RemoteCommand::GoTo(page_number) => navigate(&mut ui.borrow_mut(), |doc| doc.go_to_page(page_number)),
+            }
+        }
+    }));
+}
+
+fn toggle_click_zones_overlay(ui: &Ui) {
+    ui.click_zones_overlay
+        .set_visible(!ui.click_zones_overlay.is_visible());
+}
+
+fn build_black_area() -> DrawingArea {
+    let area = DrawingArea::builder()
+        .hexpand(true)
+        .vexpand(true)
+        .visible(false)
+        .build();
+    area.set_draw_func(|_, context, _, _| {
+        context.set_source_rgb(0.0, 0.0, 0.0);
+        if let Err(e) = context.paint() {
+            error!("Failed drawing black area: {}", e);
+        }
+    });
+    area
+}
+
+fn set_dim(ui: &Ui, dim: f64) {
+    let dim = dim.clamp(0.0, MAX_DIM);
+    ui.dim_overlay.set_opacity(dim);
+    ui.dim_overlay.set_visible(dim > 0.0);
+}
+
+// Moves the slider, which then applies the level
+fn change_dim(ui: &Ui, change: f64) {
+    ui.dim_scale.set_value(ui.dim_scale.value() + change);
+}
+
+// The pages stay as they are below, so showing them again needs no re-render
+fn toggle_blank_screen(ui: &Ui) {
+    ui.blank_overlay.set_visible(!ui.blank_overlay.is_visible());
+}
+
+fn update_loop(ui: &mut Ui, update: impl FnOnce(&mut DocumentCanvas)) {
+    let Some(doc) = ui.document_canvas.as_mut() else {
+        return;
+    };
+    update(doc);
+    let message = match (doc.loop_start, doc.loop_end, doc.active_loop()) {
+        (_, _, Some((start, end))) => format!("Looping pages {}-{}", start + 1, end + 1),
+        (None, None, None) => "Loop cleared".to_string(),
+        (Some(start), None, None) => format!("Loop starts at page {}", start + 1),
+        (None, Some(end), None) => format!("Loop ends at page {}", end + 1),
+        (Some(_), Some(_), None) => "The loop has to end after its start".to_string(),
+    };
+    update_page_status(ui);
+    ui.notify(&message);
+}
+
+fn toggle_bookmark(ui: &mut Ui) {
+    navigate(ui, DocumentCanvas::toggle_bookmark);
+    match ui
+        .document_canvas
+        .as_ref()
+        .map(DocumentCanvas::is_bookmarked)
+    {
+        Some(true) => ui.notify("Bookmark added"),
+        Some(false) => ui.notify("Bookmark removed"),
+        None => {}
+    }
+}
+
+// Points a page moves per arrow key press, ten times as much with shift
+const NUDGE_STEP: f64 = 1.0;
+
+// The right page is only adjusted while it is shown
+fn adjusted_page(ui: &Ui) -> Option<PageNumber> {
+    let doc = ui.document_canvas.as_ref()?;
+    if ui.adjusting_right_page && doc.is_right_page(doc.current_page_number + 1) {
+        Some(doc.current_page_number + 1)
+    } else {
+        Some(doc.current_page_number)
+    }
+}
+
+fn notify_adjusted_page(ui: &Ui) {
+    let (Some(doc), Some(page_number)) = (ui.document_canvas.as_ref(), adjusted_page(ui)) else {
+        return;
+    };
+    ui.notify(&format!(
+        "Adjusting page {}, Tab switches pages, 0 resets, Escape finishes",
+        doc.page_label(page_number)
+    ));
+}
+
+fn toggle_adjusting(ui: &mut Ui) {
+    if ui.document_canvas.is_none() {
+        return;
+    }
+    ui.adjusting = !ui.adjusting;
+    ui.adjusting_right_page = false;
+    if ui.adjusting {
+        notify_adjusted_page(ui);
+    } else {
+        ui.notify("Adjusting finished");
+    }
+}
+
+fn nudge_adjusted_page(ui: &mut Ui, change: Option<(f64, f64)>) {
+    let Some(page_number) = adjusted_page(ui) else {
+        return;
+    };
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        doc.nudge_page(page_number, change);
+    }
+    update_page_status(ui);
+}
+
+fn process_adjust_key_press(
+    ui: &mut Ui,
+    key: gdk::Key,
+    modifiers: gdk::ModifierType,
+) -> glib::Propagation {
+    let step = if modifiers.contains(gdk::ModifierType::SHIFT_MASK) {
+        NUDGE_STEP * 10.0
+    } else {
+        NUDGE_STEP
+    };
+    match key {
+        gdk::Key::Left => nudge_adjusted_page(ui, Some((-step, 0.0))),
+        gdk::Key::Right => nudge_adjusted_page(ui, Some((step, 0.0))),
+        gdk::Key::Up => nudge_adjusted_page(ui, Some((0.0, -step))),
+        gdk::Key::Down => nudge_adjusted_page(ui, Some((0.0, step))),
+        gdk::Key::_0 | gdk::Key::Delete => {
+            nudge_adjusted_page(ui, None);
+            ui.notify("Page position reset");
+        }
+        gdk::Key::Tab | gdk::Key::ISO_Left_Tab => {
+            ui.adjusting_right_page = !ui.adjusting_right_page;
+            notify_adjusted_page(ui);
+        }
+        gdk::Key::Escape | gdk::Key::Return | gdk::Key::N => toggle_adjusting(ui),
+        _ => return glib::Propagation::Proceed,
+    }
+    glib::Propagation::Stop
+}
+
+fn process_key_press(
+    ui: Rc<RefCell<Ui>>,
+    key: gdk::Key,
+    modifiers: gdk::ModifierType,
+) -> glib::Propagation {
+    let control = modifiers.contains(gdk::ModifierType::CONTROL_MASK);
+    if ui.borrow().adjusting {
+        if let glib::Propagation::Stop =
+            process_adjust_key_press(&mut ui.borrow_mut(), key, modifiers)
+        {
+            return glib::Propagation::Stop;
+        }
+    }
+    match key {
+        gdk::Key::Page_Down if control => {
+            navigate(&mut ui.borrow_mut(), DocumentCanvas::next_piece)
+        }
+        gdk::Key::Page_Up if control => {
+            navigate(&mut ui.borrow_mut(), DocumentCanvas::previous_piece)
+        }
+        gdk::Key::z if control => step_through_history(
+            &mut ui.borrow_mut(),
+            DocumentCanvas::undo_navigation,
+            "No page change to undo",
+        ),
+        gdk::Key::Z | gdk::Key::y if control => step_through_history(
+            &mut ui.borrow_mut(),
+            DocumentCanvas::redo_navigation,
+            "No page change to redo",
+        ),
+        gdk::Key::f if control => toggle_search(&ui.borrow()),
+        gdk::Key::g => show_goto_dialog(ui),
+        gdk::Key::a => toggle_auto_turn(ui),
+        gdk::Key::r => toggle_right_to_left(&mut ui.borrow_mut()),
+        gdk::Key::R => navigate(&mut ui.borrow_mut(), DocumentCanvas::rotate_current_page),
+        gdk::Key::z => toggle_click_zones_overlay(&ui.borrow()),
+        gdk::Key::period => toggle_blank_screen(&ui.borrow()),
+        gdk::Key::Down => change_dim(&ui.borrow(), DIM_STEP),
+        gdk::Key::Up => change_dim(&ui.borrow(), -DIM_STEP),
+        gdk::Key::braceleft => update_loop(&mut ui.borrow_mut(), DocumentCanvas::set_loop_start),
+        gdk::Key::braceright => update_loop(&mut ui.borrow_mut(), DocumentCanvas::set_loop_end),
+        gdk::Key::bar => update_loop(&mut ui.borrow_mut(), DocumentCanvas::clear_loop),
+        gdk::Key::p => toggle_print_rendering(&mut ui.borrow_mut()),
+        gdk::Key::n => toggle_notes(&ui.borrow()),
+        gdk::Key::N => toggle_adjusting(&mut ui.borrow_mut()),
+        gdk::Key::m => toggle_mirror(&mut ui.borrow_mut()),
+        gdk::Key::t => navigate(&mut ui.borrow_mut(), DocumentCanvas::toggle_trim_margins),
+        gdk::Key::s => navigate(&mut ui.borrow_mut(), DocumentCanvas::toggle_spread_step),
+        gdk::Key::b => toggle_bookmark(&mut ui.borrow_mut()),
+        gdk::Key::o => toggle_cover_page(&mut ui.borrow_mut()),
+        gdk::Key::c => {
+            let continuous_button = ui.borrow().continuous_button.clone();
+            continuous_button.set_active(!continuous_button.is_active());
+        }
+        gdk::Key::x => {
+            let crop_button = ui.borrow().crop_button.clone();
+            crop_button.set_active(!crop_button.is_active());
+        }
+        gdk::Key::BackSpace => swap_to_previous_files(ui),
+        gdk::Key::F => open_folder_as_playlist(ui),
+        gdk::Key::F5 => reload_files(ui),
+        gdk::Key::F11 => toggle_fullscreen(&ui.borrow()),
+        gdk::Key::F12 => toggle_stats(&ui.borrow()),
+        // Escape only leaves fullscreen, so it can be pressed without thinking
+        gdk::Key::Escape if ui.borrow().window.is_fullscreen() => toggle_fullscreen(&ui.borrow()),
+        gdk::Key::bracketright => navigate(&mut ui.borrow_mut(), DocumentCanvas::next_bookmark),
+        gdk::Key::bracketleft => navigate(&mut ui.borrow_mut(), DocumentCanvas::prev_bookmark),
+        _ => return glib::Propagation::Proceed,
+    }
+    glib::Propagation::Stop
+}
+
+fn populate_thumbnails(ui: &mut Ui, document: Rc<ConcatenatedDocument>) {
+    while let Some(child) = ui.thumbnail_box.first_child() {
+        ui.thumbnail_box.remove(&child);
+    }
+    ui.thumbnail_pictures = (0..document.n_pages())
+        .map(|_| {
+            let picture = Picture::builder().height_request(THUMBNAIL_HEIGHT).build();
+            ui.thumbnail_box.append(&picture);
+            picture
+        })
+        .collect();
+    ui.thumbnail_cache = Some(ThumbnailCache::new(document));
+}
+
+// Returns false once there is nothing left to render for the visible sidebar
+fn render_next_thumbnail(ui: &mut Ui) -> bool {
+    if !ui.sidebar.reveals_child() {
+        return false;
+    }
+    let Some(thumbnail_cache) = ui.thumbnail_cache.as_mut() else {
+        return false;
+    };
+    let Some(page_number) = thumbnail_cache.next_missing_page() else {
+        return false;
+    };
+    if let Some(thumbnail) = thumbnail_cache.get_or_render(page_number) {
+        ui.thumbnail_pictures[page_number].set_paintable(Some(thumbnail.as_ref()));
+        true
+    } else {
+        false
+    }
+}
+
+// Documents without a table of contents hide the outline button
+fn populate_outline(ui: Rc<RefCell<Ui>>, outline: Vec<OutlineEntry>) {
+    ui.borrow().outline_button.set_visible(!outline.is_empty());
+    let list = Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let popover = Popover::builder()
+        .child(
+            &ScrolledWindow::builder()
+                .hscrollbar_policy(PolicyType::Never)
+                .propagate_natural_height(true)
+                .max_content_height(600)
+                .child(&list)
+                .build(),
+        )
+        .build();
+    for entry in outline {
+        let button = Button::builder()
+            .child(
+                &Label::builder()
+                    .label(entry.title.as_str())
+                    .xalign(0.0)
+                    .build(),
+            )
+            .has_frame(false)
+            .margin_start(12 * entry.depth as i32)
+            .build();
+        button.connect_clicked(clone!(@weak ui, @weak popover => move |_| {
+            popover.popdown();
+            // Entries on odd pages open the spread they are part of
+            jump_to_page(&mut ui.borrow_mut(), entry.page_number);
+        }));
+        list.append(&button);
+    }
+    ui.borrow().outline_button.set_popover(Some(&popover));
+}
+
+fn toggle_sidebar(ui: Rc<RefCell<Ui>>) {
+    let reveal = !ui.borrow().sidebar.reveals_child();
+    ui.borrow().sidebar.set_reveal_child(reveal);
+    if !reveal || ui.borrow().rendering_thumbnails {
+        return;
+    }
+
+    ui.borrow_mut().rendering_thumbnails = true;
+    glib::spawn_future_local(clone!(@weak ui => async move {
+        // Render lazily, one thumbnail per iteration to keep the ui responsive
+        loop {
+            timeout_future(Duration::from_millis(1)).await;
+            if !render_next_thumbnail(&mut ui.borrow_mut()) {
+                break;
+            }
+        }
+        ui.borrow_mut().rendering_thumbnails = false;
+    }));
+}
+
+fn process_turn_click(ui: &mut Ui, forward: bool) {
+    if ui.document_canvas.is_none()
+        || is_zoomed(ui)
+        || ui.annotating
+        || ui.blank_overlay.is_visible()
+    {
+        return;
+    }
+    let previous_page_number = ui.document_canvas.as_ref().unwrap().current_page_number;
+
+    let doc = ui.document_canvas.as_mut().unwrap();
+    if forward {
+        doc.increase_page_number();
+    } else {
+        doc.decrease_page_number();
+    }
+    stop_auto_turn(ui);
+    process_page_change(ui, previous_page_number);
+    update_page_status(ui);
+}
+
+fn process_page_change(ui: &mut Ui, previous_page_number: PageNumber) {
+    let mut hit_edge = None;
+    if let Some(doc) = ui.document_canvas.as_mut() {
+        if doc.current_page_number != previous_page_number {
+            doc.push_history(previous_page_number);
+        }
+        // A double turn that moved one page still counts as a turn
+        hit_edge = doc
+            .hit_edge
+            .take()
+            .filter(|_| doc.current_page_number == previous_page_number);
+    }
+    if ui.edge_feedback {
+        match hit_edge {
+            Some(NavigationDirection::Forward) => ui.notify("Last page"),
+            Some(NavigationDirection::Backward) => ui.notify("First page"),
+            None => {}
+        }
+    }
+    show_page_change(ui, previous_page_number);
+}
+
+// Moving through the history must not add to it
+fn step_through_history(
+    ui: &mut Ui,
+    step: impl FnOnce(&mut DocumentCanvas) -> bool,
+    nothing_left: &str,
+) {
+    let Some(doc) = ui.document_canvas.as_mut() else {
+        return;
+    };
+    let previous_page_number = doc.current_page_number;
+    if !step(doc) {
+        ui.notify(nothing_left);
+        return;
+    }
+    stop_auto_turn(ui);
+    if ui.continuous {
+        let page_number = ui.document_canvas.as_ref().unwrap().current_page_number;
+        scroll_to_continuous_page(ui, page_number);
+    }
+    show_page_change(ui, previous_page_number);
+    update_page_status(ui);
+}
+
+fn show_page_change(ui: &mut Ui, previous_page_number: PageNumber) {
+    let Some(doc) = ui.document_canvas.as_ref() else {
+        return;
+    };
+    if doc.current_page_number == previous_page_number {
+        return;
+    }
+    doc.save_position();
+    let moves_right = (doc.current_page_number > previous_page_number) != doc.right_to_left;
+    let transition = if moves_right {
+        StackTransitionType::SlideLeft
+    } else {
+        StackTransitionType::SlideRight
+    };
+    record_practice_activity(ui);
+    begin_page_turn(ui, transition);
+    remember_page_view(ui, previous_page_number);
+    ui.pending_scroll = (None, None);
+    restore_page_view(ui);
+}
+
+// Shows a snapshot of the outgoing pages until the incoming pages are retrieved
+fn begin_page_turn(ui: &mut Ui, transition: StackTransitionType) {
+    if !ui.animate_page_turns {
+        return;
+    }
+    let snapshot = WidgetPaintable::new(Some(&ui.pages_box)).current_image();
+    ui.page_turn_snapshot.set_paintable(Some(&snapshot));
+    ui.page_turn_stack
+        .set_visible_child_full(PAGE_TURN_SNAPSHOT, StackTransitionType::None);
+    ui.pending_page_turn = Some(transition);
+
+    // Don't get stuck on the snapshot if the pages never arrive
+    let stack = ui.page_turn_stack.clone();
+    glib::timeout_add_local_once(Duration::from_millis(500), move || {
+        if stack.visible_child_name().as_deref() == Some(PAGE_TURN_SNAPSHOT) {
+            stack.set_visible_child_full(PAGE_TURN_PAGES, StackTransitionType::None);
+        }
+    });
+}
+
+// The preview is snapshotted and faded out, so the sharper pages don't pop in.
+// Both pages of a spread are swapped under the same snapshot, so neither changes alone.
+fn show_upgraded_pages(ui: &Ui, pages: &[(&Picture, &cache::MyPageType)]) {
+    if pages.is_empty() {
+        return;
+    }
+    let is_turning_page = ui.pending_page_turn.is_some()
+        || ui.page_turn_stack.visible_child_name().as_deref() == Some(PAGE_TURN_SNAPSHOT);
+    if !ui.fade_resolution_upgrades || is_turning_page {
+        for (picture, page) in pages {
+            picture.set_paintable(Some(*page));
+        }
+        return;
+    }
+    let snapshot = WidgetPaintable::new(Some(&ui.pages_box)).current_image();
+    ui.page_turn_snapshot.set_paintable(Some(&snapshot));
+    ui.page_turn_stack
+        .set_visible_child_full(PAGE_TURN_SNAPSHOT, StackTransitionType::None);
+    for (picture, page) in pages {
+        picture.set_paintable(Some(*page));
+    }
+    ui.page_turn_stack
+        .set_visible_child_full(PAGE_TURN_PAGES, StackTransitionType::Crossfade);
+}
+
+fn finish_page_turn(ui: &mut Ui) {
+    if let Some(transition) = ui.pending_page_turn.take() {
+        ui.page_turn_stack
+            .set_visible_child_full(PAGE_TURN_PAGES, transition);
+    }
+    // The annotations belong to the pages that just arrived
+    ui.annotation_area.queue_draw();
+}
+
+fn process_left_click(ui: &mut Ui, n_press: i32, x: f64, y: f64) {
+    // Without a keyboard, tapping is the only way back to the pages
+    if ui.blank_overlay.is_visible() {
+        toggle_blank_screen(ui);
+        return;
+    }
+    ui.show_page_turn_buttons();
+    // Scrolling continuously replaces turning pages
+    if ui.annotating || ui.continuous {
+        return;
+    }
+    if is_zoomed(ui) {
+        // Page turning is disabled while zoomed, so panning can't flip pages
+        if n_press == 2 {
+            reset_zoom(ui);
+        }
+        return;
+    }
+    if let Some(last_touch_time) = ui.last_touch_time {
+        if last_touch_time.elapsed() < Duration::from_millis(100) {
+            // Prevent accidental double touching
+            return;
+        }
+    }
+
+    ui.last_touch_time = Some(Instant::now());
+    if ui.document_canvas.is_none() {
+        return;
+    }
+    let previous_page_number = ui.document_canvas.as_ref().unwrap().current_page_number;
+    let edge_touch_area_size = ui.click_zones.edge_size(ui.app_wrapper.height() as f64);
+    let double_turns = ui.click_zones.double_turns;
+    let double_turn_width = ui
+        .click_zones
+        .double_turn_width(ui.app_wrapper.width() as f64);
+
+    let center = ui.app_wrapper.width() / 2;
+    // Mirror the touch areas, so the side with the next page always turns forward
+    let reading_x = if ui.document_canvas.as_ref().unwrap().right_to_left {
+        ui.app_wrapper.width() as f64 - x
+    } else {
+        x
+    };
+    if y < edge_touch_area_size {
+        if x > ui.app_wrapper.width() as f64 - edge_touch_area_size {
+            ui.window.close();
+            return;
+        }
+        toggle_fullscreen(ui);
+    } else if reading_x > center as f64 {
+        if !double_turns || reading_x < ui.app_wrapper.width() as f64 - double_turn_width {
+            ui.document_canvas.as_mut().unwrap().increase_page_number();
+        } else {
+            ui.document_canvas.as_mut().unwrap().increase_page_number();
+            ui.document_canvas.as_mut().unwrap().increase_page_number();
+        }
+    } else if reading_x < center as f64 {
+        if !double_turns || reading_x > double_turn_width {
+            ui.document_canvas.as_mut().unwrap().decrease_page_number();
+        } else {
+            ui.document_canvas.as_mut().unwrap().decrease_page_number();
+            ui.document_canvas.as_mut().unwrap().decrease_page_number();
+        }
+    }
+    if ui.document_canvas.as_ref().unwrap().current_page_number != previous_page_number {
+        stop_auto_turn(ui);
+    }
+    process_page_change(ui, previous_page_number);
+    update_page_status(ui);
+}
+
+impl Ui {
+    // A new message replaces the current one and restarts the timeout
+    pub fn notify(&self, message: &str) {
+        self.notification_label.set_label(message);
+        self.notification.set_reveal_child(true);
+
+        let serial = self.notification_serial.get().wrapping_add(1);
+        self.notification_serial.set(serial);
+        let notification = self.notification.clone();
+        let notification_serial = Rc::clone(&self.notification_serial);
+        glib::timeout_add_local_once(NOTIFICATION_DURATION, move || {
+            if notification_serial.get() == serial {
+                notification.set_reveal_child(false);
+            }
+        });
+    }
+
+    // Revealed on every tap and faded out again once the reader stops tapping
+    fn show_page_turn_buttons(&self) {
+        if !self.page_turn_buttons.is_visible()
+            || self.document_canvas.is_none()
+            || self.annotating
+            || self.continuous
+        {
+            return;
+        }
+        self.page_turn_buttons.set_reveal_child(true);
+
+        let serial = self.page_turn_buttons_serial.get().wrapping_add(1);
+        self.page_turn_buttons_serial.set(serial);
+        let page_turn_buttons = self.page_turn_buttons.clone();
+        let page_turn_buttons_serial = Rc::clone(&self.page_turn_buttons_serial);
+        glib::timeout_add_local_once(PAGE_TURN_BUTTONS_DURATION, move || {
+            if page_turn_buttons_serial.get() == serial {
+                page_turn_buttons.set_reveal_child(false);
+            }
+        });
+    }
+
+    pub fn build(app: &Application, config: &Config) -> Rc<RefCell<Ui>> {
         debug!("building ui");
         let open_file_button = Button::from_icon_name("document-open");
+        let recent_files_popover = Popover::new();
+        let recent_files_button = MenuButton::builder()
+            .icon_name("document-open-recent-symbolic")
+            .tooltip_text("Recent files")
+            .popover(&recent_files_popover)
+            .build();
+        let outline_button = MenuButton::builder()
+            .icon_name("view-list-symbolic")
+            .tooltip_text("Table of contents")
+            .visible(false)
+            .build();
+        let annotate_button = ToggleButton::builder()
+            .icon_name("document-edit-symbolic")
+            .tooltip_text("Draw on the pages")
+            .build();
+        let continuous_button = ToggleButton::builder()
+            .icon_name("view-continuous-symbolic")
+            .tooltip_text("Scroll through the pages continuously")
+            .build();
+        let export_button = Button::from_icon_name("document-save-symbolic");
+        export_button.set_tooltip_text(Some("Save the pages as image"));
+        let sidebar_button = Button::from_icon_name("sidebar-show-symbolic");
+        sidebar_button.set_tooltip_text(Some("Show page thumbnails"));
+        let invert_button = Button::from_icon_name("weather-clear-night-symbolic");
+        invert_button.set_tooltip_text(Some("Invert page colors"));
+        let fit_mode_button = Button::from_icon_name("zoom-fit-best-symbolic");
+        fit_mode_button.set_tooltip_text(Some("Toggle fit to height / width / without enlarging"));
+        let view_mode_button = Button::from_icon_name("view-dual-symbolic");
+        view_mode_button.set_tooltip_text(Some("Toggle single page / spread / grid"));
+        let settings_button = Button::from_icon_name("emblem-system-symbolic");
+        settings_button.set_tooltip_text(Some("Settings"));
+        let auto_turn_button = Button::builder()
+            .icon_name("media-playback-start-symbolic")
+            .has_frame(false)
+            .tooltip_text("Turn pages automatically")
+            .build();
+        let auto_turn_interval_button = SpinButton::with_range(1.0, 600.0, 1.0);
+        auto_turn_interval_button.set_value(DEFAULT_AUTO_TURN_SECONDS as f64);
+        auto_turn_interval_button.set_tooltip_text(Some("Seconds per page"));
+        let metronome_button = Button::builder()
+            .icon_name("alarm-symbolic")
+            .has_frame(false)
+            .tooltip_text("Start the metronome")
+            .build();
+        let metronome_bpm_button = SpinButton::with_range(20.0, 300.0, 1.0);
+        metronome_bpm_button.set_value(DEFAULT_METRONOME_BPM as f64);
+        metronome_bpm_button.set_tooltip_text(Some("Beats per minute"));
+        let metronome_beats_button = SpinButton::with_range(1.0, 12.0, 1.0);
+        metronome_beats_button.set_value(DEFAULT_BEATS_PER_BAR as f64);
+        metronome_beats_button.set_tooltip_text(Some("Beats per bar"));
 
         let image_container = Box::builder()
-            .spacing(0)
+            .spacing(config.page_gap.max(0))
             // .width_request(600)
             // .height_request(300)
             .vexpand(true)
@@ -219,17 +3246,164 @@ impl Ui {
             .build();
         image_container.append(&image_left);
         image_container.append(&image_right);
+        let image_bottom_left = Picture::builder().vexpand(true).build();
+        let image_bottom_right = Picture::builder().vexpand(true).build();
+        let grid_row = Box::builder()
+            .spacing(config.page_gap.max(0))
+            .vexpand(true)
+            .hexpand(true)
+            .halign(gtk::Align::Center)
+            .visible(false)
+            .build();
+        grid_row.append(&image_bottom_left);
+        grid_row.append(&image_bottom_right);
+        let pages_box = Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(config.page_gap.max(0))
+            .vexpand(true)
+            .hexpand(true)
+            .build();
+        pages_box.append(&image_container);
+        pages_box.append(&grid_row);
+
+        let page_turn_snapshot = Picture::builder().build();
+        let page_turn_stack = Stack::builder().transition_duration(250).build();
+        let annotation_area = DrawingArea::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .can_target(false)
+            .build();
+        let pages_with_annotations = Overlay::builder().child(&pages_box).build();
+        pages_with_annotations.add_overlay(&annotation_area);
+        page_turn_stack.add_named(&pages_with_annotations, Some(PAGE_TURN_PAGES));
+        page_turn_stack.add_named(&page_turn_snapshot, Some(PAGE_TURN_SNAPSHOT));
+
+        let scroller = ScrolledWindow::builder()
+            .hscrollbar_policy(PolicyType::External)
+            .vscrollbar_policy(PolicyType::External)
+            .hexpand(true)
+            .vexpand(true)
+            .child(&page_turn_stack)
+            .build();
+
+        let continuous_pages = Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(CONTINUOUS_SPACING)
+            .build();
+        let continuous_scroller = ScrolledWindow::builder()
+            .hscrollbar_policy(PolicyType::Never)
+            .hexpand(true)
+            .vexpand(true)
+            .child(&continuous_pages)
+            .build();
+        let layout_stack = Stack::new();
+        layout_stack.add_named(&scroller, Some(LAYOUT_PAGES));
+        layout_stack.add_named(&continuous_scroller, Some(LAYOUT_CONTINUOUS));
 
         let app_wrapper = Overlay::builder()
             // .orientation(Orientation::Vertical)
             .hexpand(true)
             .vexpand(true)
-            .child(&image_container)
+            .child(&layout_stack)
+            .build();
+        let thumbnail_box = FlowBox::builder()
+            .max_children_per_line(1)
+            .selection_mode(gtk::SelectionMode::None)
+            .valign(gtk::Align::Start)
+            .build();
+        let sidebar = Revealer::builder()
+            .transition_type(RevealerTransitionType::SlideRight)
+            .child(
+                &ScrolledWindow::builder()
+                    .hscrollbar_policy(PolicyType::Never)
+                    .width_request(THUMBNAIL_HEIGHT + 40)
+                    .child(&thumbnail_box)
+                    .build(),
+            )
+            .build();
+
+        let notes_view = gtk::TextView::builder()
+            .editable(false)
+            .cursor_visible(false)
+            .wrap_mode(gtk::WrapMode::WordChar)
+            .left_margin(12)
+            .right_margin(12)
+            .top_margin(12)
+            .bottom_margin(12)
+            .build();
+        let notes_panel = Revealer::builder()
+            .transition_type(RevealerTransitionType::SlideLeft)
+            .child(
+                &ScrolledWindow::builder()
+                    .hscrollbar_policy(PolicyType::Never)
+                    .width_request(NOTES_WIDTH)
+                    .child(&notes_view)
+                    .build(),
+            )
+            .build();
+
+        let notification_label = Label::builder().wrap(true).build();
+        let notification = Revealer::builder()
+            .transition_type(RevealerTransitionType::Crossfade)
+            .transition_duration(300)
+            .halign(gtk::Align::Center)
+            .valign(gtk::Align::End)
+            .margin_bottom(48)
+            .can_target(false)
+            .child(&notification_label)
+            .build();
+        notification_label.add_css_class("app-notification");
+
+        let click_zones = config.click_zones;
+        let click_zones_overlay = DrawingArea::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .visible(false)
+            .can_target(false)
+            .build();
+        click_zones_overlay.set_draw_func(move |_, context, width, height| {
+            draw::draw_click_zones(context, width as f64, height as f64, &click_zones);
+        });
+
+        let blank_overlay = build_black_area();
+        let dim_overlay = build_black_area();
+        // Drawing and turning pages has to work through the dimmed pages
+        dim_overlay.set_can_target(false);
+        pages_with_annotations.add_overlay(&dim_overlay);
+        let stats_label = Label::builder()
+            .halign(gtk::Align::Start)
+            .valign(gtk::Align::Start)
+            .margin_start(6)
+            .margin_top(6)
+            .css_classes(["osd", "monospace"])
+            .visible(false)
+            .can_target(false)
+            .build();
+        pages_with_annotations.add_overlay(&stats_label);
+        let dim_scale = Scale::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .adjustment(&gtk::Adjustment::new(
+                0.0,
+                0.0,
+                MAX_DIM,
+                DIM_STEP / 10.0,
+                DIM_STEP,
+                0.0,
+            ))
+            .draw_value(false)
+            .width_request(100)
+            .tooltip_text("Dim the pages")
             .build();
+
+        let window_content = Box::builder().build();
+        window_content.append(&sidebar);
+        window_content.append(&app_wrapper);
+        window_content.append(&notes_panel);
+
         let window = ApplicationWindow::builder()
             .application(app)
             .title("Music Reader")
-            .child(&app_wrapper)
+            .child(&window_content)
             .maximized(true)
             .width_request(600)
             .height_request(400)
@@ -238,38 +3412,490 @@ impl Ui {
         let ui = Ui {
             window,
             app_wrapper,
+            scroller,
+            layout_stack,
+            continuous: false,
+            continuous_button,
+            continuous_scroller,
+            continuous_pages,
+            continuous_pictures: Vec::new(),
+            pending_continuous_scroll: None,
+            fit_mode_before_continuous: None,
             bottom_bar: Box::builder()
                 .hexpand_set(true)
                 .valign(gtk::Align::End)
                 .build(),
             header_bar: HeaderBar::builder().build(),
-            page_indicator: Label::builder().build(),
+            page_indicator: Button::builder()
+                .has_frame(false)
+                .tooltip_text("Go to page")
+                .build(),
+            cache_progress: ProgressBar::builder()
+                .valign(gtk::Align::Center)
+                .width_request(80)
+                .build(),
             image_container,
             image_left,
             image_right,
+            pages_box,
+            grid_row,
+            image_bottom_left,
+            image_bottom_right,
             document_canvas: None,
+            document: None,
             last_touch_time: None,
+            cache_pages: 30,
+            cache_memory: 512 * BYTES_PER_MB,
+            prefetch_pages: 3,
+            animate_page_turns: true,
+            precache_all: false,
+            spread_step: 2,
+            config: config.clone(),
+            default_view_mode: config.view_mode,
+            default_fit_mode: config.fit_mode,
+            default_invert: config.invert,
+            default_print_rendering: config.print_rendering,
+            page_background: config.page_background,
+            render_scale: None,
+            right_to_left: false,
+            detect_blank_pages: false,
+            levels: state::load_levels(),
+            page_turn_stack,
+            page_turn_snapshot,
+            pending_page_turn: None,
+            fade_resolution_upgrades: config.fade_resolution_upgrades,
+            resolution_steps: config.resolution_steps.clone(),
+            center_single_pages: config.center_single_pages,
+            edge_feedback: config.edge_feedback,
+            zoom_factor: 1.0,
+            zoom_at_gesture_begin: 1.0,
+            scroll_at_drag_begin: (0.0, 0.0),
+            pending_scroll: (None, None),
+            sidebar,
+            notes_panel,
+            notes_view,
+            notes: None,
+            thumbnail_box,
+            thumbnail_pictures: Vec::new(),
+            thumbnail_cache: None,
+            rendering_thumbnails: false,
+            auto_turn_button,
+            auto_turn_interval: DEFAULT_AUTO_TURN_SECONDS,
+            auto_turn_source: None,
+            metronome: None,
+            metronome_button,
+            metronome_indicator: Label::builder()
+                .label("1")
+                .width_chars(2)
+                .opacity(DIMMED_BEAT_OPACITY)
+                .visible(false)
+                .build(),
+            metronome_bpm: DEFAULT_METRONOME_BPM,
+            metronome_beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            loading_spinner: Spinner::builder()
+                .halign(gtk::Align::Center)
+                .valign(gtk::Align::Center)
+                .width_request(48)
+                .height_request(48)
+                .visible(false)
+                // Clicks have to reach the pages below
+                .can_target(false)
+                .build(),
+            outline_button,
+            notification,
+            notification_label,
+            notification_serial: Rc::new(Cell::new(0)),
+            page_turn_buttons: Revealer::builder()
+                .transition_type(RevealerTransitionType::Crossfade)
+                .transition_duration(300)
+                .valign(gtk::Align::Center)
+                .visible(config.page_turn_buttons)
+                .build(),
+            page_turn_buttons_serial: Rc::new(Cell::new(0)),
+            click_zones,
+            click_zones_overlay,
+            blank_overlay,
+            dim_overlay,
+            stats_label,
+            dim_scale,
+            empty_state: Box::builder()
+                .orientation(gtk::Orientation::Vertical)
+                .spacing(12)
+                .halign(gtk::Align::Center)
+                .valign(gtk::Align::Center)
+                .build(),
+            search_bar: SearchBar::builder()
+                .halign(gtk::Align::Center)
+                .valign(gtk::Align::Start)
+                .show_close_button(true)
+                .build(),
+            search_entry: SearchEntry::builder()
+                .placeholder_text("Search the text")
+                .build(),
+            search_status: Label::new(None),
+            search_text: String::new(),
+            search_hits: Vec::new(),
+            searching: false,
+            search_serial: Rc::new(Cell::new(0)),
+            annotating: false,
+            peeking: false,
+            annotation_area,
+            current_stroke: None,
+            adjusting: false,
+            adjusting_right_page: false,
+            cropping: false,
+            crop_button: ToggleButton::builder()
+                .icon_name("zoom-fit-best-symbolic")
+                .tooltip_text("Crop a page to a selected region")
+                .build(),
+            crop_selection: None,
+            stroke_start: (0.0, 0.0),
+            resize_serial: Rc::new(Cell::new(0)),
+            passwords: HashMap::new(),
+            page_maps: HashMap::new(),
+            loaded_files: None,
+            previous_files: None,
+            practice_timer: PracticeTimer::default(),
         };
         let ui = Rc::new(RefCell::new(ui));
 
-        ui.borrow().header_bar.pack_start(&open_file_button);
-        ui.borrow().app_wrapper.add_overlay(&ui.borrow().bottom_bar);
-        ui.borrow().bottom_bar.append(&ui.borrow().page_indicator);
+        ui.borrow().header_bar.pack_start(&open_file_button);
+        ui.borrow().header_bar.pack_start(&recent_files_button);
+        ui.borrow().header_bar.pack_start(&sidebar_button);
+        ui.borrow()
+            .header_bar
+            .pack_start(&ui.borrow().outline_button);
+        ui.borrow().header_bar.pack_start(&annotate_button);
+        ui.borrow().header_bar.pack_start(&ui.borrow().crop_button);
+        ui.borrow().header_bar.pack_start(&export_button);
+        ui.borrow().header_bar.pack_end(&settings_button);
+        ui.borrow().header_bar.pack_end(&view_mode_button);
+        ui.borrow()
+            .header_bar
+            .pack_end(&ui.borrow().continuous_button);
+        ui.borrow().header_bar.pack_end(&fit_mode_button);
+        ui.borrow().header_bar.pack_end(&invert_button);
+        let levels_button = MenuButton::builder()
+            .icon_name("display-brightness-symbolic")
+            .tooltip_text("Brightness and contrast")
+            .popover(&build_levels_popover(Rc::clone(&ui)))
+            .build();
+        ui.borrow().header_bar.pack_end(&levels_button);
+        let practice_popover = Popover::new();
+        practice_popover.connect_show(glib::clone!(@weak ui => move |popover| {
+            populate_practice_times(&mut ui.borrow_mut(), popover);
+        }));
+        let practice_button = MenuButton::builder()
+            .icon_name("alarm-symbolic")
+            .tooltip_text("Practice time")
+            .popover(&practice_popover)
+            .build();
+        ui.borrow().header_bar.pack_end(&practice_button);
+        ui.borrow().app_wrapper.add_overlay(&ui.borrow().bottom_bar);
+        ui.borrow()
+            .app_wrapper
+            .add_overlay(&ui.borrow().loading_spinner);
+        ui.borrow()
+            .app_wrapper
+            .add_overlay(&ui.borrow().notification);
+        ui.borrow()
+            .app_wrapper
+            .add_overlay(&ui.borrow().click_zones_overlay);
+        ui.borrow()
+            .app_wrapper
+            .add_overlay(&ui.borrow().empty_state);
+        let page_turn_buttons = gtk::CenterBox::new();
+        // The left button turns forward in right to left documents
+        for (label, is_right) in [("◀", false), ("▶", true)] {
+            let button = Button::builder()
+                .label(label)
+                .width_request(72)
+                .height_request(72)
+                .margin_start(12)
+                .margin_end(12)
+                .opacity(0.6)
+                .css_classes(["osd", "circular"])
+                .build();
+            button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+                let right_to_left = ui
+                    .borrow()
+                    .document_canvas
+                    .as_ref()
+                    .is_some_and(|doc| doc.right_to_left);
+                process_turn_click(&mut ui.borrow_mut(), is_right != right_to_left);
+                ui.borrow().show_page_turn_buttons();
+            }));
+            if is_right {
+                page_turn_buttons.set_end_widget(Some(&button));
+            } else {
+                page_turn_buttons.set_start_widget(Some(&button));
+            }
+        }
+        ui.borrow()
+            .page_turn_buttons
+            .set_child(Some(&page_turn_buttons));
+        ui.borrow()
+            .app_wrapper
+            .add_overlay(&ui.borrow().page_turn_buttons);
+        let search_previous_button = Button::builder()
+            .icon_name("go-up-symbolic")
+            .tooltip_text("Previous match")
+            .build();
+        search_previous_button.connect_clicked(
+            glib::clone!(@weak ui => @default-panic, move |_button| {
+                go_to_search_hit(&mut ui.borrow_mut(), false);
+            }),
+        );
+        let search_next_button = Button::builder()
+            .icon_name("go-down-symbolic")
+            .tooltip_text("Next match")
+            .build();
+        search_next_button.connect_clicked(
+            glib::clone!(@weak ui => @default-panic, move |_button| {
+                go_to_search_hit(&mut ui.borrow_mut(), true);
+            }),
+        );
+        let search_box = Box::builder().spacing(6).build();
+        search_box.append(&ui.borrow().search_entry);
+        search_box.append(&search_previous_button);
+        search_box.append(&search_next_button);
+        search_box.append(&ui.borrow().search_status);
+        ui.borrow().search_bar.set_child(Some(&search_box));
+        ui.borrow()
+            .search_bar
+            .connect_entry(&ui.borrow().search_entry);
+        // Enter searches for new text and jumps to the next match of the same text
+        ui.borrow().search_entry.connect_activate(
+            glib::clone!(@weak ui => @default-panic, move |entry| {
+                let text = entry.text().to_string();
+                if text == ui.borrow().search_text {
+                    go_to_search_hit(&mut ui.borrow_mut(), true);
+                } else {
+                    start_search(ui, text);
+                }
+            }),
+        );
+        ui.borrow().search_entry.connect_next_match(
+            glib::clone!(@weak ui => @default-panic, move |_| {
+                go_to_search_hit(&mut ui.borrow_mut(), true);
+            }),
+        );
+        ui.borrow().search_entry.connect_previous_match(
+            glib::clone!(@weak ui => @default-panic, move |_| {
+                go_to_search_hit(&mut ui.borrow_mut(), false);
+            }),
+        );
+        ui.borrow().app_wrapper.add_overlay(&ui.borrow().search_bar);
+        ui.borrow()
+            .app_wrapper
+            .add_overlay(&ui.borrow().blank_overlay);
+        let empty_state_open_button = Button::builder()
+            .label("Open…")
+            .halign(gtk::Align::Center)
+            .build();
+        empty_state_open_button.connect_clicked(
+            glib::clone!(@weak ui => @default-panic, move |_button| {
+                let window = ui.borrow().window.clone();
+                choose_file(ui, &window);
+            }),
+        );
+        ui.borrow()
+            .empty_state
+            .append(&Label::new(Some("Drop a PDF here or click Open")));
+        ui.borrow().empty_state.append(&empty_state_open_button);
+
+        // Several dropped files are opened as one document, like on the command line
+        let drop_target = gtk::DropTarget::new(gdk::FileList::static_type(), gdk::DragAction::COPY);
+        drop_target.connect_drop(
+            glib::clone!(@weak ui => @default-return false, move |_, value, _, _| {
+                let Ok(file_list) = value.get::<gdk::FileList>() else {
+                    return false;
+                };
+                let paths: Vec<PathBuf> = file_list
+                    .files()
+                    .iter()
+                    .filter_map(|file| file.path())
+                    .collect();
+                if paths.is_empty() {
+                    show_error_dialog(&ui.borrow(), OPEN_ERROR_TITLE, "The dropped files are not local files");
+                    return false;
+                }
+                load_document_or_show_error(&paths, ui);
+                true
+            }),
+        );
+        ui.borrow().window.add_controller(drop_target);
+        ui.borrow().bottom_bar.append(&ui.borrow().page_indicator);
+        ui.borrow().bottom_bar.append(&ui.borrow().cache_progress);
+        ui.borrow().bottom_bar.append(&ui.borrow().auto_turn_button);
+        ui.borrow().bottom_bar.append(&auto_turn_interval_button);
+        ui.borrow().bottom_bar.append(&ui.borrow().metronome_button);
+        ui.borrow().bottom_bar.append(&metronome_bpm_button);
+        ui.borrow().bottom_bar.append(&metronome_beats_button);
+        ui.borrow()
+            .bottom_bar
+            .append(&ui.borrow().metronome_indicator);
+        ui.borrow().bottom_bar.append(&ui.borrow().dim_scale);
+        ui.borrow().dim_scale.connect_value_changed(
+            glib::clone!(@weak ui => @default-panic, move |scale| {
+                set_dim(&ui.borrow(), scale.value());
+            }),
+        );
+        // Changing the value applies it, the level saved on close is restored on every start
+        ui.borrow().dim_scale.set_value(state::load_dim());
+
+        let mouse_buttons = &config.mouse_buttons;
+        let mut clicks = Vec::new();
+        for &button in mouse_buttons.zones.iter() {
+            let click = gtk::GestureClick::new();
+            click.set_button(button);
+            // Handled on release, clicks that turn into swipes are cancelled before
+            click.connect_released(
+                glib::clone!(@weak ui => @default-panic, move |_, n_press, x, y| {
+                    process_left_click(&mut ui.borrow_mut(), n_press, x, y);
+                }),
+            );
+            clicks.push(click);
+        }
+        for (buttons, forward) in [
+            (&mouse_buttons.next, true),
+            (&mouse_buttons.previous, false),
+        ] {
+            for &button in buttons.iter() {
+                let click = gtk::GestureClick::new();
+                click.set_button(button);
+                click.connect_pressed(glib::clone!(@weak ui => @default-panic, move |_, _, _, _| {
+                    process_turn_click(&mut ui.borrow_mut(), forward);
+                }));
+                clicks.push(click);
+            }
+        }
+
+        let zoom = gtk::GestureZoom::new();
+        zoom.connect_begin(glib::clone!(@weak ui => @default-panic, move |_, _| {
+            process_zoom_begin(&mut ui.borrow_mut());
+        }));
+        zoom.connect_scale_changed(glib::clone!(@weak ui => @default-panic, move |_, scale| {
+            process_zoom_change(&mut ui.borrow_mut(), scale);
+        }));
+        zoom.connect_end(glib::clone!(@weak ui => @default-panic, move |_, _| {
+            render_zoomed_pages(&ui.borrow());
+            let page_number = ui.borrow().document_canvas.as_ref().map(|doc| doc.current_page_number);
+            if let Some(page_number) = page_number {
+                remember_page_view(&mut ui.borrow_mut(), page_number);
+            }
+        }));
+
+        let swipe = gtk::GestureSwipe::new();
+        swipe.connect_swipe(
+            glib::clone!(@weak ui => @default-panic, move |_, velocity_x, velocity_y| {
+                process_swipe(&mut ui.borrow_mut(), velocity_x, velocity_y);
+            }),
+        );
+
+        let peek = gtk::GestureLongPress::new();
+        peek.connect_pressed(
+            glib::clone!(@weak ui => @default-panic, move |gesture, _, _| {
+                if process_peek_begin(&mut ui.borrow_mut()) {
+                    // Releasing the pages must not count as a click that turns them
+                    gesture.set_state(gtk::EventSequenceState::Claimed);
+                }
+            }),
+        );
+        peek.connect_end(glib::clone!(@weak ui => @default-panic, move |_, _| {
+            process_peek_end(&mut ui.borrow_mut());
+        }));
+
+        // Discrete, so a touchpad turns one page per notch instead of one per tiny movement
+        let scroll = gtk::EventControllerScroll::new(
+            gtk::EventControllerScrollFlags::BOTH_AXES | gtk::EventControllerScrollFlags::DISCRETE,
+        );
+        // Decides before the scrolled window, which would take the events to pan
+        scroll.set_propagation_phase(gtk::PropagationPhase::Capture);
+        scroll.connect_scroll(
+            glib::clone!(@weak ui => @default-return glib::Propagation::Proceed, move |_, dx, dy| {
+                process_scroll(&mut ui.borrow_mut(), dx, dy)
+            }),
+        );
 
-        let click_left = gtk::GestureClick::new();
-        click_left.set_button(1);
-        click_left.connect_pressed(glib::clone!(@weak ui => @default-panic, move |_, _, x, y| {
-        process_left_click(&mut ui.borrow_mut(), x, y);
-             }));
+        let drag = gtk::GestureDrag::new();
+        drag.connect_drag_begin(glib::clone!(@weak ui => @default-panic, move |_, _, _| {
+            process_drag_begin(&mut ui.borrow_mut());
+        }));
+        drag.connect_drag_update(
+            glib::clone!(@weak ui => @default-panic, move |_, offset_x, offset_y| {
+                process_drag_update(&ui.borrow(), offset_x, offset_y);
+            }),
+        );
+        drag.connect_drag_end(glib::clone!(@weak ui => @default-panic, move |_, _, _| {
+            let page_number = ui.borrow().document_canvas.as_ref().map(|doc| doc.current_page_number);
+            if let Some(page_number) = page_number {
+                remember_page_view(&mut ui.borrow_mut(), page_number);
+            }
+        }));
+        for adjustment in [
+            ui.borrow().scroller.hadjustment(),
+            ui.borrow().scroller.vadjustment(),
+        ] {
+            adjustment.connect_changed(glib::clone!(@weak ui => @default-panic, move |_| {
+                if let Ok(mut ui) = ui.try_borrow_mut() {
+                    apply_pending_scroll(&mut ui);
+                }
+            }));
+        }
 
-        let click_right = gtk::GestureClick::new();
-        click_right.set_button(3);
-        click_right.connect_pressed(glib::clone!(@weak ui => @default-panic, move |_, _, x, y| {
-        process_right_click(&mut ui.borrow_mut(), x, y);
-             }));
+        ui.borrow().annotation_area.set_draw_func(
+            glib::clone!(@weak ui => @default-panic, move |_, context, _, _| {
+                draw_annotations(&ui.borrow(), context);
+            }),
+        );
+        let stroke = gtk::GestureDrag::new();
+        stroke.connect_drag_begin(
+            glib::clone!(@weak ui => @default-panic, move |gesture, x, y| {
+                // Keep the gestures of the pages below from turning or panning
+                gesture.set_state(gtk::EventSequenceState::Claimed);
+                // Selecting a crop takes precedence over drawing
+                if ui.borrow().cropping {
+                    process_crop_begin(&mut ui.borrow_mut(), x, y);
+                } else {
+                    process_stroke_begin(&mut ui.borrow_mut(), x, y);
+                }
+            }),
+        );
+        stroke.connect_drag_update(
+            glib::clone!(@weak ui => @default-panic, move |_, offset_x, offset_y| {
+                if ui.borrow().cropping {
+                    process_crop_update(&mut ui.borrow_mut(), offset_x, offset_y);
+                } else {
+                    process_stroke_update(&mut ui.borrow_mut(), offset_x, offset_y);
+                }
+            }),
+        );
+        stroke.connect_drag_end(glib::clone!(@weak ui => @default-panic, move |_, _, _| {
+            if ui.borrow().cropping {
+                process_crop_end(&mut ui.borrow_mut());
+            } else {
+                process_stroke_end(&mut ui.borrow_mut());
+            }
+        }));
+        ui.borrow().annotation_area.add_controller(stroke);
+        annotate_button.connect_toggled(glib::clone!(@weak ui => @default-panic, move |button| {
+            set_annotating(&mut ui.borrow_mut(), button.is_active());
+        }));
+        ui.borrow().crop_button.connect_toggled(
+            glib::clone!(@weak ui => @default-panic, move |button| {
+                set_cropping(&mut ui.borrow_mut(), button.is_active());
+            }),
+        );
 
-        ui.borrow().app_wrapper.add_controller(click_left);
-        ui.borrow().app_wrapper.add_controller(click_right);
+        for click in clicks {
+            ui.borrow().app_wrapper.add_controller(click);
+        }
+        ui.borrow().app_wrapper.add_controller(zoom);
+        ui.borrow().app_wrapper.add_controller(drag);
+        ui.borrow().app_wrapper.add_controller(swipe);
+        ui.borrow().app_wrapper.add_controller(peek);
+        ui.borrow().app_wrapper.add_controller(scroll);
 
         ui.borrow()
             .window
@@ -281,52 +3907,652 @@ impl Ui {
             }),
         );
 
+        ui.borrow().continuous_button.connect_toggled(
+            glib::clone!(@weak ui => @default-panic, move |button| {
+                set_continuous(&mut ui.borrow_mut(), button.is_active());
+            }),
+        );
+        let continuous_adjustment = ui.borrow().continuous_scroller.vadjustment();
+        continuous_adjustment.connect_value_changed(
+            glib::clone!(@weak ui => @default-panic, move |_| {
+                update_continuous_view(&mut ui.borrow_mut());
+            }),
+        );
+        // Emitted when the scrollable height changes, before the pages are allocated
+        continuous_adjustment.connect_changed(glib::clone!(@weak ui => @default-panic, move |_| {
+            glib::idle_add_local_once(clone!(@weak ui => move || {
+                process_continuous_layout(ui);
+            }));
+        }));
+
+        export_button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+            choose_export_file(ui);
+        }));
+
+        // Rebuilt on every show, so the list stays up to date
+        recent_files_popover.connect_show(glib::clone!(@weak ui => move |popover| {
+            populate_recent_files(ui, popover);
+        }));
+
+        sidebar_button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+            toggle_sidebar(ui);
+        }));
+
+        ui.borrow().thumbnail_box.connect_child_activated(
+            glib::clone!(@weak ui => @default-panic, move |_, child| {
+                jump_to_page(&mut ui.borrow_mut(), child.index() as PageNumber);
+            }),
+        );
+
+        ui.borrow().page_indicator.connect_clicked(
+            glib::clone!(@weak ui => @default-panic, move |_button| {
+                show_goto_dialog(ui);
+            }),
+        );
+
+        let key_controller = gtk::EventControllerKey::new();
+        key_controller.connect_key_pressed(
+            glib::clone!(@weak ui => @default-return glib::Propagation::Proceed, move |_, key, _, modifiers| {
+                process_key_press(ui, key, modifiers)
+            }),
+        );
+        ui.borrow().window.add_controller(key_controller);
+
+        ui.borrow().auto_turn_button.connect_clicked(
+            glib::clone!(@weak ui => @default-panic, move |_button| {
+                toggle_auto_turn(ui);
+            }),
+        );
+
+        auto_turn_interval_button.connect_value_changed(
+            glib::clone!(@weak ui => @default-panic, move |button| {
+                set_auto_turn_interval(ui, button.value_as_int() as u32);
+            }),
+        );
+
+        ui.borrow().metronome_button.connect_clicked(
+            glib::clone!(@weak ui => @default-panic, move |_button| {
+                toggle_metronome(ui);
+            }),
+        );
+        metronome_bpm_button.connect_value_changed(
+            glib::clone!(@weak ui => @default-panic, move |button| {
+                ui.borrow_mut().metronome_bpm = button.value_as_int() as u32;
+                restart_metronome(ui);
+            }),
+        );
+        metronome_beats_button.connect_value_changed(
+            glib::clone!(@weak ui => @default-panic, move |button| {
+                ui.borrow_mut().metronome_beats_per_bar = button.value_as_int() as u32;
+                restart_metronome(ui);
+            }),
+        );
+
+        invert_button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+            toggle_invert(&mut ui.borrow_mut());
+        }));
+
+        fit_mode_button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+            toggle_fit_mode(&mut ui.borrow_mut());
+        }));
+
+        view_mode_button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+            toggle_view_mode(&mut ui.borrow_mut());
+        }));
+
+        settings_button.connect_clicked(glib::clone!(@weak ui => @default-panic, move |_button| {
+            show_settings_dialog(Rc::clone(&ui));
+        }));
+
+        // Windows have no resize signal, but every way of resizing changes one of these
+        let window = ui.borrow().window.clone();
+        window.connect_default_width_notify(glib::clone!(@weak ui => move |_| process_resize(ui)));
+        window.connect_default_height_notify(glib::clone!(@weak ui => move |_| process_resize(ui)));
+        window.connect_maximized_notify(glib::clone!(@weak ui => move |_| process_resize(ui)));
+        window.connect_fullscreened_notify(glib::clone!(@weak ui => move |_| process_resize(ui)));
+        // Moving the window to another monitor can change it
+        window.connect_scale_factor_notify(glib::clone!(@weak ui => move |window| {
+            process_scale_factor_change(&mut ui.borrow_mut(), window.scale_factor());
+        }));
+
+        // Practice time only counts while the window is in front
+        window.connect_is_active_notify(glib::clone!(@weak ui => move |window| {
+            if window.is_active() {
+                record_practice_activity(&mut ui.borrow_mut());
+            } else {
+                ui.borrow_mut().practice_timer.pause();
+            }
+        }));
+        window.connect_close_request(
+            glib::clone!(@weak ui => @default-return glib::Propagation::Proceed, move |_| {
+                ui.borrow_mut().practice_timer.pause();
+                // Dragging the slider changes the level many times a second, so it is saved once
+                state::save_dim(ui.borrow().dim_scale.value().clamp(0.0, MAX_DIM));
+                glib::Propagation::Proceed
+            }),
+        );
+
+        load_user_css();
         ui.borrow().window.present();
         ui
     }
 }
 
+fn load_user_css() {
+    let path = config::css_file_path();
+    if !path.is_file() {
+        return;
+    }
+    let Some(display) = gdk::Display::default() else {
+        return;
+    };
+    debug!("Loading styles from {}", path.display());
+    let provider = gtk::CssProvider::new();
+    provider.connect_parsing_error(|_, section, e| {
+        error!("Failed parsing style {}: {}", section, e);
+    });
+    provider.load_from_path(&path);
+    gtk::style_context_add_provider_for_display(
+        &display,
+        &provider,
+        gtk::STYLE_PROVIDER_PRIORITY_USER,
+    );
+}
+
 fn choose_file(ui: Rc<RefCell<Ui>>, window: &ApplicationWindow) {
     let filechooser = FileChooserDialog::builder()
-        .title("Choose a PDF...")
+        .title("Choose a PDF or image...")
         .action(FileChooserAction::Open)
         .modal(true)
         .build();
+    let filter = FileFilter::new();
+    filter.set_name(Some("PDFs and images"));
+    filter.add_mime_type("application/pdf");
+    filter.add_mime_type("image/png");
+    filter.add_mime_type("image/jpeg");
+    filechooser.add_filter(&filter);
     filechooser.add_button("_Cancel", ResponseType::Cancel);
     filechooser.add_button("_Open", ResponseType::Accept);
     filechooser.set_transient_for(Some(window));
     filechooser.connect_response(move |d, response| {
         if response == ResponseType::Accept {
-            let path = d.file().unwrap().path().unwrap();
-            load_document(path, Rc::clone(&ui));
+            match d.file().and_then(|file| file.path()) {
+                Some(path) => load_document_or_show_error(&[path], Rc::clone(&ui)),
+                None => show_error_dialog(
+                    &ui.borrow(),
+                    OPEN_ERROR_TITLE,
+                    "The selected file is not a local file",
+                ),
+            }
+        }
+        d.destroy();
+    });
+    filechooser.show()
+}
+
+fn choose_export_file(ui: Rc<RefCell<Ui>>) {
+    let Some(page_numbers) = ui
+        .borrow()
+        .document_canvas
+        .as_ref()
+        .map(DocumentCanvas::visible_page_numbers)
+    else {
+        ui.borrow().notify("No document loaded!");
+        return;
+    };
+
+    let filechooser = FileChooserDialog::builder()
+        .title("Save pages as image...")
+        .action(FileChooserAction::Save)
+        .modal(true)
+        .build();
+    let page_labels: Vec<(String, String)> = page_numbers
+        .iter()
+        .map(|page_number| (page_number.to_string(), format!("Page {}", page_number + 1)))
+        .collect();
+    let mut page_options: Vec<(&str, &str)> = page_labels
+        .iter()
+        .map(|(id, label)| (id.as_str(), label.as_str()))
+        .collect();
+    let all_pages_label = match page_numbers.len() {
+        2 => "Both pages".to_string(),
+        num_pages => format!("All {} pages", num_pages),
+    };
+    if page_numbers.len() > 1 {
+        page_options.insert(0, (EXPORT_ALL_PAGES, all_pages_label.as_str()));
+    }
+    filechooser.add_choice("pages", "Pages", &page_options);
+    filechooser.set_choice("pages", page_options[0].0);
+    let dpi_labels: Vec<String> = EXPORT_DPI_OPTIONS.iter().map(f64::to_string).collect();
+    let dpi_options: Vec<(&str, &str)> = dpi_labels
+        .iter()
+        .map(|dpi| (dpi.as_str(), dpi.as_str()))
+        .collect();
+    filechooser.add_choice("dpi", "DPI", &dpi_options);
+    filechooser.set_choice("dpi", &DEFAULT_EXPORT_DPI.to_string());
+    filechooser.set_current_name(&format!("page-{}.png", page_numbers[0] + 1));
+    filechooser.add_button("_Cancel", ResponseType::Cancel);
+    filechooser.add_button("_Save", ResponseType::Accept);
+    filechooser.set_transient_for(Some(&ui.borrow().window));
+    filechooser.connect_response(move |d, response| {
+        if response == ResponseType::Accept {
+            match d.file().and_then(|file| file.path()) {
+                Some(path) => {
+                    let selected_page_numbers = match d.choice("pages").as_deref() {
+                        None | Some(EXPORT_ALL_PAGES) => page_numbers.clone(),
+                        Some(page_number) => page_number.parse().into_iter().collect(),
+                    };
+                    let dpi = d
+                        .choice("dpi")
+                        .and_then(|dpi| dpi.parse().ok())
+                        .unwrap_or(DEFAULT_EXPORT_DPI);
+                    match export_pages(&ui.borrow(), &selected_page_numbers, dpi, &path) {
+                        Ok(()) => ui.borrow().notify(&format!("Saved {}", path.display())),
+                        Err(e) => {
+                            show_error_dialog(&ui.borrow(), EXPORT_ERROR_TITLE, &format!("{:#}", e))
+                        }
+                    }
+                }
+                None => show_error_dialog(
+                    &ui.borrow(),
+                    EXPORT_ERROR_TITLE,
+                    "The selected file is not a local file",
+                ),
+            }
         }
         d.destroy();
     });
     filechooser.show()
 }
 
-pub fn load_document(file: impl AsRef<Path>, ui: Rc<RefCell<Ui>>) {
-    debug!("Loading file...");
-    // TODO: catch errors, maybe show error dialog
-    let path: PathBuf = file.as_ref().to_path_buf();
-    let uri = format!("file://{}", path.to_str().unwrap());
-    let document = poppler::Document::from_file(&uri, None).unwrap();
-    let num_pages = document.n_pages() as usize;
+fn export_pages(ui: &Ui, page_numbers: &[PageNumber], dpi: f64, path: &Path) -> Result<()> {
+    let (Some(document), Some(document_canvas)) =
+        (ui.document.as_ref(), ui.document_canvas.as_ref())
+    else {
+        anyhow::bail!("No document loaded");
+    };
+    let mut pages: Vec<Rc<dyn PageSource>> = page_numbers
+        .iter()
+        .filter_map(|&page_number| {
+            let rotation = document_canvas
+                .rotations
+                .get(&page_number)
+                .copied()
+                .unwrap_or_default();
+            Some(draw::rotate_page(document.page(page_number)?, rotation))
+        })
+        .collect();
+    // The image shows the pages in the same order as the window
+    if document_canvas.right_to_left {
+        pages.reverse();
+    }
+    draw::export_pages_to_png(&pages, dpi, &document_canvas.render_settings, path)
+}
+
+// Time is attributed to the piece being read, not to the whole setlist
+fn practiced_file(ui: &Ui) -> Option<PathBuf> {
+    let piece_index = ui
+        .document_canvas
+        .as_ref()
+        .and_then(DocumentCanvas::current_piece_index)
+        .unwrap_or(0);
+    ui.loaded_files.as_ref()?.paths.get(piece_index).cloned()
+}
+
+fn record_practice_activity(ui: &mut Ui) {
+    if let Some(file) = practiced_file(ui) {
+        ui.practice_timer.record_activity(&file);
+    }
+}
+
+fn populate_practice_times(ui: &mut Ui, popover: &Popover) {
+    ui.practice_timer.save();
+    let list = Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .spacing(6)
+        .build();
+    if let Some(file) = practiced_file(ui) {
+        let current = Label::builder()
+            .label(format!(
+                "This piece: {}",
+                practice_log::format_practice_time(practice_log::practice_time(&file))
+            ))
+            .halign(gtk::Align::Start)
+            .build();
+        list.append(&current);
+        list.append(&gtk::Separator::new(gtk::Orientation::Horizontal));
+    }
+    let practice_times = practice_log::practice_times();
+    if practice_times.is_empty() {
+        list.append(&Label::new(Some("Nothing practiced yet")));
+    }
+    for (path, duration) in practice_times {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let row = Box::builder().spacing(12).build();
+        row.append(
+            &Label::builder()
+                .label(name)
+                .tooltip_text(path.display().to_string())
+                .halign(gtk::Align::Start)
+                .hexpand(true)
+                .build(),
+        );
+        row.append(&Label::new(Some(&practice_log::format_practice_time(
+            duration,
+        ))));
+        list.append(&row);
+    }
+    popover.set_child(Some(&list));
+}
+
+fn populate_recent_files(ui: Rc<RefCell<Ui>>, popover: &Popover) {
+    let list = Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let recent_files = state::recent_files();
+    if recent_files.is_empty() {
+        list.append(&Label::new(Some("No recent files")));
+    }
+    for path in recent_files {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        let button = Button::builder()
+            .label(name)
+            .has_frame(false)
+            .tooltip_text(path.display().to_string())
+            // Files that were moved or deleted are still listed, but can't be opened
+            .sensitive(path.exists())
+            .build();
+        button.connect_clicked(clone!(@weak ui, @weak popover => move |_| {
+            popover.popdown();
+            load_document_or_show_error(&[&path], ui);
+        }));
+        list.append(&button);
+    }
+    popover.set_child(Some(&list));
+}
+
+pub fn load_document_or_show_error(files: &[impl AsRef<Path>], ui: Rc<RefCell<Ui>>) {
+    let files: Vec<PathBuf> = files
+        .iter()
+        .map(|file| file.as_ref().to_path_buf())
+        .collect();
+    if let Err(e) = load_document(&files, Rc::clone(&ui)) {
+        handle_load_error(ui, e, move |ui| load_document_or_show_error(&files, ui));
+    }
+}
+
+pub fn load_document_at_page_or_show_error(
+    files: &[impl AsRef<Path>],
+    page_number: PageNumber,
+    ui: Rc<RefCell<Ui>>,
+) {
+    let files: Vec<PathBuf> = files
+        .iter()
+        .map(|file| file.as_ref().to_path_buf())
+        .collect();
+    if let Err(e) = load_documents(&files, false, Some(page_number), Rc::clone(&ui)) {
+        handle_load_error(ui, e, move |ui| {
+            load_document_at_page_or_show_error(&files, page_number, ui)
+        });
+    }
+}
+
+pub fn load_setlist_or_show_error(path: &Path, ui: Rc<RefCell<Ui>>) {
+    let result = Setlist::load(path)
+        .and_then(|setlist| load_documents(&setlist.pieces, true, None, Rc::clone(&ui)));
+    if let Err(e) = result {
+        let path = path.to_path_buf();
+        handle_load_error(ui, e, move |ui| load_setlist_or_show_error(&path, ui));
+    }
+}
+
+fn swap_to_previous_files(ui: Rc<RefCell<Ui>>) {
+    let Some(previous_files) = ui.borrow().previous_files.clone() else {
+        ui.borrow().notify("No previous document");
+        return;
+    };
+    if let Err(e) = load_documents(
+        &previous_files.paths,
+        previous_files.with_dividers,
+        None,
+        Rc::clone(&ui),
+    ) {
+        handle_load_error(ui, e, swap_to_previous_files);
+    }
+}
+
+// Opens the files again, e.g. after exporting a new version from the notation software
+// The other PDFs of the folder become pieces before and after the open one
+fn open_folder_as_playlist(ui: Rc<RefCell<Ui>>) {
+    let Some(LoadedFiles { paths, .. }) = ui.borrow().loaded_files.clone() else {
+        ui.borrow().notify("No document loaded!");
+        return;
+    };
+    let [file] = paths.as_slice() else {
+        ui.borrow().notify("Several files are open already");
+        return;
+    };
+    let pieces = match setlist::folder_pieces(file) {
+        Ok(pieces) => pieces,
+        Err(e) => {
+            show_error_dialog(&ui.borrow(), OPEN_ERROR_TITLE, &format!("{:#}", e));
+            return;
+        }
+    };
+    let Some(piece_index) = pieces
+        .iter()
+        .position(|piece| piece.file_name() == file.file_name())
+    else {
+        ui.borrow().notify("Only PDFs can be played as a folder");
+        return;
+    };
+    if pieces.len() == 1 {
+        ui.borrow().notify("No other PDFs in this folder");
+        return;
+    }
+    let local_page_number = ui
+        .borrow()
+        .document_canvas
+        .as_ref()
+        .map_or(0, |doc| doc.current_page_number);
+    match load_documents(&pieces, false, None, Rc::clone(&ui)) {
+        Ok(()) => {
+            navigate(&mut ui.borrow_mut(), |doc| {
+                doc.continue_piece(piece_index, local_page_number)
+            });
+            let message = format!("Playing {} pieces of the folder", pieces.len());
+            ui.borrow().notify(&message);
+        }
+        Err(e) => handle_load_error(ui, e, open_folder_as_playlist),
+    }
+}
+
+fn reload_files(ui: Rc<RefCell<Ui>>) {
+    let Some(loaded_files) = ui.borrow().loaded_files.clone() else {
+        return;
+    };
+    let position = ui
+        .borrow()
+        .document_canvas
+        .as_ref()
+        .map(|doc| doc.current_page_number);
+    match load_documents(
+        &loaded_files.paths,
+        loaded_files.with_dividers,
+        position,
+        Rc::clone(&ui),
+    ) {
+        Ok(()) => ui.borrow().notify("Reloaded"),
+        Err(e) => handle_load_error(ui, e, reload_files),
+    }
+}
+
+// Encrypted documents ask for their password and are loaded again
+fn handle_load_error(
+    ui: Rc<RefCell<Ui>>,
+    e: anyhow::Error,
+    retry: impl Fn(Rc<RefCell<Ui>>) + 'static,
+) {
+    match e.downcast_ref::<PasswordRequired>() {
+        Some(password_required) => show_password_dialog(ui, password_required.path.clone(), retry),
+        None => show_error_dialog(&ui.borrow(), OPEN_ERROR_TITLE, &format!("{:#}", e)),
+    }
+}
+
+fn show_password_dialog(
+    ui: Rc<RefCell<Ui>>,
+    path: PathBuf,
+    retry: impl Fn(Rc<RefCell<Ui>>) + 'static,
+) {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string());
+    // A remembered password that still fails must be wrong
+    let message = if ui.borrow().passwords.contains_key(&path) {
+        format!("The password for {} is wrong", name)
+    } else {
+        format!("{} is protected by a password", name)
+    };
+
+    let dialog = Dialog::builder()
+        .title("Password required")
+        .transient_for(&ui.borrow().window)
+        .modal(true)
+        .build();
+    dialog.add_button("_Cancel", ResponseType::Cancel);
+    dialog.add_button("_Open", ResponseType::Accept);
+    dialog.set_default_response(ResponseType::Accept);
+
+    let label = Label::builder()
+        .label(message)
+        .margin_start(12)
+        .margin_end(12)
+        .margin_top(12)
+        .build();
+    let entry = PasswordEntry::builder()
+        .show_peek_icon(true)
+        .activates_default(true)
+        .margin_start(12)
+        .margin_end(12)
+        .margin_top(6)
+        .build();
+    dialog.content_area().append(&label);
+    dialog.content_area().append(&entry);
+
+    dialog.connect_response(clone!(@weak ui => move |dialog, response| {
+        dialog.destroy();
+        if response != ResponseType::Accept {
+            return;
+        }
+        ui.borrow_mut().passwords.insert(path.clone(), entry.text().to_string());
+        retry(ui);
+    }));
+    dialog.present();
+}
+
+const OPEN_ERROR_TITLE: &str = "Could not open document";
+const EXPORT_ERROR_TITLE: &str = "Could not save the pages";
+
+pub fn show_error_dialog(ui: &Ui, title: &str, message: &str) {
+    error!("{}", message);
+    let dialog = MessageDialog::builder()
+        .transient_for(&ui.window)
+        .modal(true)
+        .message_type(MessageType::Error)
+        .buttons(ButtonsType::Close)
+        .text(title)
+        .secondary_text(message)
+        .build();
+    dialog.connect_response(|dialog, _| dialog.destroy());
+    dialog.present();
+}
+
+fn cache_loaded_document(ui: &Ui) {
+    let Some(doc) = ui.document_canvas.as_ref() else {
+        return;
+    };
+    let area = render_area(ui);
+    doc.cache_initial_pages(area);
+    if !ui.precache_all {
+        return;
+    }
+    let memory = doc.cache_all_pages(area);
+    if memory > ui.cache_memory {
+        let message = format!(
+            "The document needs about {} MB to be cached completely, but the cache may only take {} MB",
+            memory / BYTES_PER_MB,
+            ui.cache_memory / BYTES_PER_MB
+        );
+        warn!("{}", message);
+        ui.notify(&message);
+    }
+}
+
+pub fn load_document(files: &[impl AsRef<Path>], ui: Rc<RefCell<Ui>>) -> Result<()> {
+    load_documents(files, false, None, ui)
+}
+
+// The position overrides the one remembered for the document
+fn load_documents(
+    files: &[impl AsRef<Path>],
+    with_dividers: bool,
+    position: Option<PageNumber>,
+    ui: Rc<RefCell<Ui>>,
+) -> Result<()> {
+    debug!("Loading {} file(s)...", files.len());
+    let paths: Vec<PathBuf> = files
+        .iter()
+        .map(|file| file.as_ref().to_path_buf())
+        .collect();
+    let page_maps = page_map::load_page_maps(&paths)?;
+    let options = OpenOptions {
+        with_dividers,
+        passwords: ui.borrow().passwords.clone(),
+        page_maps: page_maps.clone(),
+    };
+    // Open every document before touching the ui, so a failure keeps the current one
+    let document = document::open_documents(&paths, &options)?;
+    let num_pages = document.n_pages();
 
+    let precache_all = ui.borrow().precache_all;
+    // The number of pages must not limit caching everything, only the memory does
+    let cache_pages = if precache_all {
+        ui.borrow().cache_pages.max(num_pages)
+    } else {
+        ui.borrow().cache_pages
+    };
+    let cache_memory = ui.borrow().cache_memory;
+    update_cache_progress(&ui.borrow(), 0, num_pages);
     let sender = cache::spawn_sync_cache(
-        document,
+        paths.clone(),
+        options,
+        num_pages,
+        cache_pages,
+        cache_memory,
+        ui.borrow().detect_blank_pages,
         clone!(@weak ui => move |cache_response| match cache_response {
                 cache::CacheResponse::SinglePageRetrieved { page } => {
                     ui.borrow_mut().image_left.set_paintable(Some(page.as_ref()));
-                    if ui.borrow().document_canvas.as_ref().map(|canvas| canvas.num_pages.unwrap_or(0)).unwrap_or(0) > 1 {
+                    let alone_in_spread = ui.borrow().document_canvas.as_ref().is_some_and(|canvas| canvas.is_alone_in_spread());
+                    if alone_in_spread && !ui.borrow().center_single_pages {
                         // Make image invisible but keep free space in layout
                         ui.borrow_mut().image_right.set_opacity(0.0);
                     } else {
                         // Make image invisible and center left page in layout
                         ui.borrow_mut().image_right.set_visible(false);
                     }
-                    let area_height = ui.borrow().image_container.height();
-                    ui.borrow().document_canvas.as_ref().unwrap().cache_surrounding_pages(area_height);
+                    apply_single_page_width(&ui.borrow());
+                    hide_loading_spinner(&ui.borrow());
+                    finish_page_turn(&mut ui.borrow_mut());
+                    let area = render_area(&ui.borrow());
+                    ui.borrow().document_canvas.as_ref().unwrap().cache_surrounding_pages(area);
                 }
                 cache::CacheResponse::TwoPagesRetrieved {
                     page_left,
@@ -336,25 +4562,353 @@ pub fn load_document(file: impl AsRef<Path>, ui: Rc<RefCell<Ui>>) {
                     ui.borrow_mut().image_right.set_paintable(Some(page_right.as_ref()));
                     ui.borrow_mut().image_right.set_visible(true);
                     ui.borrow_mut().image_right.set_opacity(1.0);
-                    let area_height = ui.borrow().image_container.height();
-                    ui.borrow().document_canvas.as_ref().unwrap().cache_surrounding_pages(area_height);
+                    apply_single_page_width(&ui.borrow());
+                    hide_loading_spinner(&ui.borrow());
+                    finish_page_turn(&mut ui.borrow_mut());
+                    let area = render_area(&ui.borrow());
+                    ui.borrow().document_canvas.as_ref().unwrap().cache_surrounding_pages(area);
                 },
-            cache::CacheResponse::PageResolutionUpgraded { page_number, page } => {
-                if ui.borrow().document_canvas.as_ref().unwrap().is_left_page(page_number){
-                    ui.borrow_mut().image_left.set_paintable(Some(page.as_ref()));
-                } else if ui.borrow().document_canvas.as_ref().unwrap().is_right_page(page_number){
-                    ui.borrow_mut().image_right.set_paintable(Some(page.as_ref()));
+            cache::CacheResponse::PagesResolutionUpgraded { pages } if ui.borrow().continuous => {
+                for (page_number, page) in pages {
+                    // Pictures scrolled out of view were cleared and stay empty
+                    if let Some(picture) = ui.borrow().continuous_pictures.get(page_number).filter(|picture| picture.paintable().is_some()) {
+                        picture.set_paintable(Some(page.as_ref()));
+                    }
+                }
+            }
+            cache::CacheResponse::PageRetrieved { page_number, page } if ui.borrow().continuous => {
+                if let Some(picture) = ui.borrow().continuous_pictures.get(page_number) {
+                    picture.set_paintable(Some(page.as_ref()));
+                }
+            }
+            cache::CacheResponse::PageRetrieved { page_number, page } => {
+                let ui = ui.borrow();
+                let doc = ui.document_canvas.as_ref().unwrap();
+                // The right page of a spread arrives alone if the left one failed
+                if doc.is_right_page(page_number) {
+                    ui.image_right.set_paintable(Some(page.as_ref()));
+                    ui.image_right.set_visible(true);
+                    ui.image_right.set_opacity(1.0);
+                } else if let Some(picture) = grid_row_picture(&ui, page_number) {
+                    picture.set_paintable(Some(page.as_ref()));
+                }
+            }
+            cache::CacheResponse::PagesResolutionUpgraded { pages } => {
+                let ui = ui.borrow();
+                let doc = ui.document_canvas.as_ref().unwrap();
+                let visible_pages: Vec<(&Picture, &cache::MyPageType)> = pages
+                    .iter()
+                    .filter_map(|(page_number, page)| {
+                        if doc.is_left_page(*page_number) {
+                            Some((&ui.image_left, page.as_ref()))
+                        } else if doc.is_right_page(*page_number) {
+                            Some((&ui.image_right, page.as_ref()))
+                        } else {
+                            grid_row_picture(&ui, *page_number).map(|picture| (picture, page.as_ref()))
+                        }
+                    })
+                    .collect();
+                show_upgraded_pages(&ui, &visible_pages);
+            }
+            cache::CacheResponse::PageFailed { page_number, message } if ui.borrow().continuous => {
+                if let Some(picture) = ui.borrow().continuous_pictures.get(page_number) {
+                    picture.set_paintable(Some(&draw::draw_placeholder_to_texture(page_number)));
+                }
+                ui.borrow().notify(&message);
+            }
+            cache::CacheResponse::PageFailed { page_number, message } if grid_row_picture(&ui.borrow(), page_number).is_some() => {
+                if let Some(picture) = grid_row_picture(&ui.borrow(), page_number) {
+                    picture.set_paintable(Some(&draw::draw_placeholder_to_texture(page_number)));
+                }
+                ui.borrow().notify(&message);
+            }
+            cache::CacheResponse::PageFailed { page_number, message } => {
+                let placeholder = draw::draw_placeholder_to_texture(page_number);
+                let (is_right_page, has_right_page) = {
+                    let ui = ui.borrow();
+                    let doc = ui.document_canvas.as_ref().unwrap();
+                    (doc.is_right_page(page_number), doc.has_right_page())
+                };
+                // The other page of the spread stays, it arrives on its own
+                if is_right_page {
+                    ui.borrow_mut().image_right.set_paintable(Some(&placeholder));
+                    ui.borrow_mut().image_right.set_visible(true);
+                    ui.borrow_mut().image_right.set_opacity(1.0);
+                } else {
+                    ui.borrow_mut().image_left.set_paintable(Some(&placeholder));
+                    if !has_right_page {
+                        ui.borrow_mut().image_right.set_visible(false);
+                    }
+                }
+                apply_single_page_width(&ui.borrow());
+                hide_loading_spinner(&ui.borrow());
+                finish_page_turn(&mut ui.borrow_mut());
+                ui.borrow().notify(&message);
+            }
+            cache::CacheResponse::CommandFailed { message } => {
+                hide_loading_spinner(&ui.borrow());
+                ui.borrow().notify(&message);
+            }
+            cache::CacheResponse::Progress { cached_pages, total_pages } => {
+                update_cache_progress(&ui.borrow(), cached_pages, total_pages);
+            }
+            cache::CacheResponse::Stats(stats) => update_cache_stats(&ui.borrow(), stats),
+            cache::CacheResponse::PageBlank { page_number } => {
+                let label = ui.borrow().document_canvas.as_ref().map(|doc| doc.page_label(page_number));
+                if let Some(label) = label {
+                    ui.borrow().notify(&format!("Page {} came out blank", label));
                 }
             }
         }),
     );
 
+    // A running timer would otherwise turn the new document from its restored position
+    stop_auto_turn(&mut ui.borrow_mut());
+    if let Some(previous_document_canvas) = ui.borrow().document_canvas.as_ref() {
+        previous_document_canvas.close();
+    }
     let mut document_canvas = DocumentCanvas::new(sender);
     document_canvas.num_pages = Some(num_pages);
-    document_canvas.cache_initial_pages(ui.borrow().image_container.height());
+    document_canvas.spread_step = ui.borrow().spread_step;
+    document_canvas.prefetch_pages = ui.borrow().prefetch_pages;
+    document_canvas.cache_pages = cache_pages;
+    document_canvas.cache_memory = cache_memory;
+    document_canvas.view_mode = ui.borrow().default_view_mode;
+    let (fit_mode, invert) = (ui.borrow().default_fit_mode, ui.borrow().default_invert);
+    let print_rendering = ui.borrow().default_print_rendering;
+    let background = ui.borrow().page_background;
+    let page_gap = ui.borrow().image_container.spacing();
+    document_canvas.update_render_settings(|settings| {
+        settings.page_gap = page_gap;
+        settings.fit_mode = fit_mode;
+        settings.invert = invert;
+        settings.print_rendering = print_rendering;
+        settings.background = background;
+    });
+    document_canvas.right_to_left = ui.borrow().right_to_left;
+    let render_scale = ui
+        .borrow()
+        .render_scale
+        .unwrap_or_else(|| ui.borrow().window.scale_factor() as f64);
+    document_canvas.set_render_scale(render_scale);
+    document_canvas
+        .page_cache_sender
+        .set_resolution_steps(ui.borrow().resolution_steps.clone());
+    document_canvas.set_levels(ui.borrow().levels);
+    if let [file] = files {
+        let path = file.as_ref().to_path_buf();
+        // Positions, bookmarks and annotations refer to the order of the page map
+        let path = if page_maps.contains_key(&path) {
+            page_map::page_map_path(&path)
+        } else {
+            path
+        };
+        if let Some(page) = state::load_position(&path) {
+            document_canvas.current_page_number = page.min(num_pages.saturating_sub(1));
+        }
+        document_canvas.bookmarks = state::load_bookmarks(&path);
+        document_canvas.annotations = annotations::load(&path);
+        document_canvas.restore_prefs(prefs::load(&path));
+        document_canvas.set_crops(state::load_crops(&path));
+        document_canvas.state_path = Some(path);
+    }
+    if let Some(page_number) = position {
+        // The document may have lost pages since
+        let page_number = page_number.min(num_pages.saturating_sub(1));
+        document_canvas.go_to_page(page_number);
+    }
+    document_canvas.title = document.title().or_else(|| match paths.as_slice() {
+        [path] => Some(setlist::piece_title(path)),
+        _ => None,
+    });
+    document_canvas.page_labels = document.page_labels().unwrap_or_default();
+    if paths.len() > 1 {
+        // Every piece but the first is preceded by a divider
+        let documents_per_piece = if with_dividers { 2 } else { 1 };
+        document_canvas.pieces = document
+            .first_page_numbers()
+            .into_iter()
+            .step_by(documents_per_piece)
+            .zip(paths.iter().map(|path| setlist::piece_title(path)))
+            .collect();
+    }
 
     ui.borrow_mut().document_canvas = Some(document_canvas);
+    if render_area(&ui.borrow()).is_empty() {
+        // Rendering before the first allocation would only be repeated at the real size
+        let scroller = ui.borrow().scroller.clone();
+        scroller.add_tick_callback(
+            clone!(@weak ui => @default-return glib::ControlFlow::Break, move |_, _| {
+                if render_area(&ui.borrow()).is_empty() {
+                    return glib::ControlFlow::Continue;
+                }
+                cache_loaded_document(&ui.borrow());
+                if let Some(doc) = ui.borrow().document_canvas.as_ref() {
+                    doc.cache_surrounding_pages(render_area(&ui.borrow()));
+                }
+                glib::ControlFlow::Break
+            }),
+        );
+    } else {
+        cache_loaded_document(&ui.borrow());
+    }
+    ui.borrow().empty_state.set_visible(false);
+    // Hits of the previous files point to the wrong pages
+    start_search(Rc::clone(&ui), String::new());
+    apply_fit_mode(&ui.borrow());
+    if !ui.borrow().continuous {
+        restore_page_view(&mut ui.borrow_mut());
+    }
+    for file in files {
+        state::add_recent_file(file);
+    }
+    apply_reading_direction(&ui.borrow());
+    populate_outline(Rc::clone(&ui), document.outline());
+    let document = Rc::new(document);
+    ui.borrow_mut().document = Some(Rc::clone(&document));
+    populate_thumbnails(&mut ui.borrow_mut(), document);
+
+    ui.borrow_mut().page_maps = page_maps;
+    let notes = match paths.as_slice() {
+        [path] => notes::load(path),
+        _ => None,
+    };
+    if notes.is_none() {
+        ui.borrow().notes_panel.set_reveal_child(false);
+        ui.borrow().notes_view.buffer().set_text("");
+    }
+    ui.borrow_mut().notes = notes;
+    let loaded_files = LoadedFiles {
+        paths,
+        with_dividers,
+    };
+    let previous_files = ui.borrow_mut().loaded_files.replace(loaded_files.clone());
+    // Loading the same files again keeps the way back
+    if previous_files.as_ref() != Some(&loaded_files) {
+        ui.borrow_mut().previous_files = previous_files;
+    }
+    record_practice_activity(&mut ui.borrow_mut());
 
+    if ui.borrow().continuous {
+        let fit_mode = ui.borrow_mut().document_canvas.as_mut().map(|doc| {
+            let fit_mode = doc.render_settings.fit_mode;
+            doc.update_render_settings(|settings| settings.fit_mode = FitMode::Width);
+            fit_mode
+        });
+        ui.borrow_mut().fit_mode_before_continuous = fit_mode;
+        populate_continuous_pages(&mut ui.borrow_mut());
+    }
     update_page_status(&ui.borrow());
     debug!("finished loading document");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::SyncCacheCommandChannel;
+
+    // The commands go nowhere, no cache task is running
+    fn canvas(num_pages: usize, view_mode: ViewMode) -> DocumentCanvas {
+        let (sender, _receiver) = SyncCacheCommandChannel::open();
+        let mut doc = DocumentCanvas::new(sender);
+        doc.num_pages = Some(num_pages);
+        doc.view_mode = view_mode;
+        doc
+    }
+
+    #[test]
+    fn page_status_of_single_page_document_is_one_based() {
+        let doc = canvas(1, ViewMode::Spread);
+        assert_eq!(format_page_status(&doc, ViewMode::Spread), "1 / 1");
+        assert_eq!(format_page_status(&doc, ViewMode::Single), "1 / 1");
+    }
+
+    #[test]
+    fn page_status_shows_both_pages_of_spread() {
+        let mut doc = canvas(4, ViewMode::Spread);
+        assert_eq!(format_page_status(&doc, ViewMode::Spread), "1-2 / 4");
+        doc.current_page_number = 2;
+        assert_eq!(format_page_status(&doc, ViewMode::Spread), "3-4 / 4");
+        assert_eq!(format_page_status(&doc, ViewMode::Single), "3 / 4");
+    }
+
+    #[test]
+    fn page_status_shows_odd_last_page_alone() {
+        let mut doc = canvas(5, ViewMode::Spread);
+        doc.current_page_number = 4;
+        assert_eq!(format_page_status(&doc, ViewMode::Spread), "5 / 5");
+    }
+
+    #[test]
+    fn last_page_of_odd_document_is_reachable_alone() {
+        let mut doc = canvas(5, ViewMode::Spread);
+        doc.increase_page_number();
+        doc.increase_page_number();
+        assert_eq!(doc.current_page_number, 4);
+        assert!(!doc.has_right_page());
+        assert_eq!(doc.visible_page_numbers(), vec![4]);
+        doc.increase_page_number();
+        assert_eq!(doc.current_page_number, 4);
+        assert_eq!(doc.hit_edge, Some(NavigationDirection::Forward));
+    }
+
+    #[test]
+    fn even_document_stops_at_last_spread() {
+        let mut doc = canvas(4, ViewMode::Spread);
+        doc.increase_page_number();
+        assert_eq!(doc.current_page_number, 2);
+        assert!(doc.has_right_page());
+        doc.increase_page_number();
+        assert_eq!(doc.current_page_number, 2);
+        assert_eq!(doc.visible_page_numbers(), vec![2, 3]);
+        assert_eq!(doc.hit_edge, Some(NavigationDirection::Forward));
+    }
+
+    #[test]
+    fn opening_second_document_stops_first_cache_task() {
+        let context = glib::MainContext::new();
+        context
+            .with_thread_default(|| {
+                // The receiver lives as long as the cache task, which holds the only other reference
+                let responses = Rc::new(Cell::new(0));
+                let counted_responses = Rc::clone(&responses);
+                let sender = cache::spawn_sync_cache(
+                    Vec::new(),
+                    OpenOptions::default(),
+                    1,
+                    2,
+                    BYTES_PER_MB,
+                    false,
+                    move |_| counted_responses.set(counted_responses.get() + 1),
+                );
+                let mut first = DocumentCanvas::new(sender);
+                first.num_pages = Some(1);
+                for _ in 0..10 {
+                    context.iteration(false);
+                }
+                assert_eq!(Rc::strong_count(&responses), 2);
+
+                // Like loading a document closes the one before
+                first.close();
+                let second = DocumentCanvas::new(cache::spawn_sync_cache(
+                    Vec::new(),
+                    OpenOptions::default(),
+                    1,
+                    2,
+                    BYTES_PER_MB,
+                    false,
+                    |_| {},
+                ));
+                first.request_to_draw_pages(RenderSize::PREVIEW);
+                for _ in 0..100 {
+                    context.iteration(false);
+                }
+
+                assert_eq!(Rc::strong_count(&responses), 1);
+                assert_eq!(responses.get(), 0);
+                assert!(second.page_cache_sender.is_channel_open());
+            })
+            .unwrap();
+    }
 }